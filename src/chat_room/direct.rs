@@ -0,0 +1,83 @@
+use std::convert::TryInto;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::crypto::{chacha::ChaChaCrypt, dm_key::DmKeyPair, Decrypt, Encrypt};
+
+use super::Error;
+
+const KEY_LEN: usize = 32;
+
+/// Encrypts `msg`, so only the owner of `their_key` can read it
+pub fn seal(
+    keys: &DmKeyPair,
+    their_key: [u8; KEY_LEN],
+    msg: &impl Serialize,
+) -> Result<String, Error> {
+    let crypto = ChaChaCrypt::new(&keys.shared_key(their_key));
+
+    Ok(base64::encode(crypto.encrypt(serde_json::to_vec(msg)?)))
+}
+
+/// Reads message sealed by the owner of `their_key`
+pub fn open<T: DeserializeOwned>(
+    keys: &DmKeyPair,
+    their_key: [u8; KEY_LEN],
+    sealed: &str,
+) -> Result<T, Error> {
+    let crypto = ChaChaCrypt::new(&keys.shared_key(their_key));
+    let msg = crypto.decrypt(base64::decode(sealed)?)?;
+
+    Ok(serde_json::from_slice(&msg)?)
+}
+
+pub fn encode_key(key: [u8; KEY_LEN]) -> String {
+    base64::encode(key)
+}
+
+pub fn decode_key(key: &str) -> Result<[u8; KEY_LEN], Error> {
+    base64::decode(key)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid key length"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::chat_room::ChatMessage;
+
+    #[test]
+    fn should_open_message_sealed_by_peer() {
+        let alice = DmKeyPair::generate();
+        let bob = DmKeyPair::generate();
+        let msg = ChatMessage::new("alice".into(), "secret".into(), 0);
+
+        let sealed = seal(&alice, bob.public_key(), &msg).unwrap();
+
+        assert_eq!(
+            open::<ChatMessage>(&bob, alice.public_key(), &sealed).unwrap(),
+            msg
+        );
+    }
+
+    #[test]
+    fn should_not_open_message_sealed_for_someone_else() {
+        let alice = DmKeyPair::generate();
+        let bob = DmKeyPair::generate();
+        let eve = DmKeyPair::generate();
+        let msg = ChatMessage::new("alice".into(), "secret".into(), 0);
+
+        let sealed = seal(&alice, bob.public_key(), &msg).unwrap();
+
+        assert!(open::<ChatMessage>(&eve, alice.public_key(), &sealed).is_err());
+    }
+
+    #[test]
+    fn should_decode_encoded_key() {
+        let key = DmKeyPair::generate().public_key();
+
+        assert_eq!(decode_key(&encode_key(key)).unwrap(), key);
+        assert!(decode_key("c2hvcnQ=").is_err());
+    }
+}
@@ -0,0 +1,384 @@
+use std::convert::TryInto;
+
+use anyhow::Context;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{ChatMessage, Error};
+use crate::crypto::identity::{self, IdentityKey, PUBLIC_KEY_LEN};
+
+/// Version of the envelope format sent by this client
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Wrapper of everything published to the room.
+///
+/// Kinds of payload unknown to this client are read as [`Payload::Unknown`]
+/// and can be skipped, so new kinds can be added without breaking older
+/// clients. `version` tells which protocol version the sender speaks.
+///
+/// `id` and `sent` travel encrypted with the payload, so receivers can drop
+/// envelopes recorded off the broker and published again. Clients predating
+/// them send neither, and envelopes without them are dropped.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version: u32,
+    /// Named apart from `id` of the flattened payloads
+    #[serde(
+        rename = "envelope_id",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent: Option<DateTime<Local>>,
+    #[serde(flatten)]
+    pub payload: Payload,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Signature>,
+}
+
+/// Signature of the envelope made with identity key of the sender. It covers
+/// the envelope without this field, with object keys sorted, so re-encoding
+/// gives the signed bytes whatever order the fields were sent in.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signature {
+    /// Base64 public identity key of the sender
+    pub key: String,
+    /// Base64 Ed25519 signature
+    pub sig: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Payload {
+    Text(ChatMessage),
+    Presence {
+        user: String,
+        status: PresenceStatus,
+        /// Base64 public key for direct messages to `user`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dm_key: Option<String>,
+        /// Token of the will published when `user` loses connection, see [`super::will::Will`]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        will: Option<String>,
+    },
+    /// [`ChatMessage`] only `to` can read, sealed with key agreed by the two users
+    Direct {
+        from: String,
+        to: String,
+        sealed: String,
+    },
+    Typing {
+        user: String,
+    },
+    /// `user` asks admins for the current room key, sealed to `dm_key`
+    KeyRequest {
+        user: String,
+        dm_key: String,
+    },
+    /// Sender chain of `from` sealed for `to` with key agreed with `dm_key` of `from`
+    SenderKey {
+        from: String,
+        to: String,
+        dm_key: String,
+        sealed: String,
+    },
+    /// Room key of `epoch` sealed for `to` with key agreed with `dm_key` of `from`
+    RoomKey {
+        from: String,
+        to: String,
+        epoch: u32,
+        dm_key: String,
+        sealed: String,
+    },
+    /// `user` changed name to `nick`, sent on the topic of the old name
+    Nick {
+        user: String,
+        nick: String,
+    },
+    /// Room subject set by `user`, repeated to newcomers by members knowing it
+    Subject {
+        user: String,
+        subject: String,
+    },
+    /// Asks peers for messages newer than `since`, all of them if `None`
+    SyncRequest {
+        sync_from: String,
+        since: Option<DateTime<Local>>,
+    },
+    /// Batch of messages `from` passes on answering [`Payload::SyncRequest`]
+    /// of `sync_to`. Signatures of the original senders are not kept.
+    SyncResponse {
+        from: String,
+        sync_to: String,
+        messages: Vec<ChatMessage>,
+    },
+    /// Payload kind added in a newer protocol version
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Join,
+    Alive,
+    Leave,
+}
+
+impl Envelope {
+    pub fn new(payload: Payload) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id: Some(Uuid::new_v4()),
+            sent: Some(Local::now()),
+            payload,
+            signature: None,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    pub fn encode_signed(&self, identity: &IdentityKey) -> Result<Vec<u8>, Error> {
+        let unsigned = Self {
+            signature: None,
+            ..self.clone()
+        };
+        let mut value = serde_json::to_value(unsigned)?;
+        let sig = identity.sign(&serde_json::to_vec(&value)?);
+
+        value["signature"] = serde_json::to_value(Signature {
+            key: base64::encode(identity.public_key()),
+            sig: base64::encode(sig),
+        })?;
+
+        Ok(serde_json::to_vec(&value)?)
+    }
+
+    /// Identity key encoded envelope was signed with, `None` if not signed
+    pub fn signer(data: &[u8]) -> Result<Option<[u8; PUBLIC_KEY_LEN]>, Error> {
+        let mut value = serde_json::from_slice::<serde_json::Value>(data)?;
+        let signature = match value.as_object_mut().and_then(|o| o.remove("signature")) {
+            Some(signature) => serde_json::from_value::<Signature>(signature)?,
+            None => return Ok(None),
+        };
+
+        let key = base64::decode(&signature.key)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Malformed identity key"))?;
+        let sig = base64::decode(&signature.sig)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Malformed signature"))?;
+        identity::verify(&key, &serde_json::to_vec(&value)?, &sig).context("Bad signature")?;
+
+        Ok(Some(key))
+    }
+
+    /// Reads envelope, or a bare [`ChatMessage`] sent by clients predating envelopes
+    pub fn decode(data: &[u8]) -> Result<Self, Error> {
+        match serde_json::from_slice(data) {
+            Ok(envelope) => Ok(envelope),
+            Err(e) => match serde_json::from_slice::<ChatMessage>(data) {
+                Ok(msg) => Ok(Self {
+                    version: 0,
+                    id: None,
+                    sent: None,
+                    payload: Payload::Text(msg),
+                    signature: None,
+                }),
+                Err(_) => Err(e.into()),
+            },
+        }
+    }
+}
+
+impl Payload {
+    /// User the payload claims to come from, if it names one
+    pub fn sender(&self) -> Option<&str> {
+        match self {
+            Payload::Text(msg) => Some(&msg.user),
+            Payload::Presence { user, .. }
+            | Payload::Typing { user }
+            | Payload::KeyRequest { user, .. }
+            | Payload::Subject { user, .. }
+            | Payload::Nick { user, .. } => Some(user),
+            Payload::Direct { from, .. }
+            | Payload::SyncResponse { from, .. }
+            | Payload::SenderKey { from, .. }
+            | Payload::RoomKey { from, .. } => Some(from),
+            Payload::SyncRequest { sync_from, .. } => Some(sync_from),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_tell_claimed_sender() {
+        let text = Payload::Text(ChatMessage::new("alice".into(), "text".into(), 1));
+        let direct = Payload::Direct {
+            from: "bob".into(),
+            to: "alice".into(),
+            sealed: "sealed".into(),
+        };
+        let response = Payload::SyncResponse {
+            from: "carol".into(),
+            sync_to: "alice".into(),
+            messages: vec![],
+        };
+
+        assert_eq!(text.sender(), Some("alice"));
+        assert_eq!(direct.sender(), Some("bob"));
+        assert_eq!(response.sender(), Some("carol"));
+        assert_eq!(Payload::Unknown.sender(), None);
+    }
+
+    #[test]
+    fn should_read_own_payloads() {
+        let payloads = vec![
+            Payload::Text(ChatMessage::new("user".into(), "text".into(), 1)),
+            Payload::Presence {
+                user: "user".into(),
+                status: PresenceStatus::Alive,
+                dm_key: Some("key".into()),
+                will: Some("token".into()),
+            },
+            Payload::Typing {
+                user: "user".into(),
+            },
+            Payload::SyncRequest {
+                sync_from: "user".into(),
+                since: None,
+            },
+            Payload::SyncResponse {
+                from: "peer".into(),
+                sync_to: "user".into(),
+                messages: vec![],
+            },
+        ];
+
+        for payload in payloads {
+            let envelope = Envelope::new(payload);
+            let decoded = Envelope::decode(&envelope.encode().unwrap()).unwrap();
+            assert_eq!(decoded, envelope);
+        }
+    }
+
+    #[test]
+    fn should_stamp_every_envelope() {
+        let first = Envelope::new(Payload::Unknown);
+        let second = Envelope::new(Payload::Unknown);
+
+        assert!(first.id.is_some() && second.id.is_some());
+        assert_ne!(first.id, second.id);
+        assert!(first.sent.is_some());
+    }
+
+    #[test]
+    fn should_tag_payload_with_version_and_kind() {
+        let envelope = Envelope::new(Payload::Typing {
+            user: "user".into(),
+        });
+
+        let json = serde_json::to_value(&envelope).unwrap();
+
+        assert_eq!(json["version"], PROTOCOL_VERSION);
+        assert_eq!(json["kind"], "typing");
+    }
+
+    #[test]
+    fn should_read_unknown_kind_from_newer_client() {
+        let data = br#"{"version":7,"kind":"poll","question":"pizza?"}"#;
+
+        let envelope = Envelope::decode(data).unwrap();
+
+        assert_eq!(envelope.version, 7);
+        assert_eq!(envelope.payload, Payload::Unknown);
+    }
+
+    #[test]
+    fn should_read_bare_chat_message_from_older_client() {
+        let message = ChatMessage::new("user".into(), "text".into(), 1);
+
+        let envelope = Envelope::decode(&serde_json::to_vec(&message).unwrap()).unwrap();
+
+        assert_eq!(envelope.version, 0);
+        assert_eq!(envelope.id, None);
+        assert_eq!(envelope.payload, Payload::Text(message));
+    }
+
+    #[test]
+    fn should_give_random_id_to_message_without_one() {
+        let message = br#"{"user":"user","msg":"text","time":"2021-11-20T10:00:00+01:00"}"#;
+
+        let first = Envelope::decode(message).unwrap();
+        let second = Envelope::decode(message).unwrap();
+
+        assert!(matches!(first.payload, Payload::Text(_)));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn should_fail_on_garbage() {
+        assert!(Envelope::decode(b"garbage").is_err());
+    }
+
+    fn signed(identity: &IdentityKey) -> Vec<u8> {
+        let payload = Payload::Text(ChatMessage::new("alice".into(), "text".into(), 1));
+        Envelope::new(payload).encode_signed(identity).unwrap()
+    }
+
+    #[test]
+    fn should_verify_signed_envelope() {
+        let identity = IdentityKey::generate();
+        let data = signed(&identity);
+
+        assert_eq!(
+            Envelope::signer(&data).unwrap(),
+            Some(identity.public_key())
+        );
+        assert!(Envelope::decode(&data).unwrap().signature.is_some());
+    }
+
+    #[test]
+    fn should_verify_whatever_the_field_order() {
+        let identity = IdentityKey::generate();
+        let value: serde_json::Value = serde_json::from_slice(&signed(&identity)).unwrap();
+        let mut fields = value.as_object().unwrap().iter().collect::<Vec<_>>();
+        fields.reverse();
+        let reordered = format!(
+            "{{{}}}",
+            fields
+                .iter()
+                .map(|(k, v)| format!("{:?}:{}", k, v))
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+
+        assert_eq!(
+            Envelope::signer(reordered.as_bytes()).unwrap(),
+            Some(identity.public_key())
+        );
+    }
+
+    #[test]
+    fn should_reject_tampered_envelope() {
+        let data = String::from_utf8(signed(&IdentityKey::generate())).unwrap();
+        let tampered = data.replace("alice", "mallory");
+
+        assert!(Envelope::signer(tampered.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn should_tell_unsigned_envelope() {
+        let data = Envelope::new(Payload::Unknown).encode().unwrap();
+
+        assert_eq!(Envelope::signer(&data).unwrap(), None);
+    }
+}
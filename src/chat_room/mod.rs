@@ -1,20 +1,152 @@
+pub mod direct;
+pub mod envelope;
+pub mod nicks;
+pub mod presence;
 pub mod queue_chat_room;
+pub mod rate_limit;
+pub mod replay;
+pub mod will;
 
 type Error = anyhow::Error;
 
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::queue::ConnectionState;
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ChatMessage {
+    /// Unique id given by the sender. Messages from clients that don't send
+    /// ids get a random one, so they are never taken for duplicates.
+    #[serde(default = "Uuid::new_v4")]
+    pub id: Uuid,
+    /// Number of the message among messages of its sender
+    #[serde(default)]
+    pub seq: u64,
     pub user: String,
     pub msg: String,
     pub time: DateTime<Local>,
+    #[serde(skip)]
+    pub kind: MessageKind,
+    /// First name of the sender, set when they were renamed since
+    #[serde(skip)]
+    pub alias_of: Option<String>,
+    #[serde(skip)]
+    pub trust: Trust,
+    /// Claimed `time` was far from the local clock when received
+    #[serde(skip)]
+    pub skewed: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum MessageKind {
+    #[default]
+    Text,
+    /// Notice generated locally, never sent nor stored
+    System,
+    /// Private message between the sender and `to`, never stored
+    Direct { to: String },
+}
+
+/// Whether a message comes from who it claims to, judged by its signature
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Trust {
+    /// Not checked, e.g. loaded from store or relayed by another user
+    #[default]
+    Unknown,
+    /// Signed with the key first seen for the sender
+    Verified,
+    /// Sent by a client not signing messages
+    Unsigned,
+    /// Signed with another key than the one first seen for the sender
+    KeyChanged,
+}
+
+impl ChatMessage {
+    pub fn new(user: String, msg: String, seq: u64) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            seq,
+            user,
+            msg,
+            time: chrono::Local::now(),
+            kind: MessageKind::Text,
+            alias_of: None,
+            trust: Trust::Unknown,
+            skewed: false,
+        }
+    }
+
+    pub fn system(msg: String) -> Self {
+        Self {
+            kind: MessageKind::System,
+            ..Self::new(String::new(), msg, 0)
+        }
+    }
+
+    /// Text of a `/me` action. Actions are sent as plain text, so clients
+    /// not knowing them still show something readable.
+    pub fn action(&self) -> Option<&str> {
+        match self.kind {
+            MessageKind::Text => self.msg.strip_prefix("/me "),
+            _ => None,
+        }
+    }
+}
+
+/// Checks a user or room name can be a single topic level
+pub fn validate_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || "/+#".contains(c)) {
+        anyhow::bail!("Name can't be empty nor contain spaces, /, + or #");
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Member {
+    pub name: String,
+    pub status: MemberStatus,
+    /// First name of the member, set when they were renamed since
+    pub alias_of: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MemberStatus {
+    Online,
+    /// Heartbeats stopped arriving recently
+    Away,
+    Offline,
 }
 
 #[cfg_attr(test, mockall::automock)]
 #[async_trait::async_trait]
 pub trait ChatRoom {
     async fn send(&self, msg: String) -> Result<(), Error>;
+    /// Sends message only `to` can read
+    async fn send_direct(&self, to: String, msg: String) -> Result<(), Error>;
     fn get_messages(&self) -> Vec<ChatMessage>;
+    fn get_members(&self) -> Vec<Member>;
+    /// Tells others this user is typing, the signal expires unless repeated
+    async fn send_typing(&self) -> Result<(), Error>;
+    /// Other users typing at the moment
+    fn get_typing(&self) -> Vec<String>;
+    fn connection_state(&self) -> ConnectionState;
+    /// Shows a system line only this user sees
+    fn notice(&self, msg: String);
+    /// Sets subject of the room shown to everyone in it
+    async fn set_subject(&self, subject: String) -> Result<(), Error>;
+    fn get_subject(&self) -> Option<String>;
+    /// Changes name of this user, others are told about it
+    async fn set_nick(&self, nick: String) -> Result<(), Error>;
+    /// Tells others this user left and stops taking part in the room
+    async fn leave(&self) -> Result<(), Error>;
+    /// Switches the room to a new key sent to online members, except
+    /// `revoked` who can't read the room any longer. Admins only, in end-to-end
+    /// rooms anyone changes their own key and only leaves `revoked` out of it.
+    async fn rotate_key(&self, revoked: Option<String>) -> Result<(), Error>;
+    /// Sends the current room key to `user`, e.g. one joining after a
+    /// rotation. Admins only.
+    async fn invite(&self, user: String) -> Result<(), Error>;
 }
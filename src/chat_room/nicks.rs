@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+/// Names users went by in the room, so a renamed user stays the same person.
+/// A person is known by the first name they were seen with.
+#[derive(Clone, Debug, Default)]
+pub struct Nicks {
+    /// First name of the person by every name they used
+    first_names: HashMap<String, String>,
+    /// Current name of the person by their first name
+    current_names: HashMap<String, String>,
+}
+
+impl Nicks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn renamed(&mut self, old: &str, new: &str) {
+        let first = self.first_name(old).to_string();
+
+        self.first_names.insert(new.to_string(), first.clone());
+        self.current_names.insert(first, new.to_string());
+    }
+
+    pub fn first_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.first_names.get(name).map_or(name, String::as_str)
+    }
+
+    /// Name the person who used `name` goes by now
+    pub fn current_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.current_names
+            .get(self.first_name(name))
+            .map_or(name, String::as_str)
+    }
+
+    /// Someone went by `name` in the room
+    pub fn knows(&self, name: &str) -> bool {
+        self.first_names.contains_key(name) || self.current_names.contains_key(name)
+    }
+
+    /// First name of the person if it differs from `name`
+    pub fn alias_of(&self, name: &str) -> Option<String> {
+        Some(self.first_name(name))
+            .filter(|first| *first != name)
+            .map(str::to_string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_follow_renames() {
+        let mut sut = Nicks::new();
+        sut.renamed("alice", "ally");
+        sut.renamed("ally", "al");
+
+        assert_eq!(sut.first_name("al"), "alice");
+        assert_eq!(sut.current_name("alice"), "al");
+        assert_eq!(sut.current_name("ally"), "al");
+        assert_eq!(sut.alias_of("ally").as_deref(), Some("alice"));
+        assert_eq!(sut.alias_of("alice"), None);
+    }
+
+    #[test]
+    fn should_keep_unknown_names() {
+        let sut = Nicks::new();
+
+        assert_eq!(sut.first_name("bob"), "bob");
+        assert_eq!(sut.current_name("bob"), "bob");
+        assert!(!sut.knows("bob"));
+    }
+
+    #[test]
+    fn should_know_old_and_new_names() {
+        let mut sut = Nicks::new();
+        sut.renamed("alice", "ally");
+
+        assert!(sut.knows("alice"));
+        assert!(sut.knows("ally"));
+    }
+
+    #[test]
+    fn should_return_to_first_name() {
+        let mut sut = Nicks::new();
+        sut.renamed("alice", "ally");
+        sut.renamed("ally", "alice");
+
+        assert_eq!(sut.current_name("ally"), "alice");
+        assert_eq!(sut.alias_of("alice"), None);
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use super::{Member, MemberStatus};
+
+/// How often clients announce they are still in the room
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Silence after which member is shown as away, a couple of heartbeats may get lost
+const AWAY_AFTER: Duration = Duration::from_secs(75);
+
+const OFFLINE_AFTER: Duration = Duration::from_secs(150);
+
+/// How long a typing signal is shown, typing clients repeat it more often
+pub const TYPING_EXPIRY: Duration = Duration::from_secs(5);
+
+/// Members of the room, tracked by their heartbeats and messages
+#[derive(Clone, Debug, Default)]
+pub struct Presence {
+    members: HashMap<String, Seen>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Seen {
+    last_seen: Instant,
+    left: bool,
+}
+
+impl Presence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn seen(&mut self, user: &str, now: Instant) {
+        self.members.insert(
+            user.to_string(),
+            Seen {
+                last_seen: now,
+                left: false,
+            },
+        );
+    }
+
+    pub fn left(&mut self, user: &str, now: Instant) {
+        self.members.insert(
+            user.to_string(),
+            Seen {
+                last_seen: now,
+                left: true,
+            },
+        );
+    }
+
+    /// Member was seen in the room, whether still online or not
+    pub fn knows(&self, user: &str) -> bool {
+        self.members.contains_key(user)
+    }
+
+    /// Moves what is known about `old` to its new name
+    pub fn renamed(&mut self, old: &str, new: &str) {
+        if let Some(seen) = self.members.remove(old) {
+            self.members.insert(new.to_string(), seen);
+        }
+    }
+
+    /// Members ordered by name
+    pub fn members(&self, now: Instant) -> Vec<Member> {
+        let mut members = self
+            .members
+            .iter()
+            .map(|(name, seen)| Member {
+                name: name.clone(),
+                status: seen.status(now),
+                alias_of: None,
+            })
+            .collect::<Vec<_>>();
+        members.sort_by(|a, b| a.name.cmp(&b.name));
+
+        members
+    }
+}
+
+/// Users who recently signalled they are typing
+#[derive(Clone, Debug, Default)]
+pub struct Typing {
+    users: HashMap<String, Instant>,
+}
+
+impl Typing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn typing(&mut self, user: &str, now: Instant) {
+        self.users.insert(user.to_string(), now);
+    }
+
+    pub fn stopped(&mut self, user: &str) {
+        self.users.remove(user);
+    }
+
+    /// Users still typing at `now`, ordered by name
+    pub fn users(&self, now: Instant) -> Vec<String> {
+        let mut users = self
+            .users
+            .iter()
+            .filter(|(_, &since)| now.saturating_duration_since(since) < TYPING_EXPIRY)
+            .map(|(user, _)| user.clone())
+            .collect::<Vec<_>>();
+        users.sort();
+
+        users
+    }
+}
+
+impl Seen {
+    fn status(&self, now: Instant) -> MemberStatus {
+        let silence = now.saturating_duration_since(self.last_seen);
+
+        if self.left || silence >= OFFLINE_AFTER {
+            MemberStatus::Offline
+        } else if silence >= AWAY_AFTER {
+            MemberStatus::Away
+        } else {
+            MemberStatus::Online
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    fn status(sut: &Presence, now: Instant) -> MemberStatus {
+        sut.members(now)[0].status
+    }
+
+    #[test_case(0, MemberStatus::Online ; "just seen")]
+    #[test_case(60, MemberStatus::Online ; "heartbeat missed")]
+    #[test_case(75, MemberStatus::Away ; "few heartbeats missed")]
+    #[test_case(150, MemberStatus::Offline ; "long gone")]
+    fn should_judge_status_by_silence(seconds: u64, expected: MemberStatus) {
+        let start = Instant::now();
+        let mut sut = Presence::new();
+
+        sut.seen("alice", start);
+
+        assert_eq!(status(&sut, start + Duration::from_secs(seconds)), expected);
+    }
+
+    #[test]
+    fn should_keep_status_after_rename() {
+        let start = Instant::now();
+        let mut sut = Presence::new();
+
+        sut.seen("alice", start);
+        sut.renamed("alice", "ally");
+        let members = sut.members(start + Duration::from_secs(75));
+
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].name, "ally");
+        assert_eq!(members[0].status, MemberStatus::Away);
+    }
+
+    #[test]
+    fn should_be_offline_after_leaving() {
+        let start = Instant::now();
+        let mut sut = Presence::new();
+
+        sut.seen("alice", start);
+        sut.left("alice", start);
+        assert_eq!(status(&sut, start), MemberStatus::Offline);
+
+        sut.seen("alice", start);
+        assert_eq!(status(&sut, start), MemberStatus::Online);
+    }
+
+    #[test]
+    fn should_list_members_by_name() {
+        let now = Instant::now();
+        let mut sut = Presence::new();
+
+        sut.seen("bob", now);
+        sut.seen("alice", now);
+
+        let names = sut
+            .members(now)
+            .into_iter()
+            .map(|member| member.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["alice", "bob"]);
+    }
+
+    #[test]
+    fn should_forget_typing_after_expiry() {
+        let start = Instant::now();
+        let mut sut = Typing::new();
+
+        sut.typing("bob", start);
+        sut.typing("alice", start + Duration::from_secs(1));
+
+        assert_eq!(sut.users(start), vec!["alice", "bob"]);
+        assert_eq!(sut.users(start + TYPING_EXPIRY), vec!["alice"]);
+    }
+
+    #[test]
+    fn should_forget_typing_when_stopped() {
+        let now = Instant::now();
+        let mut sut = Typing::new();
+
+        sut.typing("alice", now);
+        sut.stopped("alice");
+
+        assert!(sut.users(now).is_empty());
+    }
+}
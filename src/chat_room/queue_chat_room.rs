@@ -1,156 +1,3638 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
-use super::{ChatMessage, ChatRoom, Error};
-use crate::queue::Queue;
+use chrono::{DateTime, Local};
+use rand::Rng;
+use tokio::sync::Notify;
+
+use super::{
+    direct,
+    envelope::{Envelope, Payload, PresenceStatus},
+    nicks::Nicks,
+    presence::{Presence, Typing, HEARTBEAT_INTERVAL},
+    rate_limit::RateLimit,
+    replay::{self, ReplayGuard, ReplayedMessage, StaleMessage, UnstampedMessage},
+    will::{self, Will, WILL_TOPIC_FILTER},
+    ChatMessage, ChatRoom, Error, Member, MemberStatus, MessageKind, Trust,
+};
+use crate::{
+    crypto::{
+        dm_key::DmKeyPair,
+        identity::{IdentityKey, PUBLIC_KEY_LEN},
+        keyring::Keyring,
+        room_key::RoomKey,
+        sender_key::{SenderChain, SenderKeys},
+    },
+    queue::{
+        encrypted_queue::{is_key_topic, KEY_TOPIC_SEGMENT},
+        ConnectionState, Queue, ReceivedMessage, UndecryptableMessage,
+    },
+    store::{keystore::KeyDirectory, MessageStore},
+};
 
 const TOPIC_PREFIX: &str = "df9ff5c8-c030-4e4a-8bae-a415565febd7";
 
-#[derive(Clone)]
-pub struct QueueChatRoom<Q> {
-    queue: Q,
-    topic: String,
-    user_name: String,
-    messages: Arc<RwLock<Vec<ChatMessage>>>,
-}
+/// Stands for every user in topics of rooms hiding who talks
+const HIDDEN_USER: &str = "_";
+
+/// Max number of messages in a single history sync response
+const SYNC_BATCH_SIZE: usize = 20;
+
+/// Max random wait before answering a history sync request. Peers that see
+/// another answer first don't send their own.
+const SYNC_ANSWER_DELAY: Duration = Duration::from_millis(500);
+
+/// Number of bad messages per second handled without slowing down
+const BAD_MESSAGE_BURST: u32 = 10;
+
+/// Error of a message whose payload names another user than the topic it
+/// was published on, e.g. someone speaking for others.
+#[derive(Debug)]
+struct ForgedSender {
+    topic_user: String,
+    claimed: String,
+}
+
+impl std::fmt::Display for ForgedSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Message on the topic of {} names {} as its sender",
+            self.topic_user, self.claimed
+        )
+    }
+}
+
+impl std::error::Error for ForgedSender {}
+
+#[derive(Clone)]
+pub struct QueueChatRoom<Q> {
+    queue: Q,
+    room_topic: String,
+    /// Everyone publishes on the room topic, see [`QueueChatRoom::new_hidden`]
+    hidden_topics: bool,
+    /// Changed with [`ChatRoom::set_nick`], see [`QueueChatRoom::user_name`]
+    user_name: Arc<RwLock<String>>,
+    nicks: Arc<RwLock<Nicks>>,
+    messages: Arc<RwLock<Vec<ChatMessage>>>,
+    /// Sequence number of the next sent message
+    next_seq: Arc<AtomicU64>,
+    store: Option<Arc<dyn MessageStore + Send + Sync>>,
+    /// Number of stored messages loaded on start
+    stored_messages: usize,
+    /// Number of received messages that could not be decrypted or read
+    bad_messages: Arc<AtomicU64>,
+    bad_message_notices: bool,
+    /// Presence, subject and typing of clients not signing messages are taken
+    unsigned_peers: bool,
+    presence: Arc<RwLock<Presence>>,
+    typing: Arc<RwLock<Typing>>,
+    dm_keys: Arc<DmKeyPair>,
+    /// Direct messages public keys of other users, the first verified one seen
+    peer_keys: Arc<RwLock<HashMap<String, [u8; 32]>>>,
+    /// Users told to have sent another direct messages key than the kept one
+    changed_peer_keys: Arc<RwLock<HashSet<String>>>,
+    subject: Arc<RwLock<Option<String>>>,
+    /// Signs every published envelope
+    identity: Arc<IdentityKey>,
+    /// Identity keys of other users, see [`QueueChatRoom::with_identity`]
+    key_directory: Arc<KeyDirectory>,
+    replay: Arc<RwLock<ReplayGuard>>,
+    /// Keys replacing the password key, shared with the queue encrypting messages
+    keyring: Option<Keyring>,
+    /// First names of users allowed to rotate the room key
+    admins: Arc<Vec<String>>,
+    /// Users left out of key rotations by this admin
+    revoked: Arc<RwLock<HashSet<String>>>,
+    /// Chains of members of an end-to-end encrypted room, shared with the
+    /// queue encrypting messages
+    sender_keys: Option<SenderKeys>,
+    /// First names of users the own sender chain was sent to
+    shared_with: Arc<RwLock<HashSet<String>>>,
+    /// User left the room, receiving and heartbeats stop
+    left: Arc<AtomicBool>,
+    /// Wakes receiving up when the user leaves
+    leaving: Arc<Notify>,
+    /// Topic filters subscribed on joining, unsubscribed on leaving
+    topics: Arc<Vec<String>>,
+    /// History sync requests to answer, by requesting user
+    sync_answers: Arc<RwLock<HashMap<String, SyncAnswer>>>,
+    /// Will of the connection, see [`QueueChatRoom::with_will`]
+    will: Option<Will>,
+    /// First names of users by the token of their will
+    wills: Arc<RwLock<HashMap<String, String>>>,
+}
+
+/// History sync request waiting for its answer
+#[derive(Clone, Copy, Debug)]
+struct SyncAnswer {
+    due: tokio::time::Instant,
+    since: Option<DateTime<Local>>,
+}
+
+impl<Q> QueueChatRoom<Q>
+where
+    Q: Queue,
+{
+    pub async fn new(queue: Q, user_name: String, room_name: String) -> Result<Self, Error> {
+        let room_topic = format!("{}/{}", TOPIC_PREFIX, room_name); // TODO: Remove tight coupling with mqtt topic format
+
+        Self::on_topic(queue, user_name, room_topic, false).await
+    }
+
+    /// Joins the room on a topic derived from `room_key`, shared by all users
+    /// and direct messages, so the broker can't tell the room, its members
+    /// or who talks when. Senders are told only by their signatures.
+    pub async fn new_hidden(
+        queue: Q,
+        user_name: String,
+        room_name: String,
+        room_key: &RoomKey,
+    ) -> Result<Self, Error> {
+        let room_topic = format!("{}/{}", TOPIC_PREFIX, room_key.topic_id(&room_name));
+
+        Self::on_topic(queue, user_name, room_topic, true).await
+    }
+
+    async fn on_topic(
+        queue: Q,
+        user_name: String,
+        room_topic: String,
+        hidden_topics: bool,
+    ) -> Result<Self, Error> {
+        let mut chat_room = Self {
+            queue,
+            room_topic,
+            hidden_topics,
+            user_name: Arc::new(RwLock::new(user_name)),
+            nicks: Arc::default(),
+            messages: Arc::default(),
+            next_seq: Arc::new(AtomicU64::new(1)),
+            store: None,
+            stored_messages: 0,
+            bad_messages: Arc::default(),
+            bad_message_notices: false,
+            unsigned_peers: false,
+            presence: Arc::default(),
+            typing: Arc::default(),
+            dm_keys: Arc::new(DmKeyPair::generate()),
+            peer_keys: Arc::default(),
+            changed_peer_keys: Arc::default(),
+            subject: Arc::default(),
+            identity: Arc::new(IdentityKey::generate()),
+            key_directory: Arc::default(),
+            replay: Arc::default(),
+            keyring: None,
+            admins: Arc::default(),
+            revoked: Arc::default(),
+            sender_keys: None,
+            shared_with: Arc::default(),
+            left: Arc::default(),
+            leaving: Arc::default(),
+            topics: Arc::default(),
+            sync_answers: Arc::default(),
+            will: None,
+            wills: Arc::default(),
+        };
+
+        let mut topics = if hidden_topics {
+            vec![
+                chat_room.room_topic.clone(),
+                chat_room.key_topic(HIDDEN_USER),
+            ]
+        } else {
+            // Name may change, so direct messages to anyone are received and filtered
+            vec![
+                format!("{}/+", chat_room.room_topic),
+                chat_room.dm_topic("+"),
+                chat_room.key_topic("+"),
+            ]
+        };
+        topics.push(WILL_TOPIC_FILTER.to_owned());
+        for topic in &topics {
+            chat_room.queue.subscribe(topic.clone()).await?;
+        }
+        chat_room.topics = Arc::new(topics);
+
+        Ok(chat_room)
+    }
+
+    /// Tells members the token of `will`, given to the queue when connecting,
+    /// so they know this user left when the broker publishes it
+    pub fn with_will(mut self, will: Will) -> Self {
+        self.will = Some(will);
+        self
+    }
+
+    /// Keeps received messages in `store` and shows up to `count` of the newest
+    /// stored ones when started
+    pub fn with_store(
+        mut self,
+        store: impl MessageStore + Send + Sync + 'static,
+        count: usize,
+    ) -> Self {
+        self.store = Some(Arc::new(store));
+        self.stored_messages = count;
+        self
+    }
+
+    /// Signs sent messages with `identity` and checks senders against keys
+    /// in `key_directory`. Without it a new identity is made and keys of
+    /// others are forgotten when the room is left.
+    pub fn with_identity(
+        mut self,
+        identity: IdentityKey,
+        key_directory: Arc<KeyDirectory>,
+    ) -> Self {
+        self.identity = Arc::new(identity);
+        self.key_directory = key_directory;
+        self
+    }
+
+    /// Agrees direct messages keys with `dm_keys` kept across restarts, so
+    /// others can keep the first key they saw. Without it a new one is made.
+    pub fn with_dm_keys(mut self, dm_keys: DmKeyPair) -> Self {
+        self.dm_keys = Arc::new(dm_keys);
+        self
+    }
+
+    /// Takes part in key rotations of `admins`, with `keyring` shared with the
+    /// queue encrypting messages of the room
+    pub fn with_keyring(mut self, keyring: Keyring, admins: Vec<String>) -> Self {
+        self.keyring = Some(keyring);
+        self.admins = Arc::new(admins);
+        self
+    }
+
+    /// Encrypts the room end-to-end with `sender_keys` shared with the queue,
+    /// handing own chain to members and taking theirs
+    pub fn with_sender_keys(mut self, sender_keys: SenderKeys) -> Self {
+        self.sender_keys = Some(sender_keys);
+        self
+    }
+
+    /// Shows a system line for received messages that could not be read
+    pub fn with_bad_message_notices(mut self) -> Self {
+        self.bad_message_notices = true;
+        self
+    }
+
+    /// Takes presence, subject and typing of clients from before signing,
+    /// anyone with the room password can then pass for them
+    pub fn with_unsigned_peers(mut self) -> Self {
+        self.unsigned_peers = true;
+        self
+    }
+
+    pub fn bad_messages(&self) -> u64 {
+        self.bad_messages.load(Ordering::Relaxed)
+    }
+
+    pub async fn run(&mut self) -> Result<(), Error> {
+        if let Some(store) = &self.store {
+            let mut nicks = self.nicks.write().expect("Poisoned mutex");
+            for (old, new) in store.load_renames()? {
+                nicks.renamed(&old, &new);
+            }
+            drop(nicks);
+            let stored = store.load_last(self.stored_messages)?;
+            self.messages
+                .write()
+                .expect("Poisoned mutex")
+                .splice(0..0, stored);
+        }
+        self.continue_sequence();
+
+        // Relayed history would reach members with keys of the one relaying
+        // it, past what they could read themselves
+        if self.sender_keys.is_none() {
+            self.request_sync().await?;
+        }
+        if self.keyring.is_some() || self.sender_keys.is_some() {
+            self.request_key().await?;
+        }
+
+        let mut bad_message_limit = RateLimit::new(BAD_MESSAGE_BURST, Duration::from_secs(1));
+        while !self.left.load(Ordering::Relaxed) {
+            let sync_answer_due = self.next_sync_answer();
+            let received = tokio::select! {
+                received = self.queue.receive() => received,
+                _ = self.leaving.notified() => break,
+                _ = sleep_until(sync_answer_due) => {
+                    self.answer_due_syncs().await?;
+                    continue;
+                }
+            };
+            let received = match received {
+                Ok(received) => received,
+                Err(e) => match e.downcast_ref::<UndecryptableMessage>() {
+                    Some(undecryptable) => {
+                        let topic = undecryptable.topic.clone();
+                        self.reject(e, topic.as_deref(), &mut bad_message_limit)
+                            .await;
+                        continue;
+                    }
+                    None => return Err(e.context("Queue stopped delivering messages")),
+                },
+            };
+
+            if let Some(token) = will::token(&received.topic) {
+                self.will_published(token).await;
+                continue;
+            }
+
+            let handled = match self.open(&received) {
+                Ok((envelope, signer)) => self.handle(envelope.payload, signer).await,
+                Err(e) => Err(e),
+            };
+            match handled {
+                Ok(()) => {}
+                // Brokers redeliver messages too, a copy is no news
+                Err(e) if e.is::<ReplayedMessage>() => {}
+                Err(e) => {
+                    self.reject(e, Some(&received.topic), &mut bad_message_limit)
+                        .await
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Announces joining the room and then keeps telling peers this user is
+    /// still here. Runs until publishing fails or the user leaves.
+    pub async fn heartbeat(&self) -> Result<(), Error> {
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        interval.tick().await;
+        self.publish_presence(PresenceStatus::Join).await?;
+
+        loop {
+            interval.tick().await;
+            if self.left.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            self.publish_presence(PresenceStatus::Alive).await?;
+        }
+    }
+
+    /// Reads envelope of a received message and tells the key it was signed with
+    fn open(
+        &self,
+        received: &ReceivedMessage,
+    ) -> Result<(Envelope, Option<[u8; PUBLIC_KEY_LEN]>), Error> {
+        let mut envelope = Envelope::decode(&received.payload)?;
+        self.check_sender(&received.topic, &envelope.payload)?;
+        let signer = Envelope::signer(&received.payload)?;
+        let (id, sent) = match (envelope.id, envelope.sent) {
+            (Some(id), Some(sent)) => (id, sent),
+            _ => return Err(UnstampedMessage.into()),
+        };
+        let now = Local::now();
+        self.replay
+            .write()
+            .expect("Poisoned mutex")
+            .check(id, sent, now)?;
+
+        // Messages are shown flagged, so a sender with a clock far off stays
+        // readable, anything else may be a replay of a forgotten envelope
+        match &mut envelope.payload {
+            Payload::Text(msg) => msg.skewed = replay::is_stale(sent, now),
+            Payload::Direct { .. } => {}
+            _ if replay::is_stale(sent, now) => return Err(StaleMessage { sent }.into()),
+            _ => {}
+        }
+
+        Ok((envelope, signer))
+    }
+
+    async fn handle(
+        &self,
+        payload: Payload,
+        signer: Option<[u8; PUBLIC_KEY_LEN]>,
+    ) -> Result<(), Error> {
+        let trust = self.trust(payload.sender(), signer)?;
+
+        match payload {
+            Payload::Text(mut msg) => {
+                let user = self.current_name(&msg.user);
+                self.update_presence(&user, PresenceStatus::Alive);
+                self.typing_stopped(&user);
+                msg.trust = trust;
+                msg.skewed |= replay::is_skewed(msg.time, Local::now());
+                self.add_message(msg);
+                Ok(())
+            }
+            Payload::Typing { user } if user != self.user_name() => {
+                self.check_trusted(trust)?;
+                self.typing
+                    .write()
+                    .expect("Poisoned mutex")
+                    .typing(&self.current_name(&user), Instant::now());
+                Ok(())
+            }
+            Payload::KeyRequest { user, dm_key } if user != self.user_name() => {
+                self.key_requested(&self.current_name(&user), &dm_key, trust)
+                    .await
+            }
+            Payload::SenderKey {
+                from,
+                to,
+                dm_key,
+                sealed,
+            } if to == self.user_name() => {
+                match self.receive_sender_key(&from, &dm_key, &sealed, trust) {
+                    Ok(true) => self.send_sender_key(&from).await,
+                    Ok(false) => Ok(()),
+                    Err(e) => Err(e.context(UndecryptableMessage::default())),
+                }
+            }
+            Payload::RoomKey {
+                from,
+                to,
+                epoch,
+                dm_key,
+                sealed,
+            } if to == self.user_name() => self
+                .receive_room_key(&from, epoch, &dm_key, &sealed, trust)
+                .map_err(|e| e.context(UndecryptableMessage::default())),
+            Payload::Nick { user, nick } => {
+                if trust != Trust::Verified {
+                    anyhow::bail!("Rename to {} is not signed with a known key", nick);
+                }
+                // Own renames are taken when sent
+                if self.current_name(&user) == nick {
+                    return Ok(());
+                }
+                super::validate_name(&nick)?;
+                if self.name_taken(&nick, &self.first_name(&user)) {
+                    anyhow::bail!("Rename to {} takes a name already in use", nick);
+                }
+                self.renamed(&user, &nick);
+                self.store_rename(&user, &nick);
+                Ok(())
+            }
+            Payload::Presence {
+                user,
+                status,
+                dm_key,
+                will,
+            } => {
+                self.check_trusted(trust)?;
+                let user = self.current_name(&user);
+                if let Some(key) = dm_key.and_then(|key| direct::decode_key(&key).ok()) {
+                    self.pin_peer_key(&user, key, trust);
+                }
+                // Anyone with the password could otherwise make a member
+                // leave and have everyone change their keys
+                let verified = trust == Trust::Verified;
+                if let Some(token) = will.filter(|_| verified) {
+                    self.wills
+                        .write()
+                        .expect("Poisoned mutex")
+                        .entry(token)
+                        .or_insert_with(|| self.first_name(&user));
+                }
+                match status {
+                    PresenceStatus::Leave if verified => self.user_left(&user).await,
+                    status => self.update_presence(&user, status),
+                }
+                // Let the newcomer know who is here without waiting for heartbeats
+                if status == PresenceStatus::Join && user != self.user_name() {
+                    self.publish_presence(PresenceStatus::Alive).await?;
+                    if let Some(subject) = self.known_subject() {
+                        self.publish_subject(subject).await?;
+                    }
+                }
+                Ok(())
+            }
+            Payload::Subject { user, subject } => {
+                self.check_trusted(trust)?;
+                let mut known = self.subject.write().expect("Poisoned mutex");
+                if known.as_ref() != Some(&subject) {
+                    self.add_notice(format!("{} set the topic: {}", user, subject));
+                    *known = Some(subject);
+                }
+                Ok(())
+            }
+            Payload::Direct { from, to, sealed } if to == self.user_name() => self
+                .receive_direct(from, sealed, trust)
+                .map_err(|e| e.context(UndecryptableMessage::default())),
+            Payload::SyncRequest { sync_from, since }
+                if sync_from != self.user_name() && self.sender_keys.is_none() =>
+            {
+                self.schedule_sync_answer(sync_from, since);
+                Ok(())
+            }
+            Payload::SyncResponse {
+                from,
+                sync_to,
+                messages,
+            } if sync_to == self.user_name() => {
+                // Anyone could otherwise put words in the mouth of others,
+                // and have them stored
+                if trust != Trust::Verified {
+                    anyhow::bail!("History from {} is not signed with a known key", from);
+                }
+                self.merge_messages(messages);
+                Ok(())
+            }
+            Payload::SyncResponse { sync_to, .. } => {
+                // Someone else answered already
+                self.sync_answers
+                    .write()
+                    .expect("Poisoned mutex")
+                    .remove(&sync_to);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn receive_direct(&self, from: String, sealed: String, trust: Trust) -> Result<(), Error> {
+        let key = self
+            .peer_key(&from)
+            .ok_or_else(|| anyhow::anyhow!("Direct message from {} with unknown key", from))?;
+        let mut msg: ChatMessage = direct::open(&self.dm_keys, key, &sealed)?;
+        if msg.user != from {
+            anyhow::bail!("Direct message from {} signed as {}", from, msg.user);
+        }
+        msg.kind = MessageKind::Direct {
+            to: self.user_name(),
+        };
+        msg.trust = trust;
+        msg.skewed = replay::is_skewed(msg.time, Local::now());
+
+        self.typing_stopped(&from);
+        self.add_message(msg);
+
+        Ok(())
+    }
+
+    async fn key_requested(&self, user: &str, dm_key: &str, trust: Trust) -> Result<(), Error> {
+        let key = direct::decode_key(dm_key)?;
+        // Sealing room keys to whoever asks in someone's name would let
+        // revoked members back in
+        if !self.pin_peer_key(user, key, trust) {
+            if trust != Trust::Verified {
+                self.add_notice(format!(
+                    "Ignored keys of {}, they are not signed with their known identity",
+                    user
+                ));
+            }
+            return Ok(());
+        }
+
+        if self.sender_keys.is_some() {
+            return self.send_sender_key(user).await;
+        }
+
+        let rotated = matches!(&self.keyring, Some(keyring) if keyring.current_epoch() > 0);
+        if rotated && self.is_admin(&self.user_name()) {
+            self.add_notice(format!(
+                "{} asks for the room key, /invite {} to let them in",
+                user, user
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn receive_room_key(
+        &self,
+        from: &str,
+        epoch: u32,
+        dm_key: &str,
+        sealed: &str,
+        trust: Trust,
+    ) -> Result<(), Error> {
+        let keyring = match &self.keyring {
+            Some(keyring) => keyring,
+            None => return Ok(()),
+        };
+        if !self.is_admin(from) || trust != Trust::Verified {
+            self.add_notice(format!(
+                "Ignored a room key from {}, only verified admins can change it",
+                from
+            ));
+            return Ok(());
+        }
+
+        let sender_key = direct::decode_key(dm_key)?;
+        let key: String = direct::open(&self.dm_keys, sender_key, sealed)?;
+        let key = RoomKey::from_bytes(direct::decode_key(&key)?);
+        if keyring.rotate(epoch, key)? {
+            self.add_notice(format!("{} changed the room key", from));
+        }
+
+        Ok(())
+    }
+
+    /// Reads messages of `from` with their sealed sender chain. Returns
+    /// whether own chain is to be sent back.
+    fn receive_sender_key(
+        &self,
+        from: &str,
+        dm_key: &str,
+        sealed: &str,
+        trust: Trust,
+    ) -> Result<bool, Error> {
+        let sender_keys = match &self.sender_keys {
+            Some(sender_keys) => sender_keys,
+            None => return Ok(false),
+        };
+        if trust != Trust::Verified {
+            self.add_notice(format!(
+                "Ignored keys of {}, they are not signed with their known identity",
+                from
+            ));
+            return Ok(false);
+        }
+
+        let sender_key = direct::decode_key(dm_key)?;
+        if !self.pin_peer_key(from, sender_key, trust) {
+            return Ok(false);
+        }
+        let chain: SenderChain = direct::open(&self.dm_keys, sender_key, sealed)?;
+        let first_name = self.first_name(from);
+        sender_keys.add(&first_name, chain);
+        // Their presence may have been sent before the chain arrived
+        self.update_presence(from, PresenceStatus::Alive);
+
+        let shared = self.shared_with.read().expect("Poisoned mutex");
+        Ok(!shared.contains(&first_name))
+    }
+
+    /// Seals own sender chain for `to`, from the next message on
+    async fn send_sender_key(&self, to: &str) -> Result<(), Error> {
+        let sender_keys = match &self.sender_keys {
+            Some(sender_keys) => sender_keys,
+            None => return Ok(()),
+        };
+        let first_name = self.first_name(to);
+        if self
+            .revoked
+            .read()
+            .expect("Poisoned mutex")
+            .contains(&first_name)
+        {
+            return Ok(());
+        }
+        let peer_key = self.peer_key(to).ok_or_else(|| {
+            anyhow::anyhow!("Can't send your keys to {}, their key is not known", to)
+        })?;
+        let sealed = direct::seal(&self.dm_keys, peer_key, &sender_keys.own_chain())?;
+        self.shared_with
+            .write()
+            .expect("Poisoned mutex")
+            .insert(first_name);
+
+        self.publish_to(
+            self.key_topic(to),
+            Payload::SenderKey {
+                from: self.user_name(),
+                to: to.to_string(),
+                dm_key: direct::encode_key(self.dm_keys.public_key()),
+                sealed,
+            },
+        )
+        .await
+    }
+
+    /// Starts a new own sender chain and sends it to members who may read
+    /// the room, so anyone else is left out from now on
+    async fn rotate_sender_key(&self) {
+        let sender_keys = match &self.sender_keys {
+            Some(sender_keys) => sender_keys,
+            None => return,
+        };
+        sender_keys.rotate();
+        self.shared_with.write().expect("Poisoned mutex").clear();
+
+        for member in self.key_recipients() {
+            if let Err(e) = self.send_sender_key(&member).await {
+                self.add_notice(format!("{:#}", e));
+            }
+        }
+    }
+
+    fn members(&self) -> Vec<Member> {
+        let mut presence = self.presence.read().expect("Poisoned mutex").clone();
+        // Own heartbeats may not have come back yet
+        presence.seen(&self.user_name(), Instant::now());
+
+        let mut members = presence.members(Instant::now());
+        for member in &mut members {
+            member.alias_of = self.alias_of(&member.name);
+        }
+
+        members
+    }
+
+    /// Online members other than this user, leaving revoked ones out
+    fn key_recipients(&self) -> Vec<String> {
+        let user_name = self.user_name();
+        let revoked = self.revoked.read().expect("Poisoned mutex");
+
+        self.members()
+            .into_iter()
+            .filter(|member| member.status != MemberStatus::Offline && member.name != user_name)
+            .filter(|member| !revoked.contains(member.alias_of.as_ref().unwrap_or(&member.name)))
+            .map(|member| member.name)
+            .collect()
+    }
+
+    fn revoke(&self, user: &str) {
+        let first_name = self.first_name(user);
+        self.revoked
+            .write()
+            .expect("Poisoned mutex")
+            .insert(first_name);
+    }
+
+    async fn request_key(&self) -> Result<(), Error> {
+        let user = self.user_name();
+        self.publish_to(
+            self.key_topic(&user),
+            Payload::KeyRequest {
+                user,
+                dm_key: direct::encode_key(self.dm_keys.public_key()),
+            },
+        )
+        .await
+    }
+
+    async fn send_room_key(&self, to: &str, epoch: u32, key: &RoomKey) -> Result<(), Error> {
+        let peer_key = self.peer_key(to).ok_or_else(|| {
+            anyhow::anyhow!("Can't send the room key to {}, their key is not known", to)
+        })?;
+        let sealed = direct::seal(
+            &self.dm_keys,
+            peer_key,
+            &direct::encode_key(*key.as_bytes()),
+        )?;
+
+        self.publish_to(
+            self.key_topic(to),
+            Payload::RoomKey {
+                from: self.user_name(),
+                to: to.to_string(),
+                epoch,
+                dm_key: direct::encode_key(self.dm_keys.public_key()),
+                sealed,
+            },
+        )
+        .await
+    }
+
+    /// Keyring of the room if this user may change its key
+    fn admin_keyring(&self) -> Result<&Keyring, Error> {
+        let keyring = self
+            .keyring
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Room key can't be changed in this room"))?;
+        if !self.is_admin(&self.user_name()) {
+            anyhow::bail!("Only admins can change the room key");
+        }
+
+        Ok(keyring)
+    }
+
+    fn is_admin(&self, user: &str) -> bool {
+        let nicks = self.nicks.read().expect("Poisoned mutex");
+        self.admins
+            .iter()
+            .any(|admin| admin == nicks.first_name(user))
+    }
+
+    /// How far `sender` is known to have signed the message. Keys are
+    /// remembered by the first name, so they follow renamed users.
+    fn trust(
+        &self,
+        sender: Option<&str>,
+        signer: Option<[u8; PUBLIC_KEY_LEN]>,
+    ) -> Result<Trust, Error> {
+        match (sender, signer) {
+            (None, _) => Ok(Trust::Unknown),
+            (Some(_), None) => Ok(Trust::Unsigned),
+            (Some(sender), Some(key)) => {
+                let nicks = self.nicks.read().expect("Poisoned mutex");
+                self.key_directory.check(nicks.first_name(sender), key)
+            }
+        }
+    }
+
+    /// Payloads changing what is shown of their sender are taken only from
+    /// the sender, or from clients not signing when those are let in
+    fn check_trusted(&self, trust: Trust) -> Result<(), Error> {
+        match trust {
+            Trust::Verified => Ok(()),
+            Trust::Unsigned if self.unsigned_peers => Ok(()),
+            Trust::Unsigned => anyhow::bail!("Message is not signed"),
+            Trust::KeyChanged => {
+                anyhow::bail!("Message is signed with another key than the one first seen")
+            }
+            Trust::Unknown => anyhow::bail!("Message names no sender"),
+        }
+    }
+
+    /// User publishing on `topic` as told by the topic alone, not by direct
+    /// message, key or hidden topics
+    fn topic_user<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        if self.hidden_topics {
+            return None;
+        }
+        topic
+            .strip_prefix(&self.room_topic)?
+            .strip_prefix('/')
+            .filter(|user| !user.contains('/'))
+    }
+
+    /// Every user publishes on their own topic and direct messages on the
+    /// recipient's one, payloads naming someone else are forged
+    fn check_sender(&self, topic: &str, payload: &Payload) -> Result<(), Error> {
+        if self.hidden_topics {
+            return self.check_hidden_topic(topic, payload);
+        }
+
+        let topic_user = topic
+            .strip_prefix(&self.room_topic)
+            .and_then(|topic| topic.strip_prefix('/'))
+            .ok_or_else(|| anyhow::anyhow!("Message from outside the room on {}", topic))?;
+
+        let key_topic_user = topic_user
+            .strip_prefix(KEY_TOPIC_SEGMENT)
+            .and_then(|topic| topic.strip_prefix('/'));
+        let (expected, claimed) = match (topic_user.strip_prefix("dm/"), key_topic_user, payload) {
+            (Some(to), _, Payload::Direct { to: claimed, .. }) => (to, claimed.as_str()),
+            (Some(to), _, _) => anyhow::bail!("Message other than direct one sent to {}", to),
+            (_, Some(user), Payload::KeyRequest { user: claimed, .. })
+            | (_, Some(user), Payload::SenderKey { to: claimed, .. })
+            | (_, Some(user), Payload::RoomKey { to: claimed, .. }) => (user, claimed.as_str()),
+            (_, Some(user), _) => anyhow::bail!("Message other than room key one for {}", user),
+            (None, None, payload) => match payload.sender() {
+                Some(claimed) => (topic_user, claimed),
+                None => return Ok(()),
+            },
+        };
+
+        if expected != claimed {
+            return Err(ForgedSender {
+                topic_user: expected.to_owned(),
+                claimed: claimed.to_owned(),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Topics of a hidden room tell no one apart, only key exchange has a
+    /// topic of its own
+    fn check_hidden_topic(&self, topic: &str, payload: &Payload) -> Result<(), Error> {
+        let key_exchange = matches!(
+            payload,
+            Payload::KeyRequest { .. } | Payload::SenderKey { .. } | Payload::RoomKey { .. }
+        );
+        match (topic == self.room_topic, is_key_topic(topic)) {
+            (true, _) if !key_exchange => Ok(()),
+            (false, true) if key_exchange && topic == self.key_topic(HIDDEN_USER) => Ok(()),
+            _ => anyhow::bail!("Message on unexpected topic {}", topic),
+        }
+    }
+
+    /// Keeps `key` of `user` if it came in a verified message and no other
+    /// one is kept yet. A changed key is refused and reported once.
+    fn pin_peer_key(&self, user: &str, key: [u8; 32], trust: Trust) -> bool {
+        if trust != Trust::Verified {
+            return false;
+        }
+
+        let mut peer_keys = self.peer_keys.write().expect("Poisoned mutex");
+        match peer_keys.get(user) {
+            Some(known) if *known == key => true,
+            Some(_) => {
+                drop(peer_keys);
+                let first_report = self
+                    .changed_peer_keys
+                    .write()
+                    .expect("Poisoned mutex")
+                    .insert(user.to_string());
+                if first_report {
+                    self.add_notice(format!(
+                        "{} sent another direct messages key, the one seen first is kept",
+                        user
+                    ));
+                }
+                false
+            }
+            None => {
+                peer_keys.insert(user.to_string(), key);
+                true
+            }
+        }
+    }
+
+    fn peer_key(&self, user: &str) -> Option<[u8; 32]> {
+        self.peer_keys
+            .read()
+            .expect("Poisoned mutex")
+            .get(user)
+            .copied()
+    }
+
+    pub fn user_name(&self) -> String {
+        self.user_name.read().expect("Poisoned mutex").clone()
+    }
+
+    /// Topic this user publishes on
+    fn topic(&self) -> String {
+        if self.hidden_topics {
+            return self.room_topic.clone();
+        }
+        format!("{}/{}", self.room_topic, self.user_name())
+    }
+
+    fn first_name(&self, user: &str) -> String {
+        let nicks = self.nicks.read().expect("Poisoned mutex");
+        nicks.first_name(user).to_string()
+    }
+
+    fn current_name(&self, user: &str) -> String {
+        let nicks = self.nicks.read().expect("Poisoned mutex");
+        nicks.current_name(user).to_string()
+    }
+
+    fn alias_of(&self, user: &str) -> Option<String> {
+        self.nicks.read().expect("Poisoned mutex").alias_of(user)
+    }
+
+    /// Links the old and the new name, so presence, keys and colours follow the person
+    fn renamed(&self, old: &str, new: &str) {
+        self.nicks
+            .write()
+            .expect("Poisoned mutex")
+            .renamed(old, new);
+        self.presence
+            .write()
+            .expect("Poisoned mutex")
+            .renamed(old, new);
+        self.typing_stopped(old);
+
+        let mut peer_keys = self.peer_keys.write().expect("Poisoned mutex");
+        if let Some(key) = peer_keys.remove(old) {
+            peer_keys.insert(new.to_string(), key);
+        }
+        drop(peer_keys);
+
+        self.add_notice(format!("{} is now known as {}", old, new));
+    }
+
+    /// Someone else than the person first known as `first` went by `name`,
+    /// taking it over would let them pass for that person
+    fn name_taken(&self, name: &str, first: &str) -> bool {
+        let nicks = self.nicks.read().expect("Poisoned mutex");
+        if nicks.first_name(name) == first {
+            return false;
+        }
+
+        nicks.knows(name)
+            || self.presence.read().expect("Poisoned mutex").knows(name)
+            || self.key_directory.knows(name)
+            || name == self.user_name()
+    }
+
+    fn store_rename(&self, old: &str, new: &str) {
+        let stored = match &self.store {
+            Some(store) => store.append_rename(old, new),
+            None => Ok(()),
+        };
+        if let Err(e) = stored {
+            self.add_notice(format!("Could not store rename: {:#}", e));
+        }
+    }
+
+    fn dm_topic(&self, user: &str) -> String {
+        if self.hidden_topics {
+            return self.room_topic.clone();
+        }
+        format!("{}/dm/{}", self.room_topic, user)
+    }
+
+    fn key_topic(&self, user: &str) -> String {
+        let user = if self.hidden_topics {
+            HIDDEN_USER
+        } else {
+            user
+        };
+        format!("{}/{}/{}", self.room_topic, KEY_TOPIC_SEGMENT, user)
+    }
+
+    /// Counts and reports a message that could not be read. Past the limit
+    /// receiving is paused, so a flood of junk can't keep the CPU busy.
+    async fn reject(&self, error: Error, topic: Option<&str>, limit: &mut RateLimit) {
+        self.bad_messages.fetch_add(1, Ordering::Relaxed);
+
+        let now = Instant::now();
+        if !limit.allow(now) {
+            tokio::time::sleep(limit.remaining(now)).await;
+            return;
+        }
+
+        if self.bad_message_notices {
+            let from = topic
+                .and_then(|topic| self.topic_user(topic))
+                .map(|user| format!(" from {}", user))
+                .unwrap_or_default();
+            let notice = if let Some(forged) = error.downcast_ref::<ForgedSender>() {
+                // Anyone can publish on any topic, so neither name tells who sent it
+                format!(
+                    "Dropped a message whose sender {} does not match its topic of {}",
+                    forged.claimed, forged.topic_user
+                )
+            } else if let Some(stale) = error.downcast_ref::<StaleMessage>() {
+                format!(
+                    "Dropped a message sent at {}, it may be replayed or the sender's clock is off",
+                    stale.sent.format("%Y-%m-%d %H:%M:%S")
+                )
+            } else if error.is::<UndecryptableMessage>() {
+                format!(
+                    "Could not decrypt a message{}, it may use another room password",
+                    from
+                )
+            } else {
+                format!("Could not read a message{}: {:#}", from, error)
+            };
+            self.add_notice(notice);
+        }
+    }
+
+    /// Asks online peers for messages newer than the newest one already known
+    async fn request_sync(&self) -> Result<(), Error> {
+        let since = self
+            .messages
+            .read()
+            .expect("Poisoned mutex")
+            .iter()
+            .rev()
+            .find(|msg| msg.kind == MessageKind::Text)
+            .map(|msg| msg.time);
+
+        self.publish(Payload::SyncRequest {
+            sync_from: self.user_name(),
+            since,
+        })
+        .await
+    }
+
+    /// Answers the request after a random delay, so that not every peer does
+    fn schedule_sync_answer(&self, sync_to: String, since: Option<DateTime<Local>>) {
+        let delay = rand::thread_rng().gen_range(0..=SYNC_ANSWER_DELAY.as_millis() as u64);
+        self.sync_answers
+            .write()
+            .expect("Poisoned mutex")
+            .entry(sync_to)
+            .or_insert(SyncAnswer {
+                due: tokio::time::Instant::now() + Duration::from_millis(delay),
+                since,
+            });
+    }
+
+    /// When the earliest scheduled sync answer is due
+    fn next_sync_answer(&self) -> Option<tokio::time::Instant> {
+        self.sync_answers
+            .read()
+            .expect("Poisoned mutex")
+            .values()
+            .map(|answer| answer.due)
+            .min()
+    }
+
+    async fn answer_due_syncs(&self) -> Result<(), Error> {
+        let now = tokio::time::Instant::now();
+        let due = {
+            let mut answers = self.sync_answers.write().expect("Poisoned mutex");
+            let due = answers
+                .iter()
+                .filter(|(_, answer)| answer.due <= now)
+                .map(|(sync_to, answer)| (sync_to.clone(), answer.since))
+                .collect::<Vec<_>>();
+            due.iter().for_each(|(sync_to, _)| {
+                answers.remove(sync_to);
+            });
+            due
+        };
+
+        for (sync_to, since) in due {
+            self.answer_sync(sync_to, since).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn answer_sync(
+        &self,
+        sync_to: String,
+        since: Option<DateTime<Local>>,
+    ) -> Result<(), Error> {
+        let missing = self
+            .messages
+            .read()
+            .expect("Poisoned mutex")
+            .iter()
+            .filter(|msg| msg.kind == MessageKind::Text)
+            .filter(|msg| !matches!(since, Some(since) if msg.time <= since))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for batch in missing.chunks(SYNC_BATCH_SIZE) {
+            self.publish(Payload::SyncResponse {
+                from: self.user_name(),
+                sync_to: sync_to.clone(),
+                messages: batch.to_vec(),
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn publish_subject(&self, subject: String) -> Result<(), Error> {
+        self.publish(Payload::Subject {
+            user: self.user_name(),
+            subject,
+        })
+        .await
+    }
+
+    fn known_subject(&self) -> Option<String> {
+        self.subject.read().expect("Poisoned mutex").clone()
+    }
+
+    async fn publish_presence(&self, status: PresenceStatus) -> Result<(), Error> {
+        self.publish(self.presence_payload(status)).await
+    }
+
+    fn presence_payload(&self, status: PresenceStatus) -> Payload {
+        Payload::Presence {
+            user: self.user_name(),
+            status,
+            dm_key: Some(direct::encode_key(self.dm_keys.public_key())),
+            will: self.will.as_ref().map(|will| will.token().to_owned()),
+        }
+    }
+
+    /// Broker told the connection of whoever announced will `token` is lost
+    async fn will_published(&self, token: &str) {
+        let user = self.wills.write().expect("Poisoned mutex").remove(token);
+        if let Some(user) = user {
+            self.user_left(&self.current_name(&user)).await;
+        }
+    }
+
+    async fn user_left(&self, user: &str) {
+        let first_name = self.first_name(user);
+        self.wills
+            .write()
+            .expect("Poisoned mutex")
+            .retain(|_, owner| *owner != first_name);
+        self.update_presence(user, PresenceStatus::Leave);
+        // Whoever left must not read what is sent from now on
+        if user != self.user_name() {
+            self.rotate_sender_key().await;
+        }
+    }
+
+    fn update_presence(&self, user: &str, status: PresenceStatus) {
+        let mut presence = self.presence.write().expect("Poisoned mutex");
+        match status {
+            PresenceStatus::Join | PresenceStatus::Alive => presence.seen(user, Instant::now()),
+            PresenceStatus::Leave => {
+                presence.left(user, Instant::now());
+                self.typing_stopped(user);
+            }
+        }
+    }
+
+    fn typing_stopped(&self, user: &str) {
+        self.typing.write().expect("Poisoned mutex").stopped(user);
+    }
+
+    async fn publish(&self, payload: Payload) -> Result<(), Error> {
+        self.publish_to(self.topic(), payload).await
+    }
+
+    async fn publish_to(&self, topic: String, payload: Payload) -> Result<(), Error> {
+        let envelope = Envelope::new(payload).encode_signed(&self.identity)?;
+
+        self.queue.publish(topic, envelope).await
+    }
+
+    fn add_notice(&self, msg: String) {
+        self.messages
+            .write()
+            .expect("Poisoned mutex")
+            .push(ChatMessage::system(msg));
+    }
+
+    /// Makes sent messages follow the newest known message of this user
+    fn continue_sequence(&self) {
+        let user_name = self.user_name();
+        let last_seq = self
+            .messages
+            .read()
+            .expect("Poisoned mutex")
+            .iter()
+            .filter(|msg| msg.user == user_name)
+            .map(|msg| msg.seq)
+            .max()
+            .unwrap_or(0);
+
+        self.next_seq.fetch_max(last_seq + 1, Ordering::Relaxed);
+    }
+
+    /// Appends message unless it was already received, e.g. redelivered by the broker.
+    /// Direct messages are not stored, the store keeps room messages only.
+    /// Message that could not be stored is still shown, followed by a notice.
+    fn add_message(&self, mut msg: ChatMessage) {
+        let mut messages = self.messages.write().expect("Poisoned mutex");
+        if is_known(&messages, &msg) {
+            return;
+        }
+        msg.alias_of = self.alias_of(&msg.user);
+
+        let stored = match (&self.store, &msg.kind) {
+            (Some(store), MessageKind::Text) => store.append(&msg),
+            _ => Ok(()),
+        };
+        messages.push(msg);
+        if let Err(e) = stored {
+            messages.push(not_stored(e));
+        }
+    }
+
+    /// Inserts messages not known yet, keeping the timeline ordered by time.
+    /// Several peers answer the same sync request, so duplicates are expected.
+    fn merge_messages(&self, received: Vec<ChatMessage>) {
+        let mut messages = self.messages.write().expect("Poisoned mutex");
+        let mut known = messages.iter().map(|msg| msg.id).collect::<HashSet<_>>();
+        let mut store_error = None;
+
+        for mut msg in received {
+            if !known.insert(msg.id) {
+                continue;
+            }
+            msg.alias_of = self.alias_of(&msg.user);
+            msg.trust = Trust::Unknown;
+            if let Some(store) = &self.store {
+                if let Err(e) = store.append(&msg) {
+                    store_error = Some(e);
+                }
+            }
+
+            let position = messages.partition_point(|known| known.time <= msg.time);
+            messages.insert(position, msg);
+        }
+
+        if let Some(e) = store_error {
+            messages.push(not_stored(e));
+        }
+    }
+}
+
+/// Sleeps until `due`, forever without it
+async fn sleep_until(due: Option<tokio::time::Instant>) {
+    match due {
+        Some(due) => tokio::time::sleep_until(due).await,
+        None => futures::future::pending().await,
+    }
+}
+
+fn not_stored(e: Error) -> ChatMessage {
+    ChatMessage::system(format!("Could not store messages: {:#}", e))
+}
+
+fn is_known(messages: &[ChatMessage], msg: &ChatMessage) -> bool {
+    messages.iter().any(|known| known.id == msg.id)
+}
+
+#[async_trait::async_trait]
+impl<Q> ChatRoom for QueueChatRoom<Q>
+where
+    Q: Queue + Sync + Send,
+{
+    async fn send(&self, msg: String) -> Result<(), Error> {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let msg = ChatMessage::new(self.user_name(), msg, seq);
+
+        self.publish(Payload::Text(msg)).await
+    }
+
+    async fn send_direct(&self, to: String, msg: String) -> Result<(), Error> {
+        let key = match self.peer_key(&to) {
+            Some(key) => key,
+            None => {
+                self.add_notice(format!(
+                    "Can't message {}, they need to be online to exchange keys first",
+                    to
+                ));
+                return Ok(());
+            }
+        };
+
+        let mut msg = ChatMessage::new(self.user_name(), msg, 0);
+        let sealed = direct::seal(&self.dm_keys, key, &msg)?;
+        let payload = Payload::Direct {
+            from: self.user_name(),
+            to: to.clone(),
+            sealed,
+        };
+        self.publish_to(self.dm_topic(&to), payload).await?;
+
+        msg.kind = MessageKind::Direct { to };
+        msg.trust = Trust::Verified;
+        self.add_message(msg);
+
+        Ok(())
+    }
+
+    fn get_messages(&self) -> Vec<ChatMessage> {
+        let messages = self.messages.read().expect("Poisoned mutex");
+
+        messages.to_owned()
+    }
+
+    fn get_members(&self) -> Vec<Member> {
+        self.members()
+    }
+
+    async fn send_typing(&self) -> Result<(), Error> {
+        self.publish(Payload::Typing {
+            user: self.user_name(),
+        })
+        .await
+    }
+
+    fn get_typing(&self) -> Vec<String> {
+        self.typing
+            .read()
+            .expect("Poisoned mutex")
+            .users(Instant::now())
+    }
+
+    fn connection_state(&self) -> ConnectionState {
+        self.queue.connection_state()
+    }
+
+    fn notice(&self, msg: String) {
+        self.add_notice(msg)
+    }
+
+    async fn set_subject(&self, subject: String) -> Result<(), Error> {
+        self.publish_subject(subject).await
+    }
+
+    fn get_subject(&self) -> Option<String> {
+        self.known_subject()
+    }
+
+    async fn set_nick(&self, nick: String) -> Result<(), Error> {
+        super::validate_name(&nick)?;
+        let user_name = self.user_name();
+        if nick == user_name {
+            return Ok(());
+        }
+        // Others would refuse the rename the same way
+        if self.name_taken(&nick, &self.first_name(&user_name)) {
+            anyhow::bail!("{} is a name already in use", nick);
+        }
+
+        // Sent on the old topic, receivers check it against the old name
+        self.publish(Payload::Nick {
+            user: user_name.clone(),
+            nick: nick.clone(),
+        })
+        .await?;
+        self.renamed(&user_name, &nick);
+        self.store_rename(&user_name, &nick);
+        *self.user_name.write().expect("Poisoned mutex") = nick;
+
+        Ok(())
+    }
+
+    async fn leave(&self) -> Result<(), Error> {
+        self.left.store(true, Ordering::Relaxed);
+        self.leaving.notify_one();
+        self.publish_presence(PresenceStatus::Leave).await?;
+
+        for topic in self.topics.iter() {
+            self.queue.unsubscribe(topic.clone()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn rotate_key(&self, revoked: Option<String>) -> Result<(), Error> {
+        // In end-to-end encrypted rooms everyone changes their own chain
+        let keyring = match &self.sender_keys {
+            Some(_) => None,
+            None => Some(self.admin_keyring()?),
+        };
+        if let Some(user) = &revoked {
+            self.revoke(user);
+        }
+
+        match keyring {
+            Some(keyring) => {
+                let epoch = keyring.current_epoch() + 1;
+                let key = RoomKey::from_bytes(rand::random());
+                for member in self.key_recipients() {
+                    if let Err(e) = self.send_room_key(&member, epoch, &key).await {
+                        self.add_notice(format!("{:#}", e));
+                    }
+                }
+                keyring.rotate(epoch, key)?;
+            }
+            None => self.rotate_sender_key().await,
+        }
+
+        self.add_notice(match revoked {
+            Some(user) if keyring.is_none() => format!(
+                "Changed your key, {} can't read what you send any longer",
+                user
+            ),
+            Some(user) => format!(
+                "Changed the room key, {} can't read the room any longer",
+                user
+            ),
+            None => "Changed the room key".into(),
+        });
+
+        Ok(())
+    }
+
+    async fn invite(&self, user: String) -> Result<(), Error> {
+        let keyring = self.admin_keyring()?;
+        let (epoch, key) = match keyring.current_key() {
+            Some(current) => current,
+            None => {
+                self.add_notice(format!(
+                    "Room key was not changed, {} can join with the password",
+                    user
+                ));
+                return Ok(());
+            }
+        };
+
+        let first_name = self
+            .nicks
+            .read()
+            .expect("Poisoned mutex")
+            .first_name(&user)
+            .to_string();
+        self.revoked
+            .write()
+            .expect("Poisoned mutex")
+            .remove(&first_name);
+        self.send_room_key(&user, epoch, &key).await?;
+        self.add_notice(format!("Sent the room key to {}", user));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    use crate::{
+        queue::{in_memory::InMemoryBroker, MockQueue, ReceivedMessage},
+        store::MockMessageStore,
+    };
+
+    /// Message as received from test peers, which don't sign envelopes
+    fn message(user: &str, msg: &str) -> ChatMessage {
+        ChatMessage {
+            trust: Trust::Unsigned,
+            ..ChatMessage::new(user.into(), msg.into(), 0)
+        }
+    }
+
+    fn encode(payload: Payload) -> Vec<u8> {
+        Envelope::new(payload).encode().unwrap()
+    }
+
+    /// Message as delivered by the queue, on the topic of its sender
+    fn delivered(msg: Vec<u8>) -> ReceivedMessage {
+        let user = match Envelope::decode(&msg).map(|envelope| envelope.payload) {
+            Ok(Payload::Direct { to, .. }) => format!("dm/{}", to),
+            Ok(payload) => payload.sender().unwrap_or("peer").to_owned(),
+            Err(_) => "peer".to_owned(),
+        };
+
+        ReceivedMessage::new(format!("{}/room/{}", TOPIC_PREFIX, user), msg)
+    }
+
+    fn decode(msg: &[u8]) -> Payload {
+        Envelope::decode(msg).unwrap().payload
+    }
+
+    async fn run_for_a_moment<Q: Queue>(chat_room: &mut QueueChatRoom<Q>) {
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(10), chat_room.run()).await;
+    }
+
+    #[tokio::test]
+    async fn should_subscribe_to_queue() {
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+
+        let sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string()).await;
+
+        assert!(sut.is_ok());
+    }
+
+    #[tokio::test]
+    async fn should_publish_message_to_queue() {
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock
+            .expect_publish()
+            .withf(|_, msg| {
+                matches!(decode(msg), Payload::Text(msg) if msg.user == "user" && msg.msg == "text message")
+            })
+            .times(1..)
+            .returning(|_, _| Ok(()));
+
+        let sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+        let result = sut.send("text message".to_string()).await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn should_publish_message_to_correct_topic() {
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock
+            .expect_publish()
+            .withf(|topic, _| topic.contains("room/user"))
+            .times(1..)
+            .returning(|_, _| Ok(()));
+
+        let sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+        let result = sut.send("text message".to_string()).await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_return_received_messages() {
+        let message = message("user", "text");
+
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        let received = message.clone();
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .returning(move || Ok(delivered(encode(Payload::Text(received.clone())))));
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(10), sut.run()).await;
+
+        let messages = sut.get_messages();
+        assert_eq!(messages, vec![message]);
+    }
+
+    #[tokio::test]
+    async fn should_report_queue_connection_state() {
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock
+            .expect_connection_state()
+            .return_const(ConnectionState::Reconnecting);
+
+        let sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        assert_eq!(sut.connection_state(), ConnectionState::Reconnecting);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_store_received_messages() {
+        let message = message("user", "text");
+
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        let received = message.clone();
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .returning(move || Ok(delivered(encode(Payload::Text(received.clone())))));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut store_mock = MockMessageStore::new();
+        store_mock.expect_load_renames().returning(|| Ok(vec![]));
+        store_mock.expect_load_last().returning(|_| Ok(vec![]));
+        store_mock
+            .expect_append()
+            .withf(move |msg| *msg == message)
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_store(store_mock, 10);
+
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(10), sut.run()).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_show_stored_messages_before_received() {
+        let stored = message("user", "stored");
+        let received = message("user", "received");
+
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        let payload = encode(Payload::Text(received.clone()));
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .returning(move || Ok(delivered(payload.clone())));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut store_mock = MockMessageStore::new();
+        store_mock.expect_load_renames().returning(|| Ok(vec![]));
+        let loaded = stored.clone();
+        store_mock
+            .expect_load_last()
+            .with(mockall::predicate::eq(10))
+            .times(1)
+            .returning(move |_| Ok(vec![loaded.clone()]));
+        store_mock.expect_append().returning(|_| Ok(()));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_store(store_mock, 10);
+
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(10), sut.run()).await;
+
+        assert_eq!(sut.get_messages(), vec![stored, received]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_keep_receiving_when_message_can_not_be_stored() {
+        let first = message("user", "first");
+        let second = message("user", "second");
+
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        for msg in [first.clone(), second.clone()] {
+            let payload = encode(Payload::Text(msg));
+            queue_mock
+                .expect_receive()
+                .times(1)
+                .returning(move || Ok(delivered(payload.clone())));
+        }
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut store_mock = MockMessageStore::new();
+        store_mock.expect_load_renames().returning(|| Ok(vec![]));
+        store_mock.expect_load_last().returning(|_| Ok(vec![]));
+        store_mock
+            .expect_append()
+            .returning(|_| Err(anyhow::anyhow!("disk full")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_store(store_mock, 10);
+
+        run_for_a_moment(&mut sut).await;
+
+        let messages = sut.get_messages();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0], first);
+        assert!(messages[1].msg.contains("disk full"));
+        assert_eq!(messages[2], second);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_request_sync_since_newest_message_on_start() {
+        let stored = message("user", "stored");
+        let since = stored.time;
+
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock
+            .expect_publish()
+            .withf(move |_, msg| {
+                decode(msg)
+                    == Payload::SyncRequest {
+                        sync_from: "user".into(),
+                        since: Some(since),
+                    }
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut store_mock = MockMessageStore::new();
+        store_mock.expect_load_renames().returning(|| Ok(vec![]));
+        store_mock
+            .expect_load_last()
+            .returning(move |_| Ok(vec![stored.clone()]));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_store(store_mock, 10);
+
+        run_for_a_moment(&mut sut).await;
+    }
+
+    /// Runs `sut` while `joiner` asks for history, long enough for any answer,
+    /// and returns payloads published on topics of `sut` and `others`
+    async fn answer_sync_request<Q: Queue>(
+        sut: &mut QueueChatRoom<Q>,
+        broker: &InMemoryBroker,
+        others: Vec<Payload>,
+    ) -> Vec<Payload> {
+        let mut spy = broker.connect();
+        spy.subscribe(format!("{}/room/+", TOPIC_PREFIX))
+            .await
+            .unwrap();
+        let request = Payload::SyncRequest {
+            sync_from: "joiner".into(),
+            since: None,
+        };
+        for payload in std::iter::once(request).chain(others) {
+            let received = delivered(encode(payload));
+            spy.publish(received.topic, received.payload).await.unwrap();
+        }
+
+        let _ = tokio::time::timeout(SYNC_ANSWER_DELAY * 2, sut.run()).await;
+
+        let mut published = Vec::new();
+        while let Ok(Ok(received)) =
+            tokio::time::timeout(std::time::Duration::from_millis(1), spy.receive()).await
+        {
+            if received.topic.ends_with("/user") {
+                published.push(decode(&received.payload));
+            }
+        }
+        published
+    }
+
+    fn sync_responses(published: &[Payload]) -> usize {
+        published
+            .iter()
+            .filter(|payload| {
+                matches!(payload, Payload::SyncResponse { sync_to, .. } if sync_to == "joiner")
+            })
+            .count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_answer_sync_request_in_batches() {
+        let messages = (0..SYNC_BATCH_SIZE + 1)
+            .map(|i| message("user", &i.to_string()))
+            .collect::<Vec<_>>();
+
+        let mut store_mock = MockMessageStore::new();
+        store_mock.expect_load_renames().returning(|| Ok(vec![]));
+        store_mock
+            .expect_load_last()
+            .returning(move |_| Ok(messages.clone()));
+
+        let broker = InMemoryBroker::new();
+        let mut sut = QueueChatRoom::new(broker.connect(), "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_store(store_mock, 100);
+
+        let published = answer_sync_request(&mut sut, &broker, vec![]).await;
+
+        assert_eq!(sync_responses(&published), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_not_answer_sync_request_answered_by_another_peer() {
+        let mut store_mock = MockMessageStore::new();
+        store_mock.expect_load_renames().returning(|| Ok(vec![]));
+        store_mock
+            .expect_load_last()
+            .returning(|_| Ok(vec![message("user", "text")]));
+
+        let broker = InMemoryBroker::new();
+        let mut sut = QueueChatRoom::new(broker.connect(), "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_store(store_mock, 100);
+
+        let answer = Payload::SyncResponse {
+            from: "peer".into(),
+            sync_to: "joiner".into(),
+            messages: vec![message("peer", "text")],
+        };
+        let published = answer_sync_request(&mut sut, &broker, vec![answer]).await;
+
+        assert_eq!(sync_responses(&published), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_merge_synced_messages_without_duplicates() {
+        // Relayed by another peer, so the sender's signature is not there
+        let older = ChatMessage::new("peer".into(), "older".into(), 0);
+        let live = message("peer", "live");
+        let newer = ChatMessage::new("peer".into(), "newer".into(), 0);
+
+        let relay = IdentityKey::generate();
+        let queue_mock = delivering(vec![
+            encode(Payload::Text(live.clone())),
+            signed(
+                sync_response(vec![older.clone(), live.clone(), newer.clone()]),
+                &relay,
+            ),
+            signed(sync_response(vec![older.clone()]), &relay),
+        ]);
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.get_messages(), vec![older, live, newer]);
+    }
+
+    fn sync_response(messages: Vec<ChatMessage>) -> Payload {
+        Payload::SyncResponse {
+            from: "relay".into(),
+            sync_to: "user".into(),
+            messages,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_refuse_history_not_signed_by_relay() {
+        let forged = ChatMessage::new("alice".into(), "forged by mallory".into(), 0);
+        let queue_mock = delivering(vec![encode(sync_response(vec![forged]))]);
+        let mut store_mock = MockMessageStore::new();
+        store_mock.expect_load_renames().returning(|| Ok(vec![]));
+        store_mock.expect_load_last().returning(|_| Ok(vec![]));
+        store_mock.expect_append().never();
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_store(store_mock, 100)
+            .with_unsigned_peers();
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.bad_messages(), 1);
+        assert!(sut.get_messages().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_ignore_sync_response_for_other_user() {
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        let response = encode(Payload::SyncResponse {
+            from: "peer".into(),
+            sync_to: "other".into(),
+            messages: vec![message("peer", "text")],
+        });
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .returning(move || Ok(delivered(response.clone())));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        run_for_a_moment(&mut sut).await;
+
+        assert!(sut.get_messages().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_get_history_from_peers_after_joining() {
+        let broker = InMemoryBroker::new();
+        let mut alice = QueueChatRoom::new(broker.connect(), "alice".into(), "room".into())
+            .await
+            .unwrap();
+        alice.send("one".into()).await.unwrap();
+        alice.send("two".into()).await.unwrap();
+        run_for_a_moment(&mut alice).await;
+
+        let mut bob = QueueChatRoom::new(broker.connect(), "bob".into(), "room".into())
+            .await
+            .unwrap();
+        let _ = tokio::time::timeout(
+            SYNC_ANSWER_DELAY * 2,
+            futures::future::join(alice.run(), bob.run()),
+        )
+        .await;
+
+        let relayed = alice
+            .get_messages()
+            .into_iter()
+            .map(|msg| ChatMessage {
+                trust: Trust::Unknown,
+                ..msg
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(bob.get_messages(), relayed);
+        assert_eq!(bob.get_messages().len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_drop_redelivered_message() {
+        let message = message("peer", "text");
+
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        let payload = encode(Payload::Text(message.clone()));
+        queue_mock
+            .expect_receive()
+            .times(2)
+            .returning(move || Ok(delivered(payload.clone())));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.get_messages(), vec![message]);
+    }
+
+    #[tokio::test]
+    async fn should_number_sent_messages() {
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        let mut seq = mockall::Sequence::new();
+        for expected in 1..=2 {
+            queue_mock
+                .expect_publish()
+                .withf(
+                    move |_, msg| matches!(decode(msg), Payload::Text(msg) if msg.seq == expected),
+                )
+                .times(1)
+                .in_sequence(&mut seq)
+                .returning(|_, _| Ok(()));
+        }
+
+        let sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        sut.send("first".into()).await.unwrap();
+        sut.send("second".into()).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_continue_sequence_of_stored_messages() {
+        let mut stored = message("user", "stored");
+        stored.seq = 41;
+
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock
+            .expect_publish()
+            .withf(|_, msg| matches!(decode(msg), Payload::SyncRequest { .. }))
+            .returning(|_, _| Ok(()));
+        queue_mock
+            .expect_publish()
+            .withf(|_, msg| matches!(decode(msg), Payload::Text(msg) if msg.seq == 42))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut store_mock = MockMessageStore::new();
+        store_mock.expect_load_renames().returning(|| Ok(vec![]));
+        store_mock
+            .expect_load_last()
+            .returning(move |_| Ok(vec![stored.clone()]));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_store(store_mock, 10);
+        run_for_a_moment(&mut sut).await;
+
+        sut.send("next".into()).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_skip_unknown_payload_kinds() {
+        let message = message("peer", "text");
+
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        let mut seq = mockall::Sequence::new();
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| {
+                Ok(delivered(
+                    br#"{"version":2,"kind":"poll","options":[]}"#.to_vec(),
+                ))
+            });
+        let received = message.clone();
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(move || Ok(delivered(encode(Payload::Text(received.clone())))));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.get_messages(), vec![message]);
+    }
+
+    #[tokio::test]
+    async fn should_keep_running_after_bad_messages() {
+        let message = message("peer", "text");
+
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        let mut seq = mockall::Sequence::new();
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| Err(anyhow::Error::new(UndecryptableMessage::default())));
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| Ok(delivered(b"garbage".to_vec())));
+        let received = message.clone();
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(move || Ok(delivered(encode(Payload::Text(received.clone())))));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        let result = sut.run().await;
+
+        // Only the queue failing stops receiving
+        assert_eq!(result.unwrap_err().root_cause().to_string(), "finished");
+        assert_eq!(sut.get_messages(), vec![message]);
+        assert_eq!(sut.bad_messages(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_keep_running_when_handling_message_fails() {
+        let message = message("peer", "text");
+
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock
+            .expect_publish()
+            .withf(|_, msg| matches!(decode(msg), Payload::Presence { .. }))
+            .returning(|_, _| Err(anyhow::anyhow!("disconnected")));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        let mut seq = mockall::Sequence::new();
+        let join = encode(Payload::Presence {
+            user: "peer".into(),
+            status: PresenceStatus::Join,
+            dm_key: None,
+            will: None,
+        });
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(move || Ok(delivered(join.clone())));
+        let received = message.clone();
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(move || Ok(delivered(encode(Payload::Text(received.clone())))));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_unsigned_peers()
+            .with_bad_message_notices();
+
+        run_for_a_moment(&mut sut).await;
+
+        let messages = sut.get_messages();
+        assert!(messages[0].msg.contains("disconnected"));
+        assert_eq!(messages[1], message);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_show_notice_for_bad_message() {
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        queue_mock.expect_receive().times(1).returning(|| {
+            Err(anyhow::Error::new(UndecryptableMessage {
+                topic: Some(format!("{}/room/alice", TOPIC_PREFIX)),
+            }))
+        });
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_bad_message_notices();
+
+        run_for_a_moment(&mut sut).await;
+
+        let messages = sut.get_messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].kind, MessageKind::System);
+        assert_eq!(
+            messages[0].msg,
+            "Could not decrypt a message from alice, it may use another room password"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_drop_message_forged_as_another_user() {
+        let forged = encode(Payload::Text(message("alice", "I owe mallory money")));
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        queue_mock.expect_receive().times(1).returning(move || {
+            Ok(ReceivedMessage::new(
+                format!("{}/room/mallory", TOPIC_PREFIX),
+                forged.clone(),
+            ))
+        });
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_bad_message_notices();
+
+        run_for_a_moment(&mut sut).await;
+
+        let messages = sut.get_messages();
+        assert_eq!(sut.bad_messages(), 1);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].kind, MessageKind::System);
+        assert_eq!(
+            messages[0].msg,
+            "Dropped a message whose sender alice does not match its topic of mallory"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_hide_room_and_users_in_topics() {
+        let broker = InMemoryBroker::new();
+        let mut spy = broker.connect();
+        spy.subscribe("#".into()).await.unwrap();
+        let room_key = RoomKey::from_bytes([7; 32]);
+        let mut rooms = Vec::new();
+        for user in ["alice", "bob"] {
+            let room =
+                QueueChatRoom::new_hidden(broker.connect(), user.into(), "room".into(), &room_key)
+                    .await
+                    .unwrap();
+            rooms.push(room);
+        }
+        let presence = rooms.clone();
+        let (alice, bob) = match &mut rooms[..] {
+            [alice, bob] => (alice, bob),
+            _ => unreachable!(),
+        };
+
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(10), async {
+            tokio::join!(
+                alice.run(),
+                bob.run(),
+                futures::future::join_all(presence.iter().map(|room| room.heartbeat())),
+            )
+        })
+        .await;
+        alice.send("hi".into()).await.unwrap();
+        alice
+            .send_direct("bob".into(), "secret".into())
+            .await
+            .unwrap();
+        let _ = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            futures::future::join(alice.run(), bob.run()),
+        )
+        .await;
+
+        let received = bob.get_messages();
+        assert_eq!(received[0].msg, "hi");
+        assert_eq!(received[0].trust, Trust::Verified);
+        assert_eq!(received[1].msg, "secret");
+        let mut topics = HashSet::new();
+        while let Ok(Ok(received)) =
+            tokio::time::timeout(std::time::Duration::from_millis(1), spy.receive()).await
+        {
+            topics.insert(received.topic);
+        }
+        assert_eq!(
+            topics,
+            HashSet::from([format!("{}/{}", TOPIC_PREFIX, room_key.topic_id(&"room"))])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_drop_message_on_user_topic_in_hidden_room() {
+        let room_key = RoomKey::from_bytes([7; 32]);
+        let topic = format!("{}/{}/alice", TOPIC_PREFIX, room_key.topic_id(&"room"));
+        let msg = encode(Payload::Text(message("alice", "hi")));
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(3).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .returning(move || Ok(ReceivedMessage::new(topic.clone(), msg.clone())));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut =
+            QueueChatRoom::new_hidden(queue_mock, "user".into(), "room".into(), &room_key)
+                .await
+                .unwrap();
+
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.bad_messages(), 1);
+        assert!(sut.get_messages().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_verify_signed_messages() {
+        let broker = InMemoryBroker::new();
+        let mut alice = QueueChatRoom::new(broker.connect(), "alice".into(), "room".into())
+            .await
+            .unwrap();
+        let mut bob = QueueChatRoom::new(broker.connect(), "bob".into(), "room".into())
+            .await
+            .unwrap();
+        alice.send("hi".into()).await.unwrap();
+
+        let _ = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            futures::future::join(alice.run(), bob.run()),
+        )
+        .await;
+
+        let received = bob.get_messages();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].trust, Trust::Verified);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_tell_changed_key_of_known_user() {
+        let keys = Arc::new(KeyDirectory::new());
+        keys.check("alice", IdentityKey::generate().public_key())
+            .unwrap();
+        let broker = InMemoryBroker::new();
+        let mut impostor = QueueChatRoom::new(broker.connect(), "alice".into(), "room".into())
+            .await
+            .unwrap();
+        let mut bob = QueueChatRoom::new(broker.connect(), "bob".into(), "room".into())
+            .await
+            .unwrap()
+            .with_identity(IdentityKey::generate(), keys);
+        impostor.send("hi".into()).await.unwrap();
+
+        let _ = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            futures::future::join(impostor.run(), bob.run()),
+        )
+        .await;
+
+        assert_eq!(bob.get_messages()[0].trust, Trust::KeyChanged);
+    }
+
+    fn presence_with_dm_key(dm_key: &DmKeyPair) -> Payload {
+        Payload::Presence {
+            user: "alice".into(),
+            status: PresenceStatus::Alive,
+            dm_key: Some(direct::encode_key(dm_key.public_key())),
+            will: None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_keep_first_verified_dm_key() {
+        let alice = IdentityKey::generate();
+        let first = DmKeyPair::generate();
+        let mut seq = mockall::Sequence::new();
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        for dm_key in [&first, &DmKeyPair::generate(), &DmKeyPair::generate()] {
+            let presence = Envelope::new(presence_with_dm_key(dm_key))
+                .encode_signed(&alice)
+                .unwrap();
+            queue_mock
+                .expect_receive()
+                .times(1)
+                .in_sequence(&mut seq)
+                .returning(move || Ok(delivered(presence.clone())));
+        }
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.peer_key("alice"), Some(first.public_key()));
+        let notices = sut.get_messages();
+        assert_eq!(notices.len(), 1);
+        assert_eq!(
+            notices[0].msg,
+            "alice sent another direct messages key, the one seen first is kept"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_not_take_dm_key_from_unsigned_presence() {
+        let presence = encode(presence_with_dm_key(&DmKeyPair::generate()));
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .returning(move || Ok(delivered(presence.clone())));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.peer_key("alice"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_drop_message_with_bad_signature() {
+        let mut signed = serde_json::from_slice::<serde_json::Value>(
+            &Envelope::new(Payload::Text(message("alice", "hi")))
+                .encode_signed(&IdentityKey::generate())
+                .unwrap(),
+        )
+        .unwrap();
+        signed["payload"]["msg"] = "I owe mallory money".into();
+        let tampered = serde_json::to_vec(&signed).unwrap();
+
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .returning(move || Ok(delivered(tampered.clone())));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        run_for_a_moment(&mut sut).await;
+
+        assert!(sut.get_messages().is_empty());
+        assert_eq!(sut.bad_messages(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_drop_replayed_message_quietly() {
+        let replayed = encode(Payload::Typing {
+            user: "peer".into(),
+        });
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        queue_mock
+            .expect_receive()
+            .times(2)
+            .returning(move || Ok(delivered(replayed.clone())));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_unsigned_peers()
+            .with_bad_message_notices();
+
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.bad_messages(), 0);
+        assert!(sut.get_messages().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_flag_message_sent_long_ago() {
+        let stale = Envelope {
+            sent: Some(chrono::Local::now() - chrono::Duration::hours(1)),
+            ..Envelope::new(Payload::Text(message("peer", "recorded")))
+        }
+        .encode()
+        .unwrap();
+        let queue_mock = delivering(vec![stale]);
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+        run_for_a_moment(&mut sut).await;
+
+        let messages = sut.get_messages();
+        assert_eq!(messages[0].msg, "recorded");
+        assert!(messages[0].skewed);
+        assert_eq!(sut.bad_messages(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_drop_presence_sent_long_ago() {
+        let sent = chrono::Local::now() - chrono::Duration::hours(1);
+        let stale = Envelope {
+            sent: Some(sent),
+            ..Envelope::new(alive("peer"))
+        }
+        .encode()
+        .unwrap();
+        let queue_mock = delivering(vec![stale]);
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_unsigned_peers()
+            .with_bad_message_notices();
+        run_for_a_moment(&mut sut).await;
+
+        let messages = sut.get_messages();
+        assert_eq!(sut.bad_messages(), 1);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].msg.starts_with(&format!(
+            "Dropped a message sent at {}",
+            sent.format("%Y-%m-%d %H:%M:%S")
+        )));
+    }
+
+    #[test_case(serde_json::to_vec(&message("peer", "legacy")).unwrap() ; "bare message")]
+    #[test_case(
+        Envelope {
+            id: None,
+            sent: None,
+            ..Envelope::new(Payload::Text(message("peer", "stripped")))
+        }
+        .encode()
+        .unwrap()
+        ; "stripped stamp"
+    )]
+    #[tokio::test(start_paused = true)]
+    async fn should_drop_unstamped_message(received: Vec<u8>) {
+        let queue_mock = delivering(vec![received]);
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_unsigned_peers();
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.bad_messages(), 1);
+        assert!(sut.get_messages().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_flag_message_with_skewed_time() {
+        let mut skewed = message("peer", "from the future");
+        skewed.time = skewed.time + chrono::Duration::minutes(3);
+        let payload = encode(Payload::Text(skewed));
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .returning(move || Ok(delivered(payload.clone())));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        run_for_a_moment(&mut sut).await;
+
+        assert!(sut.get_messages()[0].skewed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_throttle_flood_of_bad_messages() {
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        queue_mock
+            .expect_receive()
+            .returning(|| Ok(delivered(b"garbage".to_vec())));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_bad_message_notices();
+
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.bad_messages(), BAD_MESSAGE_BURST as u64 + 1);
+        assert_eq!(sut.get_messages().len(), BAD_MESSAGE_BURST as usize);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_not_sync_system_notices() {
+        let broker = InMemoryBroker::new();
+        let mut sut = QueueChatRoom::new(broker.connect(), "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_bad_message_notices();
+        sut.notice("notice".into());
+
+        let published = answer_sync_request(&mut sut, &broker, vec![]).await;
+
+        assert_eq!(sync_responses(&published), 0);
+    }
+
+    fn status_of<Q: Queue + Send + Sync>(
+        room: &QueueChatRoom<Q>,
+        user: &str,
+    ) -> Option<MemberStatus> {
+        room.get_members()
+            .into_iter()
+            .find(|member| member.name == user)
+            .map(|member| member.status)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_take_published_will_for_leaving() {
+        let broker = InMemoryBroker::new();
+        let will = Will::generate();
+        let connection = broker.connect_with_will(will.last_will());
+        let mut alice = QueueChatRoom::new(connection.clone(), "alice".into(), "room".into())
+            .await
+            .unwrap()
+            .with_will(will);
+        let mut bob = QueueChatRoom::new(broker.connect(), "bob".into(), "room".into())
+            .await
+            .unwrap();
+        let presence = alice.clone();
+        let _ = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            futures::future::join3(alice.run(), bob.run(), presence.heartbeat()),
+        )
+        .await;
+        assert_eq!(status_of(&bob, "alice"), Some(MemberStatus::Online));
+
+        connection.lose_connection();
+        run_for_a_moment(&mut bob).await;
+
+        assert_eq!(status_of(&bob, "alice"), Some(MemberStatus::Offline));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_ignore_will_not_announced_by_member() {
+        let broker = InMemoryBroker::new();
+        let mut bob = QueueChatRoom::new(broker.connect(), "bob".into(), "room".into())
+            .await
+            .unwrap();
+        let stranger = broker.connect_with_will(Will::generate().last_will());
+
+        stranger.lose_connection();
+        run_for_a_moment(&mut bob).await;
+
+        assert_eq!(bob.bad_messages(), 0);
+        assert!(bob.get_members().iter().all(|member| member.name == "bob"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_announce_joining() {
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock
+            .expect_publish()
+            .withf(|_, msg| {
+                matches!(
+                    decode(msg),
+                    Payload::Presence {
+                        user,
+                        status: PresenceStatus::Join,
+                        dm_key: Some(_),
+                        ..
+                    } if user == "user"
+                )
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(10), sut.heartbeat()).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_track_members_presence() {
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock
+            .expect_publish()
+            .withf(|_, msg| matches!(decode(msg), Payload::SyncRequest { .. }))
+            .returning(|_, _| Ok(()));
+        // Every newcomer is greeted, so it learns who is here
+        queue_mock
+            .expect_publish()
+            .withf(|_, msg| {
+                matches!(
+                    decode(msg),
+                    Payload::Presence {
+                        user,
+                        status: PresenceStatus::Alive,
+                        ..
+                    } if user == "user"
+                )
+            })
+            .times(2)
+            .returning(|_, _| Ok(()));
+        let mut seq = mockall::Sequence::new();
+        for (user, status) in [
+            ("alice", PresenceStatus::Join),
+            ("bob", PresenceStatus::Join),
+            ("bob", PresenceStatus::Leave),
+        ] {
+            let payload = encode(Payload::Presence {
+                user: user.into(),
+                status,
+                dm_key: None,
+                will: None,
+            });
+            queue_mock
+                .expect_receive()
+                .times(1)
+                .in_sequence(&mut seq)
+                .returning(move || Ok(delivered(payload.clone())));
+        }
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_unsigned_peers();
+
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(
+            sut.get_members(),
+            vec![
+                Member {
+                    name: "alice".into(),
+                    status: MemberStatus::Online,
+                    alias_of: None,
+                },
+                Member {
+                    name: "bob".into(),
+                    status: MemberStatus::Offline,
+                    alias_of: None,
+                },
+                Member {
+                    name: "user".into(),
+                    status: MemberStatus::Online,
+                    alias_of: None,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_publish_typing_signal() {
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock
+            .expect_publish()
+            .withf(|topic, msg| {
+                topic.ends_with("room/user")
+                    && decode(msg)
+                        == Payload::Typing {
+                            user: "user".into(),
+                        }
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        assert!(sut.send_typing().await.is_ok());
+    }
 
-impl<Q> QueueChatRoom<Q>
-where
-    Q: Queue,
-{
-    pub async fn new(mut queue: Q, user_name: String, room_name: String) -> Result<Self, Error> {
-        let topic = format!("{}/{}", TOPIC_PREFIX, room_name); // TODO: Remove tight coupling with mqtt topic format
-        queue.subscribe(format!("{}/#", topic)).await?;
+    #[tokio::test(start_paused = true)]
+    async fn should_show_others_typing_until_they_send() {
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        let mut seq = mockall::Sequence::new();
+        for payload in [
+            Payload::Typing {
+                user: "alice".into(),
+            },
+            Payload::Typing { user: "bob".into() },
+            Payload::Typing {
+                user: "user".into(),
+            },
+            Payload::Text(message("bob", "text")),
+        ] {
+            let payload = encode(payload);
+            queue_mock
+                .expect_receive()
+                .times(1)
+                .in_sequence(&mut seq)
+                .returning(move || Ok(delivered(payload.clone())));
+        }
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
 
-        let topic = format!("{}/{}", topic, user_name);
-        Ok(Self {
-            queue,
-            topic,
-            user_name,
-            messages: Arc::default(),
+        // Only the message is stored, typing signals are not
+        let mut store_mock = MockMessageStore::new();
+        store_mock.expect_load_renames().returning(|| Ok(vec![]));
+        store_mock.expect_load_last().returning(|_| Ok(vec![]));
+        store_mock.expect_append().times(1).returning(|_| Ok(()));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_unsigned_peers()
+            .with_store(store_mock, 10);
+
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.get_typing(), vec!["alice"]);
+        assert_eq!(sut.get_messages().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_deliver_direct_message_only_to_recipient() {
+        let broker = InMemoryBroker::new();
+        let mut rooms = Vec::new();
+        for user in ["alice", "bob", "eve"] {
+            let room = QueueChatRoom::new(broker.connect(), user.into(), "room".into())
+                .await
+                .unwrap();
+            rooms.push(room);
+        }
+        let presence = rooms.clone();
+        let (alice, bob, eve) = match &mut rooms[..] {
+            [alice, bob, eve] => (alice, bob, eve),
+            _ => unreachable!(),
+        };
+
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(10), async {
+            tokio::join!(
+                alice.run(),
+                bob.run(),
+                eve.run(),
+                futures::future::join_all(presence.iter().map(|room| room.heartbeat())),
+            )
+        })
+        .await;
+        alice
+            .send_direct("bob".into(), "secret".into())
+            .await
+            .unwrap();
+        let _ = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            futures::future::join3(alice.run(), bob.run(), eve.run()),
+        )
+        .await;
+
+        let direct = MessageKind::Direct { to: "bob".into() };
+        assert_eq!(alice.get_messages()[0].kind, direct);
+        assert_eq!(bob.get_messages()[0].kind, direct);
+        assert_eq!(bob.get_messages()[0].msg, "secret");
+        assert!(eve.get_messages().is_empty());
+        assert_eq!(eve.bad_messages(), 0);
+    }
+
+    type EncryptedRoom = QueueChatRoom<
+        crate::queue::encrypted_queue::EncryptedQueue<
+            crate::queue::in_memory::InMemoryQueue,
+            crate::crypto::chacha::ChaChaCrypt,
+        >,
+    >;
+
+    async fn encrypted_room(broker: &InMemoryBroker, user: &str) -> EncryptedRoom {
+        encrypted_room_on(broker.connect(), user).await
+    }
+
+    async fn encrypted_room_on(
+        connection: crate::queue::in_memory::InMemoryQueue,
+        user: &str,
+    ) -> EncryptedRoom {
+        let keyring = Keyring::new().with_grace(Duration::ZERO);
+        let password_key = crate::crypto::chacha::ChaChaCrypt::new(&RoomKey::from_bytes([7; 32]));
+        let queue = crate::queue::encrypted_queue::EncryptedQueue::new(connection, password_key)
+            .with_keyring(keyring.clone())
+            .with_plain_topic(WILL_TOPIC_FILTER.into());
+
+        QueueChatRoom::new(queue, user.into(), "room".into())
+            .await
+            .unwrap()
+            .with_keyring(keyring, vec!["alice".into()])
+    }
+
+    async fn run_rooms(rooms: &mut [EncryptedRoom]) {
+        let presence = rooms.to_vec();
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(20), async {
+            futures::future::join(
+                futures::future::join_all(rooms.iter_mut().map(|room| room.run())),
+                futures::future::join_all(presence.iter().map(|room| room.heartbeat())),
+            )
+            .await
         })
+        .await;
     }
 
-    pub async fn run(&mut self) -> Result<(), Error> {
-        while let Ok(msg) = self.queue.receive().await {
-            let msg = serde_json::from_slice(&msg)?;
-            self.messages.write().expect("Poisoned mutex").push(msg);
+    #[tokio::test(start_paused = true)]
+    async fn should_stop_revoked_member_from_reading() {
+        let broker = InMemoryBroker::new();
+        let mut rooms = Vec::new();
+        for user in ["alice", "bob", "eve"] {
+            rooms.push(encrypted_room(&broker, user).await);
         }
+        run_rooms(&mut rooms).await;
 
-        Ok(())
+        rooms[0].rotate_key(Some("eve".into())).await.unwrap();
+        run_rooms(&mut rooms).await;
+        rooms[0].send("eve is out".into()).await.unwrap();
+        run_rooms(&mut rooms).await;
+
+        let read = |room: &EncryptedRoom| {
+            room.get_messages()
+                .iter()
+                .any(|msg| msg.msg == "eve is out")
+        };
+        assert!(read(&rooms[1]));
+        assert!(!read(&rooms[2]));
+        assert!(rooms[2].bad_messages() > 0);
     }
-}
 
-#[async_trait::async_trait]
-impl<Q> ChatRoom for QueueChatRoom<Q>
-where
-    Q: Queue + Sync + Send,
-{
-    async fn send(&self, msg: String) -> Result<(), Error> {
-        let msg = ChatMessage {
-            user: self.user_name.clone(),
-            msg,
-            time: chrono::Local::now(),
+    #[tokio::test(start_paused = true)]
+    async fn should_take_will_after_room_key_change() {
+        let broker = InMemoryBroker::new();
+        let will = Will::generate();
+        let connection = broker.connect_with_will(will.last_will());
+        let mut rooms = vec![
+            encrypted_room_on(connection.clone(), "alice")
+                .await
+                .with_will(will),
+            encrypted_room(&broker, "bob").await,
+        ];
+        run_rooms(&mut rooms).await;
+        rooms[0].rotate_key(None).await.unwrap();
+        run_rooms(&mut rooms).await;
+        assert!(rooms[1]
+            .get_messages()
+            .iter()
+            .any(|msg| msg.msg == "alice changed the room key"));
+
+        connection.lose_connection();
+        run_rooms(&mut rooms[1..]).await;
+
+        assert_eq!(status_of(&rooms[1], "alice"), Some(MemberStatus::Offline));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_ignore_unverified_key_request() {
+        let alice = IdentityKey::generate();
+        let first = DmKeyPair::generate();
+        let key_request = |dm_key: &DmKeyPair| Payload::KeyRequest {
+            user: "alice".into(),
+            dm_key: direct::encode_key(dm_key.public_key()),
         };
-        let msg = serde_json::to_vec(&msg)?;
+        let queue_mock = delivering(vec![
+            signed(key_request(&first), &alice),
+            encode(key_request(&DmKeyPair::generate())),
+            signed(
+                key_request(&DmKeyPair::generate()),
+                &IdentityKey::generate(),
+            ),
+        ]);
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_keyring(Keyring::new(), vec!["user".into()]);
+        run_for_a_moment(&mut sut).await;
 
-        self.queue.publish(self.topic.clone(), msg).await
+        assert_eq!(sut.peer_key("alice"), Some(first.public_key()));
+        let notices = sut.get_messages();
+        assert_eq!(notices.len(), 2);
+        assert!(notices.iter().all(|notice| notice.msg
+            == "Ignored keys of alice, they are not signed with their known identity"));
     }
 
-    fn get_messages(&self) -> Vec<ChatMessage> {
-        let messages = self.messages.read().expect("Poisoned mutex");
+    #[tokio::test(start_paused = true)]
+    async fn should_ignore_room_key_from_non_admin() {
+        let broker = InMemoryBroker::new();
+        let mut rooms = Vec::new();
+        for user in ["alice", "bob"] {
+            rooms.push(encrypted_room(&broker, user).await);
+        }
+        run_rooms(&mut rooms).await;
 
-        messages.to_owned()
+        let result = rooms[1].rotate_key(None).await;
+
+        assert_eq!(
+            format!("{:#}", result.unwrap_err()),
+            "Only admins can change the room key"
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test(start_paused = true)]
+    async fn should_let_newcomer_in_after_invite() {
+        let broker = InMemoryBroker::new();
+        let mut rooms = vec![encrypted_room(&broker, "alice").await];
+        run_rooms(&mut rooms).await;
+        rooms[0].rotate_key(None).await.unwrap();
 
-    use crate::queue::MockQueue;
+        rooms.push(encrypted_room(&broker, "bob").await);
+        run_rooms(&mut rooms).await;
+        assert!(rooms[0]
+            .get_messages()
+            .iter()
+            .any(|msg| msg.msg == "bob asks for the room key, /invite bob to let them in"));
 
-    #[tokio::test]
-    async fn should_subscribe_to_queue() {
+        rooms[0].invite("bob".into()).await.unwrap();
+        run_rooms(&mut rooms).await;
+        rooms[0].send("welcome".into()).await.unwrap();
+        run_rooms(&mut rooms).await;
+
+        assert!(rooms[1]
+            .get_messages()
+            .iter()
+            .any(|msg| msg.msg == "welcome"));
+    }
+
+    fn password_key() -> crate::crypto::chacha::ChaChaCrypt {
+        crate::crypto::chacha::ChaChaCrypt::new(&RoomKey::from_bytes([7; 32]))
+    }
+
+    async fn end_to_end_room(
+        broker: &InMemoryBroker,
+        user: &str,
+        sender_keys: SenderKeys,
+    ) -> EncryptedRoom {
+        let queue =
+            crate::queue::encrypted_queue::EncryptedQueue::new(broker.connect(), password_key())
+                .with_sender_keys(sender_keys.clone());
+
+        QueueChatRoom::new(queue, user.into(), "room".into())
+            .await
+            .unwrap()
+            .with_sender_keys(sender_keys)
+    }
+
+    fn has_read(room: &EncryptedRoom, text: &str) -> bool {
+        room.get_messages().iter().any(|msg| msg.msg == text)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_exchange_sender_keys_in_end_to_end_room() {
+        let broker = InMemoryBroker::new();
+        let mut rooms = Vec::new();
+        for user in ["alice", "bob"] {
+            rooms.push(end_to_end_room(&broker, user, SenderKeys::new()).await);
+        }
+        run_rooms(&mut rooms).await;
+
+        rooms[0].send("hi bob".into()).await.unwrap();
+        rooms[1].send("hi alice".into()).await.unwrap();
+        run_rooms(&mut rooms).await;
+
+        assert!(has_read(&rooms[1], "hi bob"));
+        assert!(has_read(&rooms[0], "hi alice"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_change_sender_keys_when_member_leaves() {
+        let broker = InMemoryBroker::new();
+        let eve_keys = SenderKeys::new();
+        let mut rooms = vec![
+            end_to_end_room(&broker, "alice", SenderKeys::new()).await,
+            end_to_end_room(&broker, "bob", SenderKeys::new()).await,
+            end_to_end_room(&broker, "eve", eve_keys.clone()).await,
+        ];
+        run_rooms(&mut rooms).await;
+
+        rooms[2].leave().await.unwrap();
+        run_rooms(&mut rooms).await;
+        // Eve kept what her client knew and listens in
+        let mut eavesdropper =
+            crate::queue::encrypted_queue::EncryptedQueue::new(broker.connect(), password_key())
+                .with_sender_keys(eve_keys);
+        eavesdropper
+            .subscribe(format!("{}/room/alice", TOPIC_PREFIX))
+            .await
+            .unwrap();
+        rooms[0].send("eve is gone".into()).await.unwrap();
+        run_rooms(&mut rooms[..2]).await;
+
+        assert!(has_read(&rooms[1], "eve is gone"));
+        let overheard =
+            tokio::time::timeout(std::time::Duration::from_millis(10), eavesdropper.receive())
+                .await
+                .expect("Message reaches the broker");
+        assert!(overheard.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_leave_out_revoked_member_of_end_to_end_room() {
+        let broker = InMemoryBroker::new();
+        let mut rooms = Vec::new();
+        for user in ["alice", "bob", "eve"] {
+            rooms.push(end_to_end_room(&broker, user, SenderKeys::new()).await);
+        }
+        run_rooms(&mut rooms).await;
+
+        rooms[0].rotate_key(Some("eve".into())).await.unwrap();
+        run_rooms(&mut rooms).await;
+        rooms[0].send("eve is out".into()).await.unwrap();
+        run_rooms(&mut rooms).await;
+
+        assert!(has_read(&rooms[1], "eve is out"));
+        assert!(!has_read(&rooms[2], "eve is out"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_refuse_sender_key_sealed_with_changed_dm_key() {
+        let alice = IdentityKey::generate();
+        let (first, second) = (DmKeyPair::generate(), DmKeyPair::generate());
+        let own_dm_keys = DmKeyPair::generate();
+        let sealed = direct::seal(
+            &second,
+            own_dm_keys.public_key(),
+            &SenderKeys::new().own_chain(),
+        )
+        .unwrap();
+        let queue_mock = delivering(vec![
+            signed(presence_with_dm_key(&first), &alice),
+            signed(
+                Payload::SenderKey {
+                    from: "alice".into(),
+                    to: "user".into(),
+                    dm_key: direct::encode_key(second.public_key()),
+                    sealed,
+                },
+                &alice,
+            ),
+        ]);
+        let sender_keys = SenderKeys::new();
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_dm_keys(own_dm_keys)
+            .with_sender_keys(sender_keys.clone());
+        run_for_a_moment(&mut sut).await;
+
+        assert!(!sender_keys.knows("alice"));
+        assert_eq!(sut.peer_key("alice"), Some(first.public_key()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_not_change_sender_key_on_unsigned_leave() {
+        let bob = IdentityKey::generate();
+        let queue_mock = delivering(vec![
+            signed(alive("bob"), &bob),
+            encode(Payload::Presence {
+                user: "bob".into(),
+                status: PresenceStatus::Leave,
+                dm_key: None,
+                will: Some("token".into()),
+            }),
+        ]);
+        let sender_keys = SenderKeys::new();
+        let own_chain = sender_keys.own_chain();
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_sender_keys(sender_keys.clone())
+            .with_unsigned_peers();
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sender_keys.own_chain(), own_chain);
+        assert!(sut.wills.read().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_tell_subject_to_newcomers() {
+        let broker = InMemoryBroker::new();
+        let mut alice = QueueChatRoom::new(broker.connect(), "alice".into(), "room".into())
+            .await
+            .unwrap();
+        alice.set_subject("pizza".into()).await.unwrap();
+        let mut bob = QueueChatRoom::new(broker.connect(), "bob".into(), "room".into())
+            .await
+            .unwrap();
+        let presence = bob.clone();
+
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(10), async {
+            tokio::join!(alice.run(), bob.run(), presence.heartbeat())
+        })
+        .await;
+
+        assert_eq!(alice.get_subject().as_deref(), Some("pizza"));
+        assert_eq!(bob.get_subject().as_deref(), Some("pizza"));
+        assert_eq!(bob.get_messages()[0].msg, "alice set the topic: pizza");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_follow_renamed_user() {
+        let broker = InMemoryBroker::new();
+        let mut store_mock = MockMessageStore::new();
+        store_mock.expect_load_renames().returning(|| Ok(vec![]));
+        store_mock.expect_load_last().returning(|_| Ok(vec![]));
+        store_mock.expect_append().returning(|_| Ok(()));
+        store_mock
+            .expect_append_rename()
+            .with(
+                mockall::predicate::eq("bob"),
+                mockall::predicate::eq("robert"),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut rooms = vec![
+            QueueChatRoom::new(broker.connect(), "alice".into(), "room".into())
+                .await
+                .unwrap(),
+            QueueChatRoom::new(broker.connect(), "bob".into(), "room".into())
+                .await
+                .unwrap()
+                .with_store(store_mock, 100),
+        ];
+        let presence = rooms.clone();
+        let (alice, bob) = match &mut rooms[..] {
+            [alice, bob] => (alice, bob),
+            _ => unreachable!(),
+        };
+
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(10), async {
+            tokio::join!(
+                alice.run(),
+                bob.run(),
+                futures::future::join_all(presence.iter().map(|room| room.heartbeat())),
+            )
+        })
+        .await;
+        bob.set_nick("robert".into()).await.unwrap();
+        bob.send("hi".into()).await.unwrap();
+        let _ = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            futures::future::join(alice.run(), bob.run()),
+        )
+        .await;
+
+        let messages = alice.get_messages();
+        assert_eq!(messages[0].msg, "bob is now known as robert");
+        assert_eq!(messages[1].user, "robert");
+        assert_eq!(messages[1].alias_of.as_deref(), Some("bob"));
+        let members = alice.get_members();
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[1].name, "robert");
+        assert_eq!(members[1].alias_of.as_deref(), Some("bob"));
+        assert_eq!(alice.bad_messages(), 0);
+
+        let notices = bob
+            .get_messages()
+            .into_iter()
+            .filter(|msg| msg.kind == MessageKind::System)
+            .map(|msg| msg.msg)
+            .collect::<Vec<_>>();
+        assert_eq!(notices, ["bob is now known as robert"]);
+        let members = bob.get_members();
+        let names = members.iter().map(|member| member.name.as_str());
+        assert_eq!(names.collect::<Vec<_>>(), ["alice", "robert"]);
+        assert_eq!(bob.bad_messages(), 0);
+    }
+
+    /// Queue delivering `received` messages one by one, then failing
+    fn delivering(received: Vec<Vec<u8>>) -> MockQueue {
+        let mut seq = mockall::Sequence::new();
         let mut queue_mock = MockQueue::new();
-        queue_mock.expect_subscribe().times(1).returning(|_| Ok(()));
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        for msg in received {
+            queue_mock
+                .expect_receive()
+                .times(1)
+                .in_sequence(&mut seq)
+                .returning(move || Ok(delivered(msg.clone())));
+        }
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
 
-        let sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string()).await;
+        queue_mock
+    }
 
-        assert!(sut.is_ok());
+    fn signed(payload: Payload, identity: &IdentityKey) -> Vec<u8> {
+        Envelope::new(payload).encode_signed(identity).unwrap()
     }
 
-    #[tokio::test]
-    async fn should_publish_message_to_queue() {
+    fn alive(user: &str) -> Payload {
+        Payload::Presence {
+            user: user.into(),
+            status: PresenceStatus::Alive,
+            dm_key: None,
+            will: None,
+        }
+    }
+
+    fn nick(user: &str, nick: &str) -> Payload {
+        Payload::Nick {
+            user: user.into(),
+            nick: nick.into(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_refuse_rename_onto_name_in_use() {
+        let (alice, bob) = (IdentityKey::generate(), IdentityKey::generate());
+        let queue_mock = delivering(vec![
+            signed(alive("alice"), &alice),
+            signed(alive("bob"), &bob),
+            signed(nick("bob", "alice"), &bob),
+        ]);
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.bad_messages(), 1);
+        let members = sut.get_members();
+        let names = members.iter().map(|member| member.name.as_str());
+        assert_eq!(names.collect::<Vec<_>>(), ["alice", "bob", "user"]);
+        assert!(members.iter().all(|member| member.alias_of.is_none()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_refuse_unsigned_rename() {
+        let queue_mock = delivering(vec![encode(alive("bob")), encode(nick("bob", "robert"))]);
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_unsigned_peers();
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.bad_messages(), 1);
+        assert_eq!(sut.get_members()[0].name, "bob");
+        assert!(sut.get_messages().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_ignore_unsigned_presence_subject_and_typing() {
+        let queue_mock = delivering(vec![
+            encode(alive("bob")),
+            encode(Payload::Subject {
+                user: "bob".into(),
+                subject: "pizza".into(),
+            }),
+            encode(Payload::Typing { user: "bob".into() }),
+        ]);
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.bad_messages(), 3);
+        assert!(sut.get_members().iter().all(|member| member.name != "bob"));
+        assert_eq!(sut.get_subject(), None);
+        assert!(sut.get_typing().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_ignore_presence_signed_with_changed_key() {
+        let keys = Arc::new(KeyDirectory::new());
+        keys.check("bob", IdentityKey::generate().public_key())
+            .unwrap();
+        let queue_mock = delivering(vec![signed(alive("bob"), &IdentityKey::generate())]);
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_identity(IdentityKey::generate(), keys)
+            .with_unsigned_peers();
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.bad_messages(), 1);
+        assert!(sut.get_members().iter().all(|member| member.name != "bob"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_store_renames() {
+        let bob = IdentityKey::generate();
+        let queue_mock = delivering(vec![signed(nick("bob", "robert"), &bob)]);
+        let mut store_mock = MockMessageStore::new();
+        store_mock.expect_load_renames().returning(|| Ok(vec![]));
+        store_mock.expect_load_last().returning(|_| Ok(vec![]));
+        store_mock
+            .expect_append_rename()
+            .with(
+                mockall::predicate::eq("bob"),
+                mockall::predicate::eq("robert"),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_store(store_mock, 10);
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.get_messages()[0].msg, "bob is now known as robert");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_follow_stored_renames() {
+        let queue_mock = delivering(vec![encode(Payload::Text(message("robert", "hi")))]);
+        let mut store_mock = MockMessageStore::new();
+        store_mock
+            .expect_load_renames()
+            .returning(|| Ok(vec![("bob".to_string(), "robert".to_string())]));
+        store_mock.expect_load_last().returning(|_| Ok(vec![]));
+        store_mock.expect_append().returning(|_| Ok(()));
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_store(store_mock, 10);
+        run_for_a_moment(&mut sut).await;
+
+        assert_eq!(sut.get_messages()[0].alias_of.as_deref(), Some("bob"));
+    }
+
+    #[test_case("" ; "empty")]
+    #[test_case("two words" ; "space")]
+    #[test_case("a/b" ; "topic separator")]
+    #[test_case("peer" ; "taken")]
+    #[tokio::test(start_paused = true)]
+    async fn should_refuse_invalid_nick(nick: &str) {
         let mut queue_mock = MockQueue::new();
-        queue_mock.expect_subscribe().times(1).returning(|_| Ok(()));
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
         queue_mock
             .expect_publish()
-            .withf(|_, msg| {
-                let msg = serde_json::from_slice::<ChatMessage>(msg).unwrap();
-                msg.user == "user" && msg.msg == "text message"
-            })
-            .times(1..)
+            .withf(|_, msg| matches!(decode(msg), Payload::SyncRequest { .. }))
             .returning(|_, _| Ok(()));
+        let join = encode(Payload::Presence {
+            user: "peer".into(),
+            status: PresenceStatus::Alive,
+            dm_key: None,
+            will: None,
+        });
+        queue_mock
+            .expect_receive()
+            .times(1)
+            .returning(move || Ok(delivered(join.clone())));
+        queue_mock
+            .expect_receive()
+            .returning(|| Err(anyhow::anyhow!("finished")));
 
-        let sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap()
+            .with_unsigned_peers();
+        run_for_a_moment(&mut sut).await;
+
+        assert!(sut.set_nick(nick.into()).await.is_err());
+        assert_eq!(sut.user_name(), "user");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_refuse_nick_of_member_gone_offline() {
+        let bob = IdentityKey::generate();
+        let leave = Payload::Presence {
+            user: "bob".into(),
+            status: PresenceStatus::Leave,
+            dm_key: None,
+            will: None,
+        };
+        let queue_mock = delivering(vec![signed(alive("bob"), &bob), signed(leave, &bob)]);
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
             .await
             .unwrap();
-        let result = sut.send("text message".to_string()).await;
+        run_for_a_moment(&mut sut).await;
 
-        assert!(result.is_ok());
+        assert!(sut.set_nick("bob".into()).await.is_err());
+        assert_eq!(sut.user_name(), "user");
     }
 
     #[tokio::test]
-    async fn should_publish_message_to_correct_topic() {
+    async fn should_stop_receiving_after_leaving() {
         let mut queue_mock = MockQueue::new();
-        queue_mock.expect_subscribe().times(1).returning(|_| Ok(()));
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
         queue_mock
-            .expect_publish()
-            .withf(|topic, _| topic.contains("room/user"))
-            .times(1..)
-            .returning(|_, _| Ok(()));
+            .expect_unsubscribe()
+            .times(4)
+            .returning(|_| Ok(()));
+        queue_mock.expect_receive().never();
+
+        let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+        sut.leave().await.unwrap();
+
+        assert!(sut.run().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn should_unsubscribe_topics_when_leaving() {
+        let mut queue_mock = MockQueue::new();
+        let subscribed = Arc::new(RwLock::new(Vec::new()));
+        let subscribing = subscribed.clone();
+        queue_mock
+            .expect_subscribe()
+            .times(4)
+            .returning(move |topic| {
+                subscribing.write().unwrap().push(topic);
+                Ok(())
+            });
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        let unsubscribed = Arc::new(RwLock::new(Vec::new()));
+        let unsubscribing = unsubscribed.clone();
+        queue_mock
+            .expect_unsubscribe()
+            .times(4)
+            .returning(move |topic| {
+                unsubscribing.write().unwrap().push(topic);
+                Ok(())
+            });
 
         let sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
             .await
             .unwrap();
-        let result = sut.send("text message".to_string()).await;
+        sut.leave().await.unwrap();
 
-        assert!(result.is_ok());
+        assert_eq!(*unsubscribed.read().unwrap(), *subscribed.read().unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_stop_running_room_when_leaving() {
+        let broker = InMemoryBroker::new();
+        let sut = QueueChatRoom::new(broker.connect(), "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+        let mut running = sut.clone();
+        let run = tokio::spawn(async move { running.run().await });
+
+        sut.leave().await.unwrap();
+
+        let stopped = tokio::time::timeout(std::time::Duration::from_secs(1), run)
+            .await
+            .expect("Room stops receiving");
+        assert!(stopped.unwrap().is_ok());
     }
 
     #[tokio::test]
-    async fn should_return_received_messages() {
-        let time = chrono::Local::now();
+    async fn should_not_send_direct_message_without_key() {
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().never();
+
+        let sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        let result = sut.send_direct("stranger".into(), "hi".into()).await;
+
+        assert!(result.is_ok());
+        assert_eq!(sut.get_messages()[0].kind, MessageKind::System);
+    }
 
+    #[tokio::test(start_paused = true)]
+    async fn should_reject_direct_message_from_unknown_sender() {
         let mut queue_mock = MockQueue::new();
-        queue_mock.expect_subscribe().times(1).returning(|_| Ok(()));
-        queue_mock.expect_receive().times(1).returning(move || {
-            Ok(serde_json::to_vec(&ChatMessage {
-                user: "user".into(),
-                msg: "text".into(),
-                time,
-            })
-            .unwrap())
+        queue_mock.expect_subscribe().times(4).returning(|_| Ok(()));
+        queue_mock.expect_publish().returning(|_, _| Ok(()));
+        let direct = encode(Payload::Direct {
+            from: "stranger".into(),
+            to: "user".into(),
+            sealed: "c2VhbGVk".into(),
         });
         queue_mock
             .expect_receive()
             .times(1)
+            .returning(move || Ok(delivered(direct.clone())));
+        queue_mock
+            .expect_receive()
             .returning(|| Err(anyhow::anyhow!("finished")));
 
         let mut sut = QueueChatRoom::new(queue_mock, "user".to_string(), "room".to_string())
             .await
             .unwrap();
 
-        let _ = tokio::time::timeout(std::time::Duration::from_millis(10), sut.run()).await;
+        run_for_a_moment(&mut sut).await;
 
-        let messages = sut.get_messages();
-        assert_eq!(
-            messages,
-            vec![ChatMessage {
-                user: "user".into(),
-                time,
-                msg: "text".into(),
-            }]
-        );
+        assert_eq!(sut.bad_messages(), 1);
+        assert!(sut.get_messages().is_empty());
     }
 }
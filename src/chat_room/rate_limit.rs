@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant};
+
+/// Allows at most `burst` events within every `period`
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    burst: u32,
+    period: Duration,
+    /// Start of the current period, begins with the first event
+    period_start: Option<Instant>,
+    count: u32,
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, period: Duration) -> Self {
+        Self {
+            burst,
+            period,
+            period_start: None,
+            count: 0,
+        }
+    }
+
+    /// Records event happening at `now`. Returns `false` when it exceeds the limit.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.period_start {
+            Some(start) if now.duration_since(start) < self.period => (),
+            _ => {
+                self.period_start = Some(now);
+                self.count = 0;
+            }
+        }
+
+        self.count = self.count.saturating_add(1);
+        self.count <= self.burst
+    }
+
+    /// Time left until the limit is lifted
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.period_start.map_or(Duration::ZERO, |start| {
+            self.period.saturating_sub(now.duration_since(start))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_allow_burst_within_period() {
+        let start = Instant::now();
+        let mut sut = RateLimit::new(2, Duration::from_secs(1));
+
+        assert!(sut.allow(start));
+        assert!(sut.allow(start));
+        assert!(!sut.allow(start + Duration::from_millis(500)));
+        assert_eq!(
+            sut.remaining(start + Duration::from_millis(500)),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn should_allow_again_in_next_period() {
+        let start = Instant::now();
+        let mut sut = RateLimit::new(1, Duration::from_secs(1));
+
+        assert!(sut.allow(start));
+        assert!(!sut.allow(start));
+        assert!(sut.allow(start + Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,193 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, Local};
+use uuid::Uuid;
+
+use super::Error;
+
+/// How far from the local clock envelopes are taken as fresh. Ids are
+/// remembered for as long, so anything older can't be told apart from a replay.
+pub const REPLAY_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// Distance from the local clock past which message time is flagged
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Error of an envelope received before, e.g. recorded off the broker and
+/// published again, or redelivered by the broker itself.
+#[derive(Debug)]
+pub struct ReplayedMessage;
+
+impl std::fmt::Display for ReplayedMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Message was already received")
+    }
+}
+
+impl std::error::Error for ReplayedMessage {}
+
+/// Error of an envelope without id or sent time, e.g. from clients predating
+/// them. Nothing tells it apart from a replay.
+#[derive(Debug)]
+pub struct UnstampedMessage;
+
+impl std::fmt::Display for UnstampedMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Message has no id nor sent time")
+    }
+}
+
+impl std::error::Error for UnstampedMessage {}
+
+/// Error of an envelope sent outside of [`REPLAY_WINDOW`], either replayed
+/// or sent by a client with a clock far off.
+#[derive(Debug)]
+pub struct StaleMessage {
+    pub sent: DateTime<Local>,
+}
+
+impl std::fmt::Display for StaleMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Message sent at {} is too far from the local clock",
+            self.sent
+        )
+    }
+}
+
+impl std::error::Error for StaleMessage {}
+
+/// Ids of envelopes received within the sliding [`REPLAY_WINDOW`]
+#[derive(Clone, Debug, Default)]
+pub struct ReplayGuard {
+    /// Time after which the id can be forgotten, by id
+    seen: HashMap<Uuid, DateTime<Local>>,
+}
+
+impl ReplayGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accepts envelope `id` sent at `sent` unless it was seen before. Whether
+    /// it is fresh is told apart by [`is_stale`].
+    pub fn check(
+        &mut self,
+        id: Uuid,
+        sent: DateTime<Local>,
+        now: DateTime<Local>,
+    ) -> Result<(), Error> {
+        self.seen.retain(|_, forget_at| *forget_at > now);
+
+        if self.seen.contains_key(&id) {
+            return Err(ReplayedMessage.into());
+        }
+        // Kept until the envelope falls out of the window, even one that
+        // claims to be sent long ago. Time far ahead is cut to the window,
+        // it would overflow and is flagged or dropped anyway.
+        let sent = sent.max(now).min(now + window());
+        self.seen.insert(id, sent + window());
+
+        Ok(())
+    }
+}
+
+/// Whether envelope sent at `sent` is outside of [`REPLAY_WINDOW`], so it
+/// may be replayed once its id is forgotten
+pub fn is_stale(sent: DateTime<Local>, now: DateTime<Local>) -> bool {
+    !within(sent, now, REPLAY_WINDOW)
+}
+
+/// Whether `time` claimed by the sender is too far from the local clock
+pub fn is_skewed(time: DateTime<Local>, now: DateTime<Local>) -> bool {
+    !within(time, now, MAX_CLOCK_SKEW)
+}
+
+fn within(time: DateTime<Local>, now: DateTime<Local>, limit: Duration) -> bool {
+    let limit = chrono::Duration::from_std(limit).expect("Limit out of range");
+    time >= now - limit && time <= now + limit
+}
+
+fn window() -> chrono::Duration {
+    chrono::Duration::from_std(REPLAY_WINDOW).expect("Window out of range")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(minutes: i64) -> chrono::Duration {
+        chrono::Duration::minutes(minutes)
+    }
+
+    #[test]
+    fn should_reject_envelope_seen_before() {
+        let mut sut = ReplayGuard::new();
+        let now = Local::now();
+        let id = Uuid::new_v4();
+
+        assert!(sut.check(id, now, now).is_ok());
+        assert!(sut.check(Uuid::new_v4(), now, now).is_ok());
+        assert!(sut
+            .check(id, now, now + minutes(1))
+            .unwrap_err()
+            .is::<ReplayedMessage>());
+    }
+
+    #[test]
+    fn should_tell_envelope_outside_of_window() {
+        let now = Local::now();
+
+        assert!(!is_stale(now - minutes(4), now));
+        assert!(is_stale(now - minutes(6), now));
+        assert!(is_stale(now + minutes(6), now));
+    }
+
+    #[test]
+    fn should_reject_replay_of_envelope_sent_long_ago() {
+        let mut sut = ReplayGuard::new();
+        let now = Local::now();
+        let id = Uuid::new_v4();
+        sut.check(id, now - minutes(60), now).unwrap();
+
+        let replayed = sut.check(id, now - minutes(60), now + minutes(4));
+
+        assert!(replayed.unwrap_err().is::<ReplayedMessage>());
+    }
+
+    #[test]
+    fn should_remember_envelope_sent_far_ahead() {
+        let mut sut = ReplayGuard::new();
+        let now = Local::now();
+        let id = Uuid::new_v4();
+        let sent = "+262143-12-31T23:59:59Z".parse().unwrap();
+
+        sut.check(id, sent, now).unwrap();
+
+        assert!(sut
+            .check(id, sent, now + minutes(9))
+            .unwrap_err()
+            .is::<ReplayedMessage>());
+        assert!(sut.check(id, sent, now + minutes(11)).is_ok());
+    }
+
+    #[test]
+    fn should_forget_ids_out_of_window() {
+        let mut sut = ReplayGuard::new();
+        let sent = Local::now();
+        sut.check(Uuid::new_v4(), sent, sent).unwrap();
+
+        sut.check(Uuid::new_v4(), sent, sent + minutes(6)).unwrap();
+
+        assert_eq!(sut.seen.len(), 1);
+    }
+
+    #[test]
+    fn should_flag_time_far_from_local_clock() {
+        let now = Local::now();
+
+        assert!(!is_skewed(now - chrono::Duration::seconds(30), now));
+        assert!(is_skewed(now - minutes(2), now));
+        assert!(is_skewed(now + minutes(2), now));
+    }
+}
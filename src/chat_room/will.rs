@@ -0,0 +1,80 @@
+use rand::Rng;
+
+use crate::queue::LastWill;
+
+/// Root of will topics, apart from room topics so no room name can clash
+const WILL_TOPIC_PREFIX: &str = "df9ff5c8-c030-4e4a-8bae-a415565febd7-will";
+
+/// Filter of topics wills of all users are published on
+pub const WILL_TOPIC_FILTER: &str = "df9ff5c8-c030-4e4a-8bae-a415565febd7-will/+";
+
+/// Last will of a connection, shared by all rooms using it. The broker
+/// publishes it empty on a topic named after a random token, rooms tell
+/// members the token in their presence, so the will needs no room key and
+/// only leaves a user once. Nobody learns the token from the will before it
+/// is published, so it can't be forged by others than room members.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Will {
+    token: String,
+}
+
+impl Will {
+    pub fn generate() -> Self {
+        let token = rand::thread_rng().gen::<[u8; 16]>();
+
+        Self {
+            token: base64::encode_config(token, base64::URL_SAFE_NO_PAD),
+        }
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Will to give the queue when connecting
+    pub fn last_will(&self) -> LastWill {
+        LastWill {
+            topic: format!("{}/{}", WILL_TOPIC_PREFIX, self.token),
+            message: Vec::new(),
+        }
+    }
+}
+
+/// Token of the will published on `topic`, if it is a will topic
+pub fn token(topic: &str) -> Option<&str> {
+    topic
+        .strip_prefix(WILL_TOPIC_PREFIX)?
+        .strip_prefix('/')
+        .filter(|token| !token.is_empty() && !token.contains('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::queue::topic;
+
+    #[test]
+    fn should_tell_token_of_will_topic() {
+        let sut = Will::generate();
+
+        let will = sut.last_will();
+
+        assert!(topic::matches(WILL_TOPIC_FILTER, &will.topic));
+        assert_eq!(token(&will.topic), Some(sut.token()));
+        assert!(will.message.is_empty());
+    }
+
+    #[test]
+    fn should_not_take_room_topic_for_will() {
+        assert_eq!(
+            token("df9ff5c8-c030-4e4a-8bae-a415565febd7/room/user"),
+            None
+        );
+    }
+
+    #[test]
+    fn should_generate_unique_tokens() {
+        assert_ne!(Will::generate(), Will::generate());
+    }
+}
@@ -0,0 +1,129 @@
+use anyhow::Result;
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use rand::Rng;
+
+use super::{room_key::RoomKey, wire, Decrypt, Encrypt};
+
+const NONCE_LEN: usize = 12;
+
+/// Authenticated encryption with a random nonce per message.
+///
+/// Output layout: `wire header | nonce | ciphertext with tag`
+#[derive(Clone)]
+pub struct ChaChaCrypt {
+    cipher: ChaCha20Poly1305,
+}
+
+impl ChaChaCrypt {
+    pub fn new(key: &RoomKey) -> Self {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key.as_bytes()));
+        Self { cipher }
+    }
+}
+
+impl Encrypt for ChaChaCrypt {
+    fn encrypt<T>(&self, data: T) -> Vec<u8>
+    where
+        T: AsRef<[u8]>,
+    {
+        let nonce = rand::thread_rng().gen::<[u8; NONCE_LEN]>();
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), data.as_ref())
+            .expect("Message too long to encrypt");
+
+        let mut encrypted = wire::header(wire::VERSION_CHACHA20_POLY1305).to_vec();
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend(ciphertext);
+        encrypted
+    }
+}
+
+impl Decrypt for ChaChaCrypt {
+    fn decrypt<T>(&self, data: T) -> Result<Vec<u8>>
+    where
+        T: AsRef<[u8]>,
+    {
+        let data = data.as_ref();
+        if wire::version(data) != Some(wire::VERSION_CHACHA20_POLY1305) {
+            anyhow::bail!("Unsupported ciphertext version");
+        }
+
+        let data = &data[wire::HEADER_LEN..];
+        if data.len() < NONCE_LEN {
+            anyhow::bail!("Ciphertext too short");
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Could not decrypt message"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crypto(key: u8) -> ChaChaCrypt {
+        ChaChaCrypt::new(&RoomKey::from_bytes([key; 32]))
+    }
+
+    #[test]
+    fn should_decrypt_encrypted_message() {
+        let sut = crypto(1);
+
+        let encrypted = sut.encrypt(b"secret message");
+
+        assert_eq!(sut.decrypt(encrypted).unwrap(), b"secret message");
+    }
+
+    #[test]
+    fn should_not_repeat_ciphertext_for_same_message() {
+        let sut = crypto(1);
+
+        assert_ne!(
+            sut.encrypt(b"secret message"),
+            sut.encrypt(b"secret message")
+        );
+    }
+
+    #[test]
+    fn should_tag_ciphertext_with_version() {
+        let sut = crypto(1);
+
+        let encrypted = sut.encrypt(b"secret message");
+
+        assert_eq!(
+            wire::version(&encrypted),
+            Some(wire::VERSION_CHACHA20_POLY1305)
+        );
+    }
+
+    #[test]
+    fn should_reject_tampered_message() {
+        let sut = crypto(1);
+
+        let mut encrypted = sut.encrypt(b"secret message");
+        *encrypted.last_mut().unwrap() ^= 1;
+
+        assert!(sut.decrypt(encrypted).is_err());
+    }
+
+    #[test]
+    fn should_reject_message_encrypted_with_other_key() {
+        let encrypted = crypto(1).encrypt(b"secret message");
+
+        assert!(crypto(2).decrypt(encrypted).is_err());
+    }
+
+    #[test]
+    fn should_reject_truncated_message() {
+        let sut = crypto(1);
+
+        let mut encrypted = sut.encrypt(b"secret message");
+        encrypted.truncate(wire::HEADER_LEN + 4);
+
+        assert!(sut.decrypt(encrypted).is_err());
+    }
+}
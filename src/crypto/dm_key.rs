@@ -0,0 +1,78 @@
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::room_key::RoomKey;
+
+const KEY_LEN: usize = 32;
+const INFO: &[u8] = b"rust-mqtt-chat/dm";
+
+/// X25519 key pair used to agree on a direct messages key with another user
+#[derive(Clone)]
+pub struct DmKeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl DmKeyPair {
+    pub fn generate() -> Self {
+        Self::from_bytes(StaticSecret::random_from_rng(rand::thread_rng()).to_bytes())
+    }
+
+    pub fn from_bytes(secret: [u8; KEY_LEN]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey::from(&secret);
+
+        Self { secret, public }
+    }
+
+    /// Secret key, to be kept private
+    pub fn to_bytes(&self) -> [u8; KEY_LEN] {
+        self.secret.to_bytes()
+    }
+
+    pub fn public_key(&self) -> [u8; KEY_LEN] {
+        self.public.to_bytes()
+    }
+
+    /// Key shared only with the owner of `their_public` key, both sides get
+    /// the same one
+    pub fn shared_key(&self, their_public: [u8; KEY_LEN]) -> RoomKey {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(their_public));
+
+        let mut key = [0; KEY_LEN];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(INFO, &mut key)
+            .expect("Key length valid for HKDF");
+
+        RoomKey::from_bytes(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_agree_on_same_key() {
+        let alice = DmKeyPair::generate();
+        let bob = DmKeyPair::generate();
+
+        let alice_key = alice.shared_key(bob.public_key());
+        let bob_key = bob.shared_key(alice.public_key());
+
+        assert_eq!(alice_key.as_bytes(), bob_key.as_bytes());
+    }
+
+    #[test]
+    fn should_not_share_key_with_third_user() {
+        let alice = DmKeyPair::generate();
+        let bob = DmKeyPair::generate();
+        let eve = DmKeyPair::generate();
+
+        let alice_key = alice.shared_key(bob.public_key());
+        let eve_key = eve.shared_key(bob.public_key());
+
+        assert_ne!(alice_key.as_bytes(), eve_key.as_bytes());
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::Result;
+
+use super::{wire, Decrypt, Encrypt};
+
+/// Encrypts with the `current` scheme, but still decrypts headerless
+/// ciphertexts with the `legacy` one. Meant for the migration window, while
+/// some room members still run clients without versioned ciphertexts. The
+/// window ends with [`FallbackCrypt::without_legacy`], and the legacy scheme
+/// is removed altogether in 0.2.0.
+#[derive(Clone)]
+pub struct FallbackCrypt<C, L> {
+    current: C,
+    legacy: L,
+    legacy_allowed: bool,
+}
+
+impl<C, L> FallbackCrypt<C, L>
+where
+    C: Encrypt + Decrypt,
+    L: Decrypt,
+{
+    pub fn new(current: C, legacy: L) -> Self {
+        Self {
+            current,
+            legacy,
+            legacy_allowed: true,
+        }
+    }
+
+    /// Refuses headerless ciphertexts, once every member encrypts with the
+    /// current scheme
+    pub fn without_legacy(mut self) -> Self {
+        self.legacy_allowed = false;
+        self
+    }
+}
+
+impl<C, L> Encrypt for FallbackCrypt<C, L>
+where
+    C: Encrypt,
+{
+    fn encrypt<T>(&self, data: T) -> Vec<u8>
+    where
+        T: AsRef<[u8]> + 'static,
+    {
+        self.current.encrypt(data)
+    }
+}
+
+impl<C, L> Decrypt for FallbackCrypt<C, L>
+where
+    C: Decrypt,
+    L: Decrypt,
+{
+    fn decrypt<T>(&self, data: T) -> Result<Vec<u8>>
+    where
+        T: AsRef<[u8]> + 'static,
+    {
+        match wire::version(data.as_ref()) {
+            Some(_) => self.current.decrypt(data),
+            None if self.legacy_allowed => self.legacy.decrypt(data),
+            None => anyhow::bail!("Legacy ciphertext refused"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::crypto::{chacha::ChaChaCrypt, magic_crypt::MagicCrypt, room_key::RoomKey};
+
+    fn sut() -> FallbackCrypt<ChaChaCrypt, MagicCrypt> {
+        FallbackCrypt::new(
+            ChaChaCrypt::new(&RoomKey::from_bytes([1; 32])),
+            MagicCrypt::new(&"password"),
+        )
+    }
+
+    #[test]
+    fn should_encrypt_with_current_scheme() {
+        let encrypted = sut().encrypt(b"message");
+
+        assert_eq!(
+            wire::version(&encrypted),
+            Some(wire::VERSION_CHACHA20_POLY1305)
+        );
+    }
+
+    #[test]
+    fn should_decrypt_current_scheme() {
+        let sut = sut();
+
+        let encrypted = sut.encrypt(b"message");
+
+        assert_eq!(sut.decrypt(encrypted).unwrap(), b"message");
+    }
+
+    #[test]
+    fn should_decrypt_legacy_scheme() {
+        let encrypted = MagicCrypt::new(&"password").encrypt(b"message");
+
+        assert_eq!(sut().decrypt(encrypted).unwrap(), b"message");
+    }
+
+    #[test]
+    fn should_refuse_legacy_scheme_after_migration() {
+        let encrypted = MagicCrypt::new(&"password").encrypt(b"message");
+
+        assert!(sut().without_legacy().decrypt(encrypted).is_err());
+    }
+}
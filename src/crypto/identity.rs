@@ -0,0 +1,90 @@
+use anyhow::Result;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Long-term Ed25519 key of a user, proves messages come from them
+#[derive(Clone)]
+pub struct IdentityKey {
+    signing: SigningKey,
+}
+
+impl IdentityKey {
+    pub fn generate() -> Self {
+        Self::from_bytes(rand::random())
+    }
+
+    pub fn from_bytes(secret: [u8; 32]) -> Self {
+        Self {
+            signing: SigningKey::from_bytes(&secret),
+        }
+    }
+
+    /// Secret key, keep it private
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.signing.to_bytes()
+    }
+
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.signing.verifying_key().to_bytes()
+    }
+
+    pub fn sign(&self, data: &[u8]) -> [u8; SIGNATURE_LEN] {
+        self.signing.sign(data).to_bytes()
+    }
+}
+
+/// Checks `signature` of `data` was made with the secret key of `public_key`
+pub fn verify(
+    public_key: &[u8; PUBLIC_KEY_LEN],
+    data: &[u8],
+    signature: &[u8; SIGNATURE_LEN],
+) -> Result<()> {
+    let key = VerifyingKey::from_bytes(public_key)?;
+    key.verify(data, &Signature::from_bytes(signature))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_verify_own_signature() {
+        let sut = IdentityKey::generate();
+
+        let signature = sut.sign(b"data");
+
+        assert!(verify(&sut.public_key(), b"data", &signature).is_ok());
+    }
+
+    #[test]
+    fn should_reject_changed_data() {
+        let sut = IdentityKey::generate();
+
+        let signature = sut.sign(b"data");
+
+        assert!(verify(&sut.public_key(), b"date", &signature).is_err());
+    }
+
+    #[test]
+    fn should_reject_signature_of_another_key() {
+        let sut = IdentityKey::generate();
+        let other = IdentityKey::generate();
+
+        let signature = other.sign(b"data");
+
+        assert!(verify(&sut.public_key(), b"data", &signature).is_err());
+    }
+
+    #[test]
+    fn should_restore_key_from_bytes() {
+        let sut = IdentityKey::generate();
+
+        let restored = IdentityKey::from_bytes(sut.to_bytes());
+
+        assert_eq!(restored.public_key(), sut.public_key());
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{chacha::ChaChaCrypt, room_key::RoomKey, wire, Decrypt, Encrypt};
+use crate::store::{keystore, room_file_name};
+
+/// How long keys of past epochs are still read after rotation, so messages
+/// sent just before it are not lost
+pub const EPOCH_GRACE: Duration = Duration::from_secs(60);
+
+const EPOCH_LEN: usize = 4;
+
+/// Room keys replacing the password derived one, numbered by epoch. Epoch 0
+/// stands for the password key, which is kept by the user of the keyring.
+///
+/// Output layout: `wire header | epoch (big endian) | nonce | ciphertext with tag`
+#[derive(Clone)]
+pub struct Keyring {
+    epochs: Arc<RwLock<Epochs>>,
+    grace: Duration,
+    /// File the current key is kept in, see [`Keyring::load`]
+    file: Option<PathBuf>,
+}
+
+/// Current key as kept on disk
+#[derive(Serialize, Deserialize)]
+struct SavedEpoch {
+    epoch: u32,
+    /// Base64 room key
+    key: String,
+}
+
+#[derive(Default)]
+struct Epochs {
+    current: u32,
+    keys: BTreeMap<u32, Epoch>,
+    /// Time the password key stops being read, set on the first rotation
+    password_retired_at: Option<Instant>,
+}
+
+struct Epoch {
+    key: RoomKey,
+    crypto: ChaChaCrypt,
+    retired_at: Option<Instant>,
+}
+
+impl Keyring {
+    pub fn new() -> Self {
+        Self {
+            epochs: Arc::default(),
+            grace: EPOCH_GRACE,
+            file: None,
+        }
+    }
+
+    /// Keyring of `room` keeping its current key in `dir`, so a restarted
+    /// admin can still hand out the key the room uses
+    pub fn load(dir: &Path, room: &str) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let file = dir.join(room_file_name(room, "epoch"));
+
+        let keyring = Self {
+            file: Some(file.clone()),
+            ..Self::new()
+        };
+        if file.exists() {
+            let saved: SavedEpoch = serde_json::from_slice(&std::fs::read(&file)?)?;
+            let key = base64::decode(saved.key)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Malformed key in {}", file.display()))?;
+            keyring.switch(saved.epoch, RoomKey::from_bytes(key));
+        }
+
+        Ok(keyring)
+    }
+
+    /// Reads past epochs for `grace` after rotation instead of [`EPOCH_GRACE`]
+    pub fn with_grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    /// Epoch messages are encrypted with, 0 until the first rotation
+    pub fn current_epoch(&self) -> u32 {
+        self.epochs.read().expect("Poisoned mutex").current
+    }
+
+    pub fn current_key(&self) -> Option<(u32, RoomKey)> {
+        let epochs = self.epochs.read().expect("Poisoned mutex");
+        let epoch = epochs.keys.get(&epochs.current)?;
+
+        Some((epochs.current, epoch.key.clone()))
+    }
+
+    /// Switches to `key` of `epoch`, unless a newer epoch is already used.
+    /// Keys of past epochs are read for a while longer.
+    pub fn rotate(&self, epoch: u32, key: RoomKey) -> Result<bool> {
+        let saved = SavedEpoch {
+            epoch,
+            key: base64::encode(key.as_bytes()),
+        };
+        if !self.switch(epoch, key) {
+            return Ok(false);
+        }
+
+        if let Some(file) = &self.file {
+            keystore::write_private(file, &serde_json::to_vec(&saved)?)?;
+        }
+
+        Ok(true)
+    }
+
+    fn switch(&self, epoch: u32, key: RoomKey) -> bool {
+        let mut epochs = self.epochs.write().expect("Poisoned mutex");
+        if epoch <= epochs.current {
+            return false;
+        }
+
+        let now = Instant::now();
+        let retired_at = now + self.grace;
+        epochs
+            .keys
+            .retain(|_, epoch| still_read(epoch.retired_at, now));
+        for past in epochs.keys.values_mut() {
+            past.retired_at.get_or_insert(retired_at);
+        }
+        epochs.password_retired_at.get_or_insert(retired_at);
+
+        epochs.keys.insert(
+            epoch,
+            Epoch {
+                crypto: ChaChaCrypt::new(&key),
+                key,
+                retired_at: None,
+            },
+        );
+        epochs.current = epoch;
+
+        true
+    }
+
+    /// Whether messages encrypted with the password key are still read
+    pub fn reads_password_key(&self) -> bool {
+        let epochs = self.epochs.read().expect("Poisoned mutex");
+        still_read(epochs.password_retired_at, Instant::now())
+    }
+
+    /// Encrypts with the key of the current epoch, `None` before the first
+    /// rotation when the password key is to be used
+    pub fn encrypt<T>(&self, data: T) -> Option<Vec<u8>>
+    where
+        T: AsRef<[u8]> + 'static,
+    {
+        let epochs = self.epochs.read().expect("Poisoned mutex");
+        let epoch = epochs.keys.get(&epochs.current)?;
+
+        let encrypted = epoch.crypto.encrypt(data);
+        let mut tagged = wire::header(wire::VERSION_EPOCH_CHACHA20_POLY1305).to_vec();
+        tagged.extend_from_slice(&epochs.current.to_be_bytes());
+        tagged.extend_from_slice(&encrypted[wire::HEADER_LEN..]);
+
+        Some(tagged)
+    }
+
+    /// Expects data returned by [`Keyring::encrypt`] with a key not retired yet
+    pub fn decrypt<T>(&self, data: T) -> Result<Vec<u8>>
+    where
+        T: AsRef<[u8]>,
+    {
+        let data = data.as_ref();
+        if wire::version(data) != Some(wire::VERSION_EPOCH_CHACHA20_POLY1305) {
+            anyhow::bail!("Unsupported ciphertext version");
+        }
+        let data = &data[wire::HEADER_LEN..];
+        if data.len() < EPOCH_LEN {
+            anyhow::bail!("Ciphertext too short");
+        }
+        let (epoch, ciphertext) = data.split_at(EPOCH_LEN);
+        let epoch = u32::from_be_bytes(epoch.try_into().expect("Epoch length checked"));
+
+        let epochs = self.epochs.read().expect("Poisoned mutex");
+        let key = epochs
+            .keys
+            .get(&epoch)
+            .filter(|key| still_read(key.retired_at, Instant::now()))
+            .ok_or_else(|| anyhow::anyhow!("No key of epoch {}", epoch))?;
+
+        let mut untagged = wire::header(wire::VERSION_CHACHA20_POLY1305).to_vec();
+        untagged.extend_from_slice(ciphertext);
+        key.crypto.decrypt(untagged)
+    }
+}
+
+impl Default for Keyring {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether a key retired at `retired_at`, if it was, is still read at `now`
+fn still_read(retired_at: Option<Instant>, now: Instant) -> bool {
+    match retired_at {
+        Some(at) => now < at,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::store::temp_dir;
+
+    fn key(byte: u8) -> RoomKey {
+        RoomKey::from_bytes([byte; 32])
+    }
+
+    #[test]
+    fn should_use_password_key_until_rotated() {
+        let sut = Keyring::new();
+
+        assert_eq!(sut.current_epoch(), 0);
+        assert!(sut.encrypt(b"message").is_none());
+        assert!(sut.reads_password_key());
+    }
+
+    #[test]
+    fn should_decrypt_message_of_current_epoch() {
+        let sut = Keyring::new();
+        sut.rotate(1, key(1)).unwrap();
+
+        let encrypted = sut.encrypt(b"message").unwrap();
+
+        assert_eq!(
+            wire::version(&encrypted),
+            Some(wire::VERSION_EPOCH_CHACHA20_POLY1305)
+        );
+        assert_eq!(sut.decrypt(encrypted).unwrap(), b"message");
+    }
+
+    #[test]
+    fn should_read_past_epoch_during_grace() {
+        let sut = Keyring::new();
+        sut.rotate(1, key(1)).unwrap();
+        let encrypted = sut.encrypt(b"in flight").unwrap();
+
+        sut.rotate(2, key(2)).unwrap();
+
+        assert_eq!(sut.current_epoch(), 2);
+        assert_eq!(sut.decrypt(encrypted).unwrap(), b"in flight");
+        assert!(sut.reads_password_key());
+    }
+
+    #[test]
+    fn should_retire_past_epochs_after_grace() {
+        let sut = Keyring::new().with_grace(Duration::ZERO);
+        sut.rotate(1, key(1)).unwrap();
+        let encrypted = sut.encrypt(b"old").unwrap();
+
+        sut.rotate(2, key(2)).unwrap();
+
+        assert!(sut.decrypt(encrypted).is_err());
+        assert!(!sut.reads_password_key());
+    }
+
+    #[test]
+    fn should_ignore_rotation_to_older_epoch() {
+        let sut = Keyring::new();
+        sut.rotate(2, key(2)).unwrap();
+
+        assert!(!sut.rotate(1, key(1)).unwrap());
+        assert_eq!(sut.current_key().unwrap().0, 2);
+    }
+
+    #[test]
+    fn should_not_decrypt_unknown_epoch() {
+        let other = Keyring::new();
+        other.rotate(1, key(1)).unwrap();
+        let sut = Keyring::new();
+        sut.rotate(2, key(1)).unwrap();
+
+        assert!(sut.decrypt(other.encrypt(b"message").unwrap()).is_err());
+    }
+
+    #[test]
+    fn should_keep_current_key_on_disk() {
+        let dir = temp_dir();
+        let sut = Keyring::load(&dir, "room").unwrap();
+        sut.rotate(1, key(1)).unwrap();
+        sut.rotate(2, key(2)).unwrap();
+        let encrypted = sut.encrypt(b"message").unwrap();
+
+        let loaded = Keyring::load(&dir, "room").unwrap();
+
+        assert_eq!(loaded.current_epoch(), 2);
+        assert_eq!(loaded.decrypt(encrypted).unwrap(), b"message");
+        assert_eq!(
+            Keyring::load(&dir, "other room").unwrap().current_epoch(),
+            0
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.join("room.epoch"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
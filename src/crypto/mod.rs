@@ -1,19 +1,26 @@
 use anyhow::Result;
 
+pub mod chacha;
+pub mod dm_key;
+pub mod fallback;
+pub mod identity;
+pub mod keyring;
 pub mod magic_crypt;
+pub mod room_key;
+pub mod sender_key;
+pub mod wire;
 
 #[cfg_attr(test, mockall::automock)]
 pub trait Encrypt {
-    /// Returns base64 encrypted string
+    /// Returns encrypted data
     fn encrypt<T>(&self, data: T) -> Vec<u8>
     where
         T: AsRef<[u8]> + 'static; // 'static needed only for mocking purposes
 }
 
 #[cfg_attr(test, mockall::automock)]
-
 pub trait Decrypt {
-    /// Expects input as a base64 string
+    /// Expects data returned by [`Encrypt::encrypt`]
     fn decrypt<T>(&self, data: T) -> Result<Vec<u8>>
     where
         T: AsRef<[u8]> + 'static; // 'static needed only for mocking purposes
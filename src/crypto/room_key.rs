@@ -0,0 +1,83 @@
+use anyhow::Result;
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+const KEY_LEN: usize = 32;
+const SALT_PREFIX: &str = "rust-mqtt-chat/room/";
+const TOPIC_INFO: &[u8] = b"rust-mqtt-chat/topic";
+const TOPIC_ID_LEN: usize = 16;
+
+/// Symmetric key shared by everyone who knows the room password
+#[derive(Clone)]
+pub struct RoomKey([u8; KEY_LEN]);
+
+impl RoomKey {
+    /// Derives the key with Argon2id, salted with the room name, so the same
+    /// password gives unrelated keys in different rooms.
+    pub fn derive(password: &impl AsRef<str>, room: &impl AsRef<str>) -> Result<Self> {
+        let params = Params::new(19 * 1024, 2, 1, Some(KEY_LEN)).map_err(|e| anyhow::anyhow!(e))?;
+        let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+        let salt = format!("{}{}", SALT_PREFIX, room.as_ref());
+        let mut key = [0; KEY_LEN];
+        argon
+            .hash_password_into(password.as_ref().as_bytes(), salt.as_bytes(), &mut key)
+            .map_err(|e| anyhow::anyhow!(e))?;
+
+        Ok(Self(key))
+    }
+
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    /// Name of `room` that only those knowing the key can tell, usable as a
+    /// topic level
+    pub fn topic_id(&self, room: &impl AsRef<str>) -> String {
+        let hkdf = Hkdf::<Sha256>::new(Some(room.as_ref().as_bytes()), &self.0);
+        let mut id = [0; TOPIC_ID_LEN];
+        hkdf.expand(TOPIC_INFO, &mut id)
+            .expect("Id length valid for HKDF");
+
+        base64::encode_config(id, base64::URL_SAFE_NO_PAD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_derive_same_key_for_same_room_and_password() {
+        let first = RoomKey::derive(&"password", &"room").unwrap();
+        let second = RoomKey::derive(&"password", &"room").unwrap();
+
+        assert_eq!(first.as_bytes(), second.as_bytes());
+    }
+
+    #[test]
+    fn should_derive_different_keys_for_different_rooms() {
+        let first = RoomKey::derive(&"password", &"room").unwrap();
+        let second = RoomKey::derive(&"password", &"other room").unwrap();
+
+        assert_ne!(first.as_bytes(), second.as_bytes());
+    }
+
+    #[test]
+    fn should_derive_topic_id_known_only_with_key() {
+        let key = RoomKey::from_bytes([1; KEY_LEN]);
+        let other_key = RoomKey::from_bytes([2; KEY_LEN]);
+
+        let id = key.topic_id(&"room");
+
+        assert_eq!(id, key.topic_id(&"room"));
+        assert_ne!(id, key.topic_id(&"other room"));
+        assert_ne!(id, other_key.topic_id(&"room"));
+        assert!(!id.contains(['/', '+', '#']));
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    convert::TryInto,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use super::{chacha::ChaChaCrypt, room_key::RoomKey, wire, Decrypt, Encrypt};
+
+const KEY_LEN: usize = 32;
+const CHAIN_ID_LEN: usize = 8;
+const ITERATION_LEN: usize = 4;
+const CHAIN_INFO: &[u8] = b"rust-mqtt-chat/sender-key/chain";
+const MESSAGE_INFO: &[u8] = b"rust-mqtt-chat/sender-key/message";
+
+/// Max number of messages of a chain that may be missed or come out of order
+const MAX_SKIPPED: u32 = 1000;
+
+/// Chains kept per sender, the older ones for messages sent before rotation
+const CHAINS_PER_SENDER: usize = 2;
+
+/// State of a sender chain, enough to read messages from `iteration` on.
+/// Sent sealed to every member, see [`SenderKeys`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SenderChain {
+    pub id: u64,
+    pub iteration: u32,
+    pub key: [u8; KEY_LEN],
+}
+
+impl SenderChain {
+    fn generate() -> Self {
+        Self {
+            id: rand::random(),
+            iteration: 0,
+            key: rand::random(),
+        }
+    }
+
+    /// Key of the message at `iteration`, moving the chain past it. Chain
+    /// keys are one way, so a chain can't be turned back to earlier messages.
+    fn next_message_key(&mut self) -> [u8; KEY_LEN] {
+        let hkdf = Hkdf::<Sha256>::from_prk(&self.key).expect("Chain key long enough for HKDF");
+        let mut message_key = [0; KEY_LEN];
+        hkdf.expand(MESSAGE_INFO, &mut message_key)
+            .expect("Key length valid for HKDF");
+        hkdf.expand(CHAIN_INFO, &mut self.key)
+            .expect("Key length valid for HKDF");
+        self.iteration += 1;
+
+        message_key
+    }
+}
+
+/// Chain of another sender, with keys of messages skipped on the way
+#[derive(Clone)]
+struct ReceivingChain {
+    chain: SenderChain,
+    skipped: BTreeMap<u32, [u8; KEY_LEN]>,
+}
+
+impl ReceivingChain {
+    fn message_key(&mut self, iteration: u32) -> Result<[u8; KEY_LEN]> {
+        if iteration < self.chain.iteration {
+            return self
+                .skipped
+                .remove(&iteration)
+                .ok_or_else(|| anyhow::anyhow!("Message key already used or forgotten"));
+        }
+        if iteration - self.chain.iteration > MAX_SKIPPED {
+            anyhow::bail!("Too many messages skipped");
+        }
+
+        while self.chain.iteration < iteration {
+            let skipped = self.chain.iteration;
+            let key = self.chain.next_message_key();
+            self.skipped.insert(skipped, key);
+        }
+        while self.skipped.len() > MAX_SKIPPED as usize {
+            if let Some(oldest) = self.skipped.keys().next().copied() {
+                self.skipped.remove(&oldest);
+            }
+        }
+
+        Ok(self.chain.next_message_key())
+    }
+}
+
+/// Group encryption with a hash ratchet per sender. Every message is
+/// encrypted with a key used once and forgotten, derived from the sender's
+/// chain key, which then moves forward. Getting hold of the current chain
+/// keys doesn't expose past messages.
+///
+/// Sender chains are handed out by the user, e.g. sealed for every member of
+/// the room. Senders are not authenticated by the chain, anyone knowing it
+/// can encrypt with it, so messages need signatures of their own.
+///
+/// Output layout: `wire header | chain id | iteration | nonce | ciphertext with tag`,
+/// numbers big endian
+#[derive(Clone)]
+pub struct SenderKeys {
+    state: Arc<RwLock<State>>,
+}
+
+struct State {
+    own: SenderChain,
+    /// Own chains, newest last, as own messages come back from the broker
+    own_ids: VecDeque<u64>,
+    chains: HashMap<u64, ReceivingChain>,
+    /// Chains of every sender, newest last
+    sender_ids: HashMap<String, VecDeque<u64>>,
+}
+
+impl SenderKeys {
+    pub fn new() -> Self {
+        let keys = Self {
+            state: Arc::new(RwLock::new(State {
+                own: SenderChain::generate(),
+                own_ids: VecDeque::new(),
+                chains: HashMap::new(),
+                sender_ids: HashMap::new(),
+            })),
+        };
+        // Own chain is kept for reading like any later one
+        keys.rotate();
+
+        keys
+    }
+
+    /// Chain of this user from the next message on
+    pub fn own_chain(&self) -> SenderChain {
+        self.state.read().expect("Poisoned mutex").own.clone()
+    }
+
+    /// Starts a new chain of this user, messages of the past one are still
+    /// read for a while. Returns the new chain to be handed out.
+    pub fn rotate(&self) -> SenderChain {
+        let mut state = self.state.write().expect("Poisoned mutex");
+        let chain = SenderChain::generate();
+
+        state.own = chain.clone();
+        let State {
+            own_ids, chains, ..
+        } = &mut *state;
+        keep_chain(own_ids, chains, chain.clone());
+
+        chain
+    }
+
+    /// Reads messages of `sender` sent with `chain` from now on. Chains
+    /// already known are left as they are, so they can't be turned back.
+    pub fn add(&self, sender: &str, chain: SenderChain) {
+        let mut state = self.state.write().expect("Poisoned mutex");
+        if state.chains.contains_key(&chain.id) {
+            return;
+        }
+
+        let State {
+            chains, sender_ids, ..
+        } = &mut *state;
+        keep_chain(
+            sender_ids.entry(sender.to_string()).or_default(),
+            chains,
+            chain,
+        );
+    }
+
+    /// Whether a chain of `sender` is known
+    pub fn knows(&self, sender: &str) -> bool {
+        let state = self.state.read().expect("Poisoned mutex");
+        state.sender_ids.contains_key(sender)
+    }
+}
+
+impl Default for SenderKeys {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn keep_chain(
+    ids: &mut VecDeque<u64>,
+    chains: &mut HashMap<u64, ReceivingChain>,
+    chain: SenderChain,
+) {
+    ids.push_back(chain.id);
+    chains.insert(
+        chain.id,
+        ReceivingChain {
+            chain,
+            skipped: BTreeMap::new(),
+        },
+    );
+    while ids.len() > CHAINS_PER_SENDER {
+        if let Some(id) = ids.pop_front() {
+            chains.remove(&id);
+        }
+    }
+}
+
+fn crypto(message_key: [u8; KEY_LEN]) -> ChaChaCrypt {
+    ChaChaCrypt::new(&RoomKey::from_bytes(message_key))
+}
+
+impl Encrypt for SenderKeys {
+    fn encrypt<T>(&self, data: T) -> Vec<u8>
+    where
+        T: AsRef<[u8]> + 'static,
+    {
+        let mut state = self.state.write().expect("Poisoned mutex");
+        let id = state.own.id;
+        let iteration = state.own.iteration;
+        let message_key = state.own.next_message_key();
+        drop(state);
+
+        let encrypted = crypto(message_key).encrypt(data);
+        let mut tagged = wire::header(wire::VERSION_SENDER_KEY_CHACHA20_POLY1305).to_vec();
+        tagged.extend_from_slice(&id.to_be_bytes());
+        tagged.extend_from_slice(&iteration.to_be_bytes());
+        tagged.extend_from_slice(&encrypted[wire::HEADER_LEN..]);
+
+        tagged
+    }
+}
+
+impl Decrypt for SenderKeys {
+    fn decrypt<T>(&self, data: T) -> Result<Vec<u8>>
+    where
+        T: AsRef<[u8]> + 'static,
+    {
+        let data = data.as_ref();
+        if wire::version(data) != Some(wire::VERSION_SENDER_KEY_CHACHA20_POLY1305) {
+            anyhow::bail!("Unsupported ciphertext version");
+        }
+        let data = &data[wire::HEADER_LEN..];
+        if data.len() < CHAIN_ID_LEN + ITERATION_LEN {
+            anyhow::bail!("Ciphertext too short");
+        }
+        let (id, data) = data.split_at(CHAIN_ID_LEN);
+        let (iteration, ciphertext) = data.split_at(ITERATION_LEN);
+        let id = u64::from_be_bytes(id.try_into().expect("Chain id length checked"));
+        let iteration = u32::from_be_bytes(iteration.try_into().expect("Iteration length checked"));
+
+        let mut state = self.state.write().expect("Poisoned mutex");
+        let chain = state
+            .chains
+            .get_mut(&id)
+            .ok_or_else(|| anyhow::anyhow!("Unknown sender chain"))?;
+        // Moved forward only once the message turns out genuine
+        let mut moved = chain.clone();
+        let message_key = moved.message_key(iteration)?;
+
+        let mut untagged = wire::header(wire::VERSION_CHACHA20_POLY1305).to_vec();
+        untagged.extend_from_slice(ciphertext);
+        let decrypted = crypto(message_key).decrypt(untagged)?;
+        *chain = moved;
+
+        Ok(decrypted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members() -> (SenderKeys, SenderKeys) {
+        let alice = SenderKeys::new();
+        let bob = SenderKeys::new();
+        bob.add("alice", alice.own_chain());
+        (alice, bob)
+    }
+
+    #[test]
+    fn should_decrypt_message_of_known_sender() {
+        let (alice, bob) = members();
+
+        let encrypted = alice.encrypt(b"message");
+
+        assert_eq!(bob.decrypt(encrypted).unwrap(), b"message");
+    }
+
+    #[test]
+    fn should_decrypt_own_message() {
+        let alice = SenderKeys::new();
+
+        let encrypted = alice.encrypt(b"message");
+
+        assert_eq!(alice.decrypt(encrypted).unwrap(), b"message");
+    }
+
+    #[test]
+    fn should_decrypt_messages_out_of_order() {
+        let (alice, bob) = members();
+        let first = alice.encrypt(b"first");
+        let second = alice.encrypt(b"second");
+
+        assert_eq!(bob.decrypt(second).unwrap(), b"second");
+        assert_eq!(bob.decrypt(first).unwrap(), b"first");
+    }
+
+    #[test]
+    fn should_use_message_key_once() {
+        let (alice, bob) = members();
+        let encrypted = alice.encrypt(b"message");
+        bob.decrypt(encrypted.clone()).unwrap();
+
+        assert!(bob.decrypt(encrypted).is_err());
+    }
+
+    #[test]
+    fn should_not_expose_past_messages_with_current_chain() {
+        let alice = SenderKeys::new();
+        let past = alice.encrypt(b"past");
+
+        let stolen = SenderKeys::new();
+        stolen.add("alice", alice.own_chain());
+
+        assert!(stolen.decrypt(past).is_err());
+        assert_eq!(stolen.decrypt(alice.encrypt(b"now")).unwrap(), b"now");
+    }
+
+    #[test]
+    fn should_read_previous_chain_after_rotation() {
+        let (alice, bob) = members();
+        let in_flight = alice.encrypt(b"in flight");
+
+        bob.add("alice", alice.rotate());
+
+        assert_eq!(bob.decrypt(in_flight).unwrap(), b"in flight");
+        assert_eq!(bob.decrypt(alice.encrypt(b"new")).unwrap(), b"new");
+    }
+
+    #[test]
+    fn should_not_read_sender_without_chain() {
+        let alice = SenderKeys::new();
+        let eve = SenderKeys::new();
+
+        assert!(eve.decrypt(alice.encrypt(b"message")).is_err());
+    }
+
+    #[test]
+    fn should_not_read_rotated_chain_of_left_out_member() {
+        let (alice, bob) = members();
+
+        alice.rotate();
+
+        assert!(bob.decrypt(alice.encrypt(b"after")).is_err());
+    }
+
+    #[test]
+    fn should_keep_chain_that_was_already_known() {
+        let (alice, bob) = members();
+        let old_state = alice.own_chain();
+        bob.decrypt(alice.encrypt(b"first")).unwrap();
+
+        bob.add("alice", old_state);
+
+        assert!(bob.knows("alice"));
+        assert_eq!(bob.decrypt(alice.encrypt(b"second")).unwrap(), b"second");
+    }
+}
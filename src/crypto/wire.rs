@@ -0,0 +1,47 @@
+//! Versioned header prepended to ciphertexts, so receivers can tell which
+//! scheme produced them. Legacy [`super::magic_crypt::MagicCrypt`]
+//! ciphertexts carry no header at all.
+
+const MAGIC: &[u8; 3] = b"RMC";
+
+pub const HEADER_LEN: usize = MAGIC.len() + 1;
+
+/// ChaCha20-Poly1305 with Argon2id derived room key
+pub const VERSION_CHACHA20_POLY1305: u8 = 1;
+
+/// ChaCha20-Poly1305 with a rotated room key, tagged with its epoch
+pub const VERSION_EPOCH_CHACHA20_POLY1305: u8 = 2;
+
+/// ChaCha20-Poly1305 with a key used once, from the sender's chain
+pub const VERSION_SENDER_KEY_CHACHA20_POLY1305: u8 = 3;
+
+pub fn header(version: u8) -> [u8; HEADER_LEN] {
+    [MAGIC[0], MAGIC[1], MAGIC[2], version]
+}
+
+/// Returns scheme version of `data` or `None` for headerless (legacy) data
+pub fn version(data: &[u8]) -> Option<u8> {
+    match data {
+        [m0, m1, m2, version, ..] if [*m0, *m1, *m2] == *MAGIC => Some(*version),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_read_version_from_header() {
+        let mut data = header(7).to_vec();
+        data.extend_from_slice(b"payload");
+
+        assert_eq!(version(&data), Some(7));
+    }
+
+    #[test]
+    fn should_not_find_version_in_headerless_data() {
+        assert_eq!(version(b"RM"), None);
+        assert_eq!(version(b"legacy payload"), None);
+    }
+}
@@ -1,4 +1,5 @@
 pub mod chat_room;
 pub mod crypto;
 pub mod queue;
+pub mod store;
 pub mod tui;
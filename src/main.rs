@@ -1,11 +1,36 @@
+use anyhow::Context;
 use rust_mqtt_chat::{
-    chat_room::queue_chat_room::QueueChatRoom,
-    crypto::magic_crypt::MagicCrypt,
-    queue::{encrypted_queue::EncryptedQueue, mqtt::MqttQueue},
-    tui::{components::main_view::MainView, terminal_driver::TerminalDriver},
+    chat_room::{
+        queue_chat_room::QueueChatRoom,
+        validate_name,
+        will::{Will, WILL_TOPIC_FILTER},
+        ChatRoom,
+    },
+    crypto::{
+        chacha::ChaChaCrypt, dm_key::DmKeyPair, fallback::FallbackCrypt, identity::IdentityKey,
+        keyring::Keyring, magic_crypt::MagicCrypt, room_key::RoomKey, sender_key::SenderKeys,
+    },
+    queue::{
+        demux::{Demux, DemuxQueue},
+        encrypted_queue::EncryptedQueue,
+        mqtt::{MqttQueue, ReconnectPolicy},
+    },
+    store::{
+        file_store::FileStore,
+        keystore::{self, KeyDirectory},
+    },
+    tui::{components::main_view::MainView, history::History, terminal_driver::TerminalDriver},
+};
+use std::{
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
 };
 use structopt::StructOpt;
 
+type Room =
+    QueueChatRoom<EncryptedQueue<DemuxQueue<MqttQueue>, FallbackCrypt<ChaChaCrypt, MagicCrypt>>>;
+
 #[derive(StructOpt)]
 #[structopt(
     name = "rust mqtt chat",
@@ -16,38 +41,221 @@ struct Opt {
     #[structopt(short, long, env)]
     server: String,
 
-    /// Name of chat room to connect to
-    #[structopt(short, long, env)]
-    room: String,
+    /// Names of chat rooms to connect to, repeat to join several
+    #[structopt(short, long, env, required = true)]
+    room: Vec<String>,
 
-    /// Rooms password
-    #[structopt(short, long, env)]
-    password: String,
+    /// Rooms password, either one for all rooms or one for every room in the same order
+    #[structopt(short, long, env, required = true)]
+    password: Vec<String>,
 
     /// User name
     #[structopt(short, long, env)]
     user: String,
+
+    /// Number of lines the input box can grow to
+    #[structopt(long, default_value = "5")]
+    input_rows: u16,
+
+    /// Directory to keep sent messages history in, history is not saved if not set
+    #[structopt(long, env, parse(from_os_str))]
+    history_dir: Option<PathBuf>,
+
+    /// Directory to keep encrypted room messages and renames of members in, nothing is saved if not set
+    #[structopt(long, env, parse(from_os_str))]
+    store_dir: Option<PathBuf>,
+
+    /// Directory to keep identity and direct messages keys, keys of other users and changed room keys in, new ones are made every start if not set
+    #[structopt(long, env, parse(from_os_str))]
+    key_dir: Option<PathBuf>,
+
+    /// Users allowed to change room keys, repeat for several, keys sent by others are ignored
+    #[structopt(long)]
+    admin: Vec<String>,
+
+    /// Rooms to encrypt end-to-end with keys of every sender, repeat for several, the password only guards key exchange
+    #[structopt(long)]
+    e2e: Vec<String>,
+
+    /// Number of saved messages to show on start
+    #[structopt(long, default_value = "100")]
+    stored_messages: usize,
+
+    /// Show a notice for every received message that could not be decrypted or read
+    #[structopt(long)]
+    show_bad_messages: bool,
+
+    /// Refuse messages of clients from before the authenticated encryption, once everyone updated
+    #[structopt(long)]
+    no_legacy_crypto: bool,
+
+    /// Take presence, topic and typing of clients that don't sign messages, anyone with the password can then pass for them
+    #[structopt(long)]
+    allow_unsigned: bool,
+
+    /// Publish on topics derived from the room key instead of room and user names, everyone in the room has to set it
+    #[structopt(long)]
+    hide_topics: bool,
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), anyhow::Error> {
-    let opt = Opt::from_args();
+    let opt = Rc::new(Opt::from_args());
+    if opt.password.len() != 1 && opt.password.len() != opt.room.len() {
+        anyhow::bail!("Give one password for all rooms or one for every room");
+    }
+    for room in &opt.room {
+        validate_name(room).with_context(|| format!("Invalid room {}", room))?;
+    }
 
-    let queue = MqttQueue::new(opt.server).await?;
+    let will = Will::generate();
+    let queue = MqttQueue::new(
+        opt.server.clone(),
+        ReconnectPolicy::default(),
+        Some(will.last_will()),
+    )
+    .await?;
+    let mut demux = Demux::new(queue);
 
-    let crypto = MagicCrypt::new(&opt.password);
-    let queue = EncryptedQueue::new(queue, crypto);
+    let keys = Rc::new(Keys::load(opt.key_dir.as_deref(), will)?);
 
-    let mut chat_room = QueueChatRoom::new(queue, opt.user, opt.room).await?;
+    let mut ui = MainView::new().with_input_rows(opt.input_rows);
+    for (i, room) in opt.room.iter().enumerate() {
+        let password = opt.password.get(i).unwrap_or(&opt.password[0]);
+        let (chat_room, history) = join_room(&opt, &keys, &demux, room, password).await?;
+
+        ui = ui.with_room(room.clone(), chat_room, history);
+    }
 
-    let ui = MainView::new(chat_room.clone());
+    let join_opt = opt.clone();
+    let join_keys = keys.clone();
+    let join_demux = demux.clone();
+    ui = ui.with_join_room(Box::new(move |room| {
+        let opt = join_opt.clone();
+        let keys = join_keys.clone();
+        let demux = join_demux.clone();
+        Box::pin(async move { join_room(&opt, &keys, &demux, &room, &opt.password[0]).await })
+    }));
 
     let mut driver = TerminalDriver::new(std::io::stdout())?;
 
     tokio::select! {
-        r = chat_room.run() => {r?}
-        r = driver.run(ui) => {r?}
+        r = demux.run() => {r?}
+        r = driver.run(&mut ui) => {r?}
     }
 
-    Ok(())
+    ui.leave_rooms().await
+}
+
+/// Keys of this user, keys of others and the connection will, shared by all rooms
+struct Keys {
+    identity: IdentityKey,
+    key_directory: Arc<KeyDirectory>,
+    dm_keys: DmKeyPair,
+    will: Will,
+}
+
+impl Keys {
+    /// Keys kept in `dir`, new ones forgotten on exit without it
+    fn load(dir: Option<&Path>, will: Will) -> Result<Self, anyhow::Error> {
+        let (identity, key_directory, dm_keys) = match dir {
+            Some(dir) => (
+                keystore::load_identity(dir)?,
+                KeyDirectory::load(dir)?,
+                keystore::load_dm_key(dir)?,
+            ),
+            None => (
+                IdentityKey::generate(),
+                KeyDirectory::new(),
+                DmKeyPair::generate(),
+            ),
+        };
+
+        Ok(Self {
+            identity,
+            key_directory: Arc::new(key_directory),
+            dm_keys,
+            will,
+        })
+    }
+}
+
+async fn join_room(
+    opt: &Opt,
+    keys: &Keys,
+    demux: &Demux<MqttQueue>,
+    room: &str,
+    password: &str,
+) -> Result<(Room, History), anyhow::Error> {
+    let key = RoomKey::derive(&password, &room)?;
+    let mut crypto = FallbackCrypt::new(ChaChaCrypt::new(&key), MagicCrypt::new(&password));
+    if opt.no_legacy_crypto {
+        crypto = crypto.without_legacy();
+    }
+    let queue =
+        EncryptedQueue::new(demux.connect(), crypto).with_plain_topic(WILL_TOPIC_FILTER.into());
+    let keyring = match &opt.key_dir {
+        Some(dir) => Keyring::load(dir, room)?,
+        None => Keyring::new(),
+    };
+    let sender_keys = SenderKeys::new();
+    let end_to_end = opt.e2e.iter().any(|e2e| e2e == room);
+    let queue = if end_to_end {
+        queue.with_sender_keys(sender_keys.clone())
+    } else {
+        queue.with_keyring(keyring.clone())
+    };
+
+    let history = match &opt.history_dir {
+        Some(dir) => History::load(dir, room, ChaChaCrypt::new(&key))?,
+        None => History::new(),
+    };
+
+    let store = match &opt.store_dir {
+        Some(dir) => Some(FileStore::open(dir, room, ChaChaCrypt::new(&key))?),
+        None => None,
+    };
+
+    let chat_room = if opt.hide_topics {
+        QueueChatRoom::new_hidden(queue, opt.user.clone(), room.to_string(), &key).await?
+    } else {
+        QueueChatRoom::new(queue, opt.user.clone(), room.to_string()).await?
+    };
+    let mut chat_room = chat_room
+        .with_identity(keys.identity.clone(), keys.key_directory.clone())
+        .with_dm_keys(keys.dm_keys.clone())
+        .with_will(keys.will.clone());
+    chat_room = if end_to_end {
+        chat_room.with_sender_keys(sender_keys)
+    } else {
+        chat_room.with_keyring(keyring, opt.admin.clone())
+    };
+    if let Some(store) = store {
+        chat_room = chat_room.with_store(store, opt.stored_messages);
+    }
+    if opt.show_bad_messages {
+        chat_room = chat_room.with_bad_message_notices();
+    }
+    if opt.allow_unsigned {
+        chat_room = chat_room.with_unsigned_peers();
+    }
+    start(chat_room.clone());
+
+    Ok((chat_room, history))
+}
+
+/// Keeps receiving messages and heartbeats of `room` going in background,
+/// until the user leaves it
+fn start(room: Room) {
+    let mut receiving = room.clone();
+    tokio::spawn(async move {
+        if let Err(e) = receiving.run().await {
+            receiving.notice(format!("Stopped receiving messages: {:#}", e));
+        }
+    });
+    tokio::spawn(async move {
+        if let Err(e) = room.heartbeat().await {
+            room.notice(format!("Stopped telling others you are online: {:#}", e));
+        }
+    });
 }
@@ -0,0 +1,214 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use futures::{channel::mpsc, lock::Mutex, StreamExt};
+
+use super::{topic, ConnectionState, Error, Message, Queue, ReceivedMessage};
+
+struct Route {
+    filter: String,
+    client: usize,
+    sender: mpsc::UnboundedSender<ReceivedMessage>,
+}
+
+/// Shares one queue connection between several clients, e.g. rooms. Messages
+/// received by [`Demux::run`] are handed to every client subscribed to their topic.
+/// The connection has a single last will, given to the queue before connecting.
+#[derive(Clone)]
+pub struct Demux<Q> {
+    queue: Q,
+    routes: Arc<std::sync::Mutex<Vec<Route>>>,
+    next_client: Arc<AtomicUsize>,
+}
+
+impl<Q> Demux<Q>
+where
+    Q: Queue + Clone,
+{
+    pub fn new(queue: Q) -> Self {
+        Self {
+            queue,
+            routes: Arc::default(),
+            next_client: Arc::default(),
+        }
+    }
+
+    pub fn connect(&self) -> DemuxQueue<Q> {
+        let (sender, receiver) = mpsc::unbounded();
+
+        DemuxQueue {
+            demux: self.clone(),
+            client: self.next_client.fetch_add(1, Ordering::Relaxed),
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+        }
+    }
+
+    /// Routes received messages until the underlying queue fails
+    pub async fn run(&mut self) -> Result<(), Error> {
+        loop {
+            let received = self.queue.receive().await?;
+            self.route(&received);
+        }
+    }
+
+    fn route(&self, received: &ReceivedMessage) {
+        let mut routes = self.routes.lock().expect("Poisoned mutex");
+        routes.retain(|route| !route.sender.is_closed());
+
+        let mut delivered_to = Vec::new();
+        for route in routes.iter() {
+            if delivered_to.contains(&route.client)
+                || !topic::matches(&route.filter, &received.topic)
+            {
+                continue;
+            }
+
+            delivered_to.push(route.client);
+            let _ = route.sender.unbounded_send(received.clone());
+        }
+    }
+}
+
+/// Client of a [`Demux`], receives only messages on topics it subscribed to
+#[derive(Clone)]
+pub struct DemuxQueue<Q> {
+    demux: Demux<Q>,
+    client: usize,
+    sender: mpsc::UnboundedSender<ReceivedMessage>,
+    receiver: Arc<Mutex<mpsc::UnboundedReceiver<ReceivedMessage>>>,
+}
+
+#[async_trait::async_trait]
+impl<Q> Queue for DemuxQueue<Q>
+where
+    Q: Queue + Send + Sync,
+{
+    async fn publish(&self, topic: String, message: Message) -> Result<(), Error> {
+        self.demux.queue.publish(topic, message).await
+    }
+
+    async fn subscribe(&mut self, topic: String) -> Result<(), Error> {
+        self.demux
+            .routes
+            .lock()
+            .expect("Poisoned mutex")
+            .push(Route {
+                filter: topic.clone(),
+                client: self.client,
+                sender: self.sender.clone(),
+            });
+
+        self.demux.queue.subscribe(topic).await
+    }
+
+    async fn unsubscribe(&self, topic: String) -> Result<(), Error> {
+        let still_routed = {
+            let mut routes = self.demux.routes.lock().expect("Poisoned mutex");
+            routes.retain(|route| route.client != self.client || route.filter != topic);
+            routes.iter().any(|route| route.filter == topic)
+        };
+
+        // Other clients may still need the topic, e.g. wills shared by rooms
+        if still_routed {
+            return Ok(());
+        }
+        self.demux.queue.unsubscribe(topic).await
+    }
+
+    async fn receive(&mut self) -> Result<ReceivedMessage, Error> {
+        let mut locked_receiver = self.receiver.lock().await;
+        locked_receiver
+            .next()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Demux closed"))
+    }
+
+    fn connection_state(&self) -> ConnectionState {
+        self.demux.queue.connection_state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    use crate::queue::in_memory::InMemoryBroker;
+
+    async fn receive_now<Q: Queue + Send + Sync>(queue: &mut DemuxQueue<Q>) -> Option<String> {
+        tokio::time::timeout(Duration::from_millis(10), queue.receive())
+            .await
+            .ok()
+            .map(|received| received.unwrap().topic)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_route_messages_by_topic() {
+        let broker = InMemoryBroker::new();
+        let mut sut = Demux::new(broker.connect());
+        let mut kitchen = sut.connect();
+        let mut hall = sut.connect();
+        kitchen.subscribe("kitchen/+".to_string()).await.unwrap();
+        kitchen.subscribe("kitchen/#".to_string()).await.unwrap();
+        hall.subscribe("hall/+".to_string()).await.unwrap();
+
+        let publisher = broker.connect();
+        for topic in ["kitchen/alice", "hall/bob"] {
+            publisher
+                .publish(topic.to_string(), b"data".to_vec())
+                .await
+                .unwrap();
+        }
+        let _ = tokio::time::timeout(Duration::from_millis(10), sut.run()).await;
+
+        assert_eq!(
+            receive_now(&mut kitchen).await.as_deref(),
+            Some("kitchen/alice")
+        );
+        assert_eq!(receive_now(&mut kitchen).await, None);
+        assert_eq!(receive_now(&mut hall).await.as_deref(), Some("hall/bob"));
+        assert_eq!(receive_now(&mut hall).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_keep_topic_subscribed_by_other_clients() {
+        let broker = InMemoryBroker::new();
+        let mut sut = Demux::new(broker.connect());
+        let mut kitchen = sut.connect();
+        let mut hall = sut.connect();
+        kitchen.subscribe("wills/+".to_string()).await.unwrap();
+        hall.subscribe("wills/+".to_string()).await.unwrap();
+
+        kitchen.unsubscribe("wills/+".to_string()).await.unwrap();
+        broker
+            .connect()
+            .publish("wills/alice".to_string(), Vec::new())
+            .await
+            .unwrap();
+        let _ = tokio::time::timeout(Duration::from_millis(10), sut.run()).await;
+
+        assert_eq!(receive_now(&mut kitchen).await, None);
+        assert_eq!(receive_now(&mut hall).await.as_deref(), Some("wills/alice"));
+    }
+
+    #[tokio::test]
+    async fn should_publish_through_shared_queue() {
+        let broker = InMemoryBroker::new();
+        let mut subscriber = broker.connect();
+        subscriber.subscribe("room/+".to_string()).await.unwrap();
+        let sut = Demux::new(broker.connect());
+
+        sut.connect()
+            .publish("room/user".to_string(), b"data".to_vec())
+            .await
+            .unwrap();
+
+        let received = subscriber.receive().await.unwrap();
+        assert_eq!(received.topic, "room/user");
+        assert_eq!(received.payload, b"data".to_vec());
+    }
+}
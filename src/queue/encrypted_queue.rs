@@ -1,10 +1,21 @@
-use super::{Error, Message, Queue};
-use crate::crypto::{Decrypt, Encrypt};
+use anyhow::Context;
+
+use super::{topic, ConnectionState, Error, Message, Queue, ReceivedMessage, UndecryptableMessage};
+use crate::crypto::{keyring::Keyring, sender_key::SenderKeys, wire, Decrypt, Encrypt};
+
+/// Topics `<...>/keys/<user>` carry room keys sealed for a single user. They
+/// stay encrypted with the password key, the only one newcomers know.
+pub const KEY_TOPIC_SEGMENT: &str = "keys";
 
 #[derive(Clone)]
 pub struct EncryptedQueue<Q, C> {
     queue: Q,
+    /// Password key, see [`Keyring`] for keys replacing it
     crypto: C,
+    keyring: Option<Keyring>,
+    sender_keys: Option<SenderKeys>,
+    /// Filter of topics received as they are, see [`EncryptedQueue::with_plain_topic`]
+    plain_topic: Option<String>,
 }
 
 impl<Q, C> EncryptedQueue<Q, C>
@@ -13,8 +24,79 @@ where
     C: Encrypt + Decrypt,
 {
     pub fn new(queue: Q, crypto: C) -> Self {
-        Self { queue, crypto }
+        Self {
+            queue,
+            crypto,
+            keyring: None,
+            sender_keys: None,
+            plain_topic: None,
+        }
+    }
+
+    /// Receives messages on topics matching `filter` without decrypting them,
+    /// e.g. last wills set once for all rooms of a connection
+    pub fn with_plain_topic(mut self, filter: String) -> Self {
+        self.plain_topic = Some(filter);
+        self
+    }
+
+    /// Encrypts with keys rotated into `keyring` once there are any. Messages
+    /// encrypted with the password key are read only until it is retired.
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Encrypts end-to-end with `sender_keys` of this and other members
+    /// instead. Only key topics are left to the password key, which is
+    /// refused anywhere else.
+    pub fn with_sender_keys(mut self, sender_keys: SenderKeys) -> Self {
+        self.sender_keys = Some(sender_keys);
+        self
     }
+
+    fn encrypt(&self, topic: &str, message: Message) -> Message {
+        if let Some(sender_keys) = self.sender_keys.as_ref().filter(|_| !is_key_topic(topic)) {
+            return sender_keys.encrypt(message);
+        }
+
+        match &self.keyring {
+            Some(keyring) if !is_key_topic(topic) && keyring.current_epoch() > 0 => keyring
+                .encrypt(message)
+                .expect("Rotated keyring has current key"),
+            _ => self.crypto.encrypt(message),
+        }
+    }
+
+    fn decrypt(&self, topic: &str, message: Message) -> Result<Message, Error> {
+        if let Some(sender_keys) = &self.sender_keys {
+            if wire::version(&message) == Some(wire::VERSION_SENDER_KEY_CHACHA20_POLY1305) {
+                return sender_keys.decrypt(message);
+            }
+            if !is_key_topic(topic) {
+                anyhow::bail!("Not encrypted end-to-end in end-to-end encrypted room");
+            }
+        }
+
+        match &self.keyring {
+            Some(keyring)
+                if wire::version(&message) == Some(wire::VERSION_EPOCH_CHACHA20_POLY1305) =>
+            {
+                keyring.decrypt(message)
+            }
+            Some(keyring) if !is_key_topic(topic) && !keyring.reads_password_key() => {
+                anyhow::bail!(
+                    "Encrypted with the password key, replaced by epoch {}",
+                    keyring.current_epoch()
+                )
+            }
+            _ => self.crypto.decrypt(message),
+        }
+    }
+}
+
+pub fn is_key_topic(topic: &str) -> bool {
+    topic.rsplit('/').nth(1) == Some(KEY_TOPIC_SEGMENT)
 }
 
 #[async_trait::async_trait]
@@ -24,7 +106,7 @@ where
     C: Encrypt + Decrypt + Send + Sync,
 {
     async fn publish(&self, topic: String, message: Message) -> Result<(), Error> {
-        let encrypted_msg = self.crypto.encrypt(message);
+        let encrypted_msg = self.encrypt(&topic, message);
         self.queue.publish(topic, encrypted_msg).await
     }
 
@@ -32,9 +114,29 @@ where
         self.queue.subscribe(topic).await
     }
 
-    async fn receive(&mut self) -> Result<Message, Error> {
-        let encrypted_msg = self.queue.receive().await?;
-        self.crypto.decrypt(encrypted_msg)
+    async fn unsubscribe(&self, topic: String) -> Result<(), Error> {
+        self.queue.unsubscribe(topic).await
+    }
+
+    async fn receive(&mut self) -> Result<ReceivedMessage, Error> {
+        let mut encrypted = self.queue.receive().await?;
+        if matches!(&self.plain_topic, Some(filter) if topic::matches(filter, &encrypted.topic)) {
+            return Ok(encrypted);
+        }
+        let payload = self
+            .decrypt(&encrypted.topic, std::mem::take(&mut encrypted.payload))
+            .with_context(|| UndecryptableMessage {
+                topic: Some(encrypted.topic.clone()),
+            })?;
+
+        Ok(ReceivedMessage {
+            payload,
+            ..encrypted
+        })
+    }
+
+    fn connection_state(&self) -> ConnectionState {
+        self.queue.connection_state()
     }
 }
 
@@ -42,7 +144,9 @@ where
 mod tests {
     use super::*;
 
-    use crate::crypto::MockCrypto;
+    use test_case::test_case;
+
+    use crate::crypto::{chacha::ChaChaCrypt, room_key::RoomKey, MockCrypto};
     use crate::queue::MockQueue;
 
     #[tokio::test]
@@ -70,16 +174,219 @@ mod tests {
         let mut crypto_mock = MockCrypto::new();
         crypto_mock.expect_decrypt().times(1).returning(Ok);
 
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_receive().times(1).returning(|| {
+            Ok(ReceivedMessage {
+                retained: true,
+                ..ReceivedMessage::new("test_topic".to_string(), b"test_data".to_vec())
+            })
+        });
+
+        let mut sut = EncryptedQueue::new(queue_mock, crypto_mock);
+
+        let result = sut.receive().await.unwrap();
+
+        assert_eq!(
+            result,
+            ReceivedMessage {
+                retained: true,
+                ..ReceivedMessage::new("test_topic".to_string(), b"test_data".to_vec())
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn should_receive_plain_topic_as_it_is() {
+        let mut crypto_mock = MockCrypto::new();
+        crypto_mock.expect_decrypt::<Vec<u8>>().never();
+
         let mut queue_mock = MockQueue::new();
         queue_mock
             .expect_receive()
             .times(1)
-            .returning(|| Ok("test_data".as_bytes().to_owned()));
+            .returning(|| Ok(ReceivedMessage::new("wills/token".into(), Vec::new())));
+
+        let mut sut =
+            EncryptedQueue::new(queue_mock, crypto_mock).with_plain_topic("wills/+".into());
+
+        let result = sut.receive().await.unwrap();
+
+        assert_eq!(result.topic, "wills/token");
+        assert!(result.payload.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_mark_undecryptable_message() {
+        let mut crypto_mock = MockCrypto::new();
+        crypto_mock
+            .expect_decrypt()
+            .returning(|_: Vec<u8>| Err(anyhow::anyhow!("wrong key")));
+
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_receive().returning(|| {
+            Ok(ReceivedMessage::new(
+                "test_topic".to_string(),
+                b"test_data".to_vec(),
+            ))
+        });
 
         let mut sut = EncryptedQueue::new(queue_mock, crypto_mock);
 
+        let result = sut.receive().await.unwrap_err();
+
+        assert!(result.is::<UndecryptableMessage>());
+    }
+
+    fn password_crypto() -> ChaChaCrypt {
+        ChaChaCrypt::new(&RoomKey::from_bytes([0; 32]))
+    }
+
+    fn rotated_keyring() -> Keyring {
+        let keyring = Keyring::new().with_grace(std::time::Duration::ZERO);
+        keyring.rotate(1, RoomKey::from_bytes([1; 32])).unwrap();
+        keyring
+    }
+
+    #[test_case("room/alice", Some(wire::VERSION_EPOCH_CHACHA20_POLY1305) ; "room topic")]
+    #[test_case("room/keys/alice", Some(wire::VERSION_CHACHA20_POLY1305) ; "key topic")]
+    #[tokio::test]
+    async fn should_encrypt_with_rotated_key_but_on_key_topics(topic: &str, version: Option<u8>) {
+        let mut queue_mock = MockQueue::new();
+        queue_mock
+            .expect_publish()
+            .withf(move |_, msg| wire::version(msg) == version)
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let sut =
+            EncryptedQueue::new(queue_mock, password_crypto()).with_keyring(rotated_keyring());
+
+        let result = sut.publish(topic.to_string(), b"data".to_vec()).await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn should_refuse_password_key_once_retired() {
+        let encrypted = password_crypto().encrypt(b"data");
+        let mut queue_mock = MockQueue::new();
+        queue_mock
+            .expect_receive()
+            .returning(move || Ok(ReceivedMessage::new("room/alice".into(), encrypted.clone())));
+
+        let mut sut =
+            EncryptedQueue::new(queue_mock, password_crypto()).with_keyring(rotated_keyring());
+
+        let result = sut.receive().await.unwrap_err();
+
+        assert!(result.is::<UndecryptableMessage>());
+    }
+
+    #[tokio::test]
+    async fn should_read_key_topics_with_password_key() {
+        let encrypted = password_crypto().encrypt(b"data");
+        let mut queue_mock = MockQueue::new();
+        queue_mock.expect_receive().returning(move || {
+            Ok(ReceivedMessage::new(
+                "room/keys/alice".into(),
+                encrypted.clone(),
+            ))
+        });
+
+        let mut sut =
+            EncryptedQueue::new(queue_mock, password_crypto()).with_keyring(rotated_keyring());
+
         let result = sut.receive().await.unwrap();
 
-        assert_eq!(result, "test_data".as_bytes().to_owned());
+        assert_eq!(result.payload, b"data");
+    }
+
+    #[tokio::test]
+    async fn should_read_rotated_key() {
+        let keyring = rotated_keyring();
+        let encrypted = keyring.encrypt(b"data").unwrap();
+        let mut queue_mock = MockQueue::new();
+        queue_mock
+            .expect_receive()
+            .returning(move || Ok(ReceivedMessage::new("room/alice".into(), encrypted.clone())));
+
+        let mut sut = EncryptedQueue::new(queue_mock, password_crypto()).with_keyring(keyring);
+
+        let result = sut.receive().await.unwrap();
+
+        assert_eq!(result.payload, b"data");
+    }
+
+    #[tokio::test]
+    async fn should_encrypt_end_to_end_but_on_key_topics() {
+        let mut queue_mock = MockQueue::new();
+        queue_mock
+            .expect_publish()
+            .withf(|topic, msg| {
+                let end_to_end =
+                    wire::version(msg) == Some(wire::VERSION_SENDER_KEY_CHACHA20_POLY1305);
+                end_to_end != is_key_topic(topic)
+            })
+            .times(2)
+            .returning(|_, _| Ok(()));
+
+        let sut =
+            EncryptedQueue::new(queue_mock, password_crypto()).with_sender_keys(SenderKeys::new());
+
+        sut.publish("room/alice".into(), b"data".to_vec())
+            .await
+            .unwrap();
+        sut.publish("room/keys/alice".into(), b"data".to_vec())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_refuse_password_key_in_end_to_end_room() {
+        let encrypted = password_crypto().encrypt(b"data");
+        let mut queue_mock = MockQueue::new();
+        queue_mock
+            .expect_receive()
+            .returning(move || Ok(ReceivedMessage::new("room/alice".into(), encrypted.clone())));
+
+        let mut sut =
+            EncryptedQueue::new(queue_mock, password_crypto()).with_sender_keys(SenderKeys::new());
+
+        let result = sut.receive().await.unwrap_err();
+
+        assert!(result.is::<UndecryptableMessage>());
+    }
+
+    #[tokio::test]
+    async fn should_read_end_to_end_message_of_known_sender() {
+        let alice = SenderKeys::new();
+        let bob = SenderKeys::new();
+        bob.add("alice", alice.own_chain());
+        let encrypted = alice.encrypt(b"data");
+        let mut queue_mock = MockQueue::new();
+        queue_mock
+            .expect_receive()
+            .returning(move || Ok(ReceivedMessage::new("room/alice".into(), encrypted.clone())));
+
+        let mut sut = EncryptedQueue::new(queue_mock, password_crypto()).with_sender_keys(bob);
+
+        let result = sut.receive().await.unwrap();
+
+        assert_eq!(result.payload, b"data");
+    }
+
+    #[test]
+    fn should_forward_connection_state() {
+        let crypto_mock = MockCrypto::new();
+
+        let mut queue_mock = MockQueue::new();
+        queue_mock
+            .expect_connection_state()
+            .times(1)
+            .return_const(ConnectionState::Reconnecting);
+
+        let sut = EncryptedQueue::new(queue_mock, crypto_mock);
+
+        assert_eq!(sut.connection_state(), ConnectionState::Reconnecting);
     }
 }
@@ -0,0 +1,263 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use futures::{channel::mpsc, lock::Mutex, StreamExt};
+
+use super::{topic, Error, LastWill, Message, Queue, ReceivedMessage};
+
+struct Subscription {
+    filter: String,
+    session: usize,
+    sender: mpsc::UnboundedSender<ReceivedMessage>,
+}
+
+/// In-process message broker. Every [`InMemoryQueue`] obtained from
+/// [`InMemoryBroker::connect`] behaves like a separate broker client.
+#[derive(Clone, Default)]
+pub struct InMemoryBroker {
+    subscriptions: Arc<std::sync::Mutex<Vec<Subscription>>>,
+    next_session: Arc<AtomicUsize>,
+}
+
+impl InMemoryBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connect(&self) -> InMemoryQueue {
+        let (sender, receiver) = mpsc::unbounded();
+
+        InMemoryQueue {
+            broker: self.clone(),
+            session: self.next_session.fetch_add(1, Ordering::Relaxed),
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+            will: None,
+        }
+    }
+
+    /// Connects a client whose `will` is published by [`InMemoryQueue::lose_connection`]
+    pub fn connect_with_will(&self, will: LastWill) -> InMemoryQueue {
+        InMemoryQueue {
+            will: Some(will),
+            ..self.connect()
+        }
+    }
+
+    fn route(&self, topic: &str, message: &[u8]) {
+        let mut subscriptions = self.subscriptions.lock().expect("Poisoned mutex");
+        subscriptions.retain(|subscription| !subscription.sender.is_closed());
+
+        let mut delivered_to = Vec::new();
+        for subscription in subscriptions.iter() {
+            if delivered_to.contains(&subscription.session)
+                || !topic::matches(&subscription.filter, topic)
+            {
+                continue;
+            }
+
+            delivered_to.push(subscription.session);
+            let _ = subscription
+                .sender
+                .unbounded_send(ReceivedMessage::new(topic.to_owned(), message.to_owned()));
+        }
+    }
+}
+
+/// Client of an [`InMemoryBroker`]. Like [`super::mqtt::MqttQueue`], clones
+/// share one session, so they see the same subscriptions and incoming messages.
+#[derive(Clone)]
+pub struct InMemoryQueue {
+    broker: InMemoryBroker,
+    session: usize,
+    sender: mpsc::UnboundedSender<ReceivedMessage>,
+    receiver: Arc<Mutex<mpsc::UnboundedReceiver<ReceivedMessage>>>,
+    will: Option<LastWill>,
+}
+
+impl InMemoryQueue {
+    /// Publishes the will, like a broker noticing the client is gone
+    pub fn lose_connection(&self) {
+        if let Some(will) = &self.will {
+            self.broker.route(&will.topic, &will.message);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Queue for InMemoryQueue {
+    async fn publish(&self, topic: String, message: Message) -> Result<(), Error> {
+        self.broker.route(&topic, &message);
+
+        Ok(())
+    }
+
+    async fn subscribe(&mut self, topic: String) -> Result<(), Error> {
+        let mut subscriptions = self.broker.subscriptions.lock().expect("Poisoned mutex");
+        subscriptions.push(Subscription {
+            filter: topic,
+            session: self.session,
+            sender: self.sender.clone(),
+        });
+
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, topic: String) -> Result<(), Error> {
+        let mut subscriptions = self.broker.subscriptions.lock().expect("Poisoned mutex");
+        subscriptions.retain(|subscription| {
+            subscription.session != self.session || subscription.filter != topic
+        });
+
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<ReceivedMessage, Error> {
+        let mut locked_receiver = self.receiver.lock().await;
+        locked_receiver
+            .next()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Broker closed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    use crate::{
+        chat_room::{queue_chat_room::QueueChatRoom, ChatRoom},
+        crypto::magic_crypt::MagicCrypt,
+        queue::encrypted_queue::EncryptedQueue,
+    };
+
+    async fn receive_now(queue: &mut InMemoryQueue) -> Option<Message> {
+        tokio::time::timeout(Duration::from_millis(10), queue.receive())
+            .await
+            .ok()
+            .map(|received| received.unwrap().payload)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_deliver_to_matching_subscribers() {
+        let broker = InMemoryBroker::new();
+        let publisher = broker.connect();
+        let mut first = broker.connect();
+        let mut second = broker.connect();
+        first.subscribe("room/+".to_string()).await.unwrap();
+        second.subscribe("room/#".to_string()).await.unwrap();
+
+        publisher
+            .publish("room/user".to_string(), b"data".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(receive_now(&mut first).await, Some(b"data".to_vec()));
+        assert_eq!(receive_now(&mut second).await, Some(b"data".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn should_tell_topic_of_received_message() {
+        let broker = InMemoryBroker::new();
+        let mut client = broker.connect();
+        client.subscribe("room/+".to_string()).await.unwrap();
+
+        client
+            .publish("room/user".to_string(), b"data".to_vec())
+            .await
+            .unwrap();
+
+        let received = client.receive().await.unwrap();
+        assert_eq!(received.topic, "room/user");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_not_deliver_to_not_matching_subscribers() {
+        let broker = InMemoryBroker::new();
+        let publisher = broker.connect();
+        let mut subscriber = broker.connect();
+        subscriber.subscribe("other/#".to_string()).await.unwrap();
+
+        publisher
+            .publish("room/user".to_string(), b"data".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(receive_now(&mut subscriber).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_deliver_once_for_overlapping_subscriptions() {
+        let broker = InMemoryBroker::new();
+        let mut client = broker.connect();
+        client.subscribe("room/+".to_string()).await.unwrap();
+        client.subscribe("room/#".to_string()).await.unwrap();
+
+        client
+            .publish("room/user".to_string(), b"data".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(receive_now(&mut client).await, Some(b"data".to_vec()));
+        assert_eq!(receive_now(&mut client).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_publish_will_when_connection_is_lost() {
+        let broker = InMemoryBroker::new();
+        let mut subscriber = broker.connect();
+        subscriber.subscribe("wills/+".to_string()).await.unwrap();
+        let sut = broker.connect_with_will(LastWill {
+            topic: "wills/user".to_string(),
+            message: b"bye".to_vec(),
+        });
+
+        sut.lose_connection();
+
+        assert_eq!(receive_now(&mut subscriber).await, Some(b"bye".to_vec()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_share_session_between_clones() {
+        let broker = InMemoryBroker::new();
+        let mut client = broker.connect();
+        let mut clone = client.clone();
+        client.subscribe("room/#".to_string()).await.unwrap();
+
+        broker
+            .connect()
+            .publish("room/user".to_string(), b"data".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(receive_now(&mut clone).await, Some(b"data".to_vec()));
+        assert_eq!(receive_now(&mut client).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_deliver_chat_messages_between_users() {
+        let broker = InMemoryBroker::new();
+        let crypto = MagicCrypt::new(&"password");
+
+        let alice = EncryptedQueue::new(broker.connect(), crypto.clone());
+        let alice = QueueChatRoom::new(alice, "alice".to_string(), "room".to_string())
+            .await
+            .unwrap();
+        let bob = EncryptedQueue::new(broker.connect(), crypto);
+        let mut bob = QueueChatRoom::new(bob, "bob".to_string(), "room".to_string())
+            .await
+            .unwrap();
+
+        alice.send("hello bob".to_string()).await.unwrap();
+        let _ = tokio::time::timeout(Duration::from_millis(10), bob.run()).await;
+
+        let messages = bob.get_messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].user, "alice");
+        assert_eq!(messages[0].msg, "hello bob");
+    }
+}
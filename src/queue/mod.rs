@@ -1,6 +1,59 @@
 type Message = Vec<u8>;
 type Error = anyhow::Error;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Reconnecting,
+}
+
+/// Error of a single received message that could not be decrypted, e.g. one
+/// encrypted with another password. The queue can still receive further ones.
+#[derive(Debug, Default)]
+pub struct UndecryptableMessage {
+    /// Topic the message was published on, if known
+    pub topic: Option<String>,
+}
+
+impl std::fmt::Display for UndecryptableMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Could not decrypt message")
+    }
+}
+
+impl std::error::Error for UndecryptableMessage {}
+
+/// Message the broker publishes on behalf of a client that disconnects
+/// without saying goodbye
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LastWill {
+    pub topic: String,
+    pub message: Message,
+}
+
+/// Message delivered by a [`Queue`] together with what the broker told about it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReceivedMessage {
+    /// Topic the message was published on
+    pub topic: String,
+    pub payload: Message,
+    /// Broker kept the message and delivered it on subscribe, it may be old
+    pub retained: bool,
+    pub qos: i32,
+    /// MQTT v5 user properties, empty for older protocol versions
+    pub properties: Vec<(String, String)>,
+}
+
+impl ReceivedMessage {
+    pub fn new(topic: String, payload: Message) -> Self {
+        Self {
+            topic,
+            payload,
+            ..Default::default()
+        }
+    }
+}
+
 #[cfg_attr(test, mockall::automock)]
 #[async_trait::async_trait]
 pub trait Queue {
@@ -8,8 +61,20 @@ pub trait Queue {
 
     async fn subscribe(&mut self, topic: String) -> Result<(), Error>;
 
-    async fn receive(&mut self) -> Result<Message, Error>;
+    /// Stops receiving messages on `topic`, given as it was subscribed
+    async fn unsubscribe(&self, topic: String) -> Result<(), Error>;
+
+    /// Returns next message together with the topic it was published on,
+    /// see [`ReceivedMessage`]
+    async fn receive(&mut self) -> Result<ReceivedMessage, Error>;
+
+    fn connection_state(&self) -> ConnectionState {
+        ConnectionState::Connected
+    }
 }
 
+pub mod demux;
 pub mod encrypted_queue;
+pub mod in_memory;
 pub mod mqtt;
+pub mod topic;
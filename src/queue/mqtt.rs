@@ -1,29 +1,133 @@
-use std::sync::Arc;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 use futures::{channel::mpsc, lock::Mutex, StreamExt};
 
-use super::{Error, Message, Queue};
+use super::{ConnectionState, Error, LastWill, Message, Queue, ReceivedMessage};
+
+/// Max number of messages kept while disconnected, the oldest are dropped first
+const MAX_PENDING: usize = 1000;
+
+/// Exponential backoff used between reconnection attempts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: u32,
+}
+
+impl ReconnectPolicy {
+    /// Delay to wait before reconnection attempt number `attempt` (counted from 0)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.checked_pow(attempt).unwrap_or(u32::MAX);
+
+        self.initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct MqttQueue {
     client: paho_mqtt::AsyncClient,
     receiver: Arc<Mutex<mpsc::Receiver<Option<paho_mqtt::Message>>>>,
+    reconnect_policy: ReconnectPolicy,
+    subscriptions: Arc<RwLock<Vec<String>>>,
+    pending: Arc<std::sync::Mutex<VecDeque<paho_mqtt::Message>>>,
+    state: Arc<RwLock<ConnectionState>>,
 }
 
 impl MqttQueue {
-    pub async fn new(url: String) -> Result<Self, Error> {
+    /// Connects to the broker at `url`, which publishes `will` if the
+    /// connection is lost. The will is kept across reconnects.
+    pub async fn new(
+        url: String,
+        reconnect_policy: ReconnectPolicy,
+        will: Option<LastWill>,
+    ) -> Result<Self, Error> {
         let opts = paho_mqtt::CreateOptionsBuilder::new()
             .server_uri(url)
             .finalize();
         let mut client = paho_mqtt::AsyncClient::new(opts)?;
         let receiver = Arc::new(Mutex::new(client.get_stream(1)));
 
-        let conn_opts = paho_mqtt::ConnectOptionsBuilder::new()
-            .keep_alive_interval(std::time::Duration::from_secs(30))
-            .finalize();
-        client.connect(conn_opts).await?;
+        client.connect(connect_options(will)).await?;
+
+        Ok(Self {
+            client,
+            receiver,
+            reconnect_policy,
+            subscriptions: Arc::default(),
+            pending: Arc::default(),
+            state: Arc::new(RwLock::new(ConnectionState::Connected)),
+        })
+    }
+
+    async fn reconnect(&self) {
+        self.set_state(ConnectionState::Reconnecting);
 
-        Ok(Self { client, receiver })
+        let mut attempt = 0;
+        loop {
+            tokio::time::sleep(self.reconnect_policy.delay(attempt)).await;
+
+            if self.client.reconnect().await.is_ok() && self.resubscribe().await.is_ok() {
+                break;
+            }
+            attempt = attempt.saturating_add(1);
+        }
+
+        // Publishing goes straight to the client from now on, so nothing
+        // sent during the flush is left behind until the next reconnect
+        self.set_state(ConnectionState::Connected);
+        self.flush_pending().await;
+    }
+
+    async fn resubscribe(&self) -> Result<(), Error> {
+        let topics = self.subscriptions.read().expect("Poisoned mutex").clone();
+        if !topics.is_empty() {
+            let qos = vec![0; topics.len()];
+            self.client.subscribe_many(&topics, &qos).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn flush_pending(&self) {
+        let pending = self
+            .pending
+            .lock()
+            .expect("Poisoned mutex")
+            .drain(..)
+            .collect::<Vec<_>>();
+
+        for (i, mqtt_msg) in pending.iter().enumerate() {
+            if self.client.publish(mqtt_msg.clone()).await.is_err() {
+                self.buffer(pending[i..].to_vec());
+                break;
+            }
+        }
+    }
+
+    fn buffer(&self, messages: Vec<paho_mqtt::Message>) {
+        let mut pending = self.pending.lock().expect("Poisoned mutex");
+        keep_newest(&mut pending, messages, MAX_PENDING);
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        *self.state.write().expect("Poisoned mutex") = state;
     }
 }
 
@@ -31,25 +135,119 @@ impl MqttQueue {
 impl Queue for MqttQueue {
     async fn publish(&self, topic: String, message: Message) -> Result<(), Error> {
         let mqtt_msg = paho_mqtt::Message::new(topic, message, 0);
-        self.client.publish(mqtt_msg).await?;
+
+        if self.connection_state() != ConnectionState::Connected || !self.client.is_connected() {
+            self.buffer(vec![mqtt_msg]);
+            return Ok(());
+        }
+
+        if let Err(e) = self.client.publish(mqtt_msg.clone()).await {
+            if self.client.is_connected() {
+                return Err(e.into());
+            }
+            self.buffer(vec![mqtt_msg]);
+        }
 
         Ok(())
     }
 
     async fn subscribe(&mut self, topic: String) -> Result<(), Error> {
-        self.client.subscribe(&topic, 0).await?;
+        self.subscriptions
+            .write()
+            .expect("Poisoned mutex")
+            .push(topic.clone());
+
+        // While disconnected, topic will be subscribed on reconnect
+        if self.client.is_connected() {
+            self.client.subscribe(&topic, 0).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, topic: String) -> Result<(), Error> {
+        self.subscriptions
+            .write()
+            .expect("Poisoned mutex")
+            .retain(|subscribed| *subscribed != topic);
+
+        // While disconnected, topic is simply not subscribed on reconnect
+        if self.client.is_connected() {
+            self.client.unsubscribe(&topic).await?;
+        }
 
         Ok(())
     }
 
-    async fn receive(&mut self) -> Result<Message, Error> {
+    async fn receive(&mut self) -> Result<ReceivedMessage, Error> {
         let mut locked_receiver = self.receiver.lock().await;
-        let msg = locked_receiver
-            .next()
-            .await
-            .ok_or_else(|| anyhow::anyhow!("Error"))?
-            .ok_or_else(|| anyhow::anyhow!("Error"))?;
 
-        Ok(msg.payload().to_owned())
+        loop {
+            let msg = locked_receiver
+                .next()
+                .await
+                .ok_or_else(|| anyhow::anyhow!("Mqtt stream closed"))?;
+
+            match msg {
+                Some(msg) => {
+                    return Ok(ReceivedMessage {
+                        topic: msg.topic().to_owned(),
+                        payload: msg.payload().to_owned(),
+                        retained: msg.retained(),
+                        qos: msg.qos(),
+                        properties: msg.properties().user_iter().collect(),
+                    })
+                }
+                None => self.reconnect().await,
+            }
+        }
+    }
+
+    fn connection_state(&self) -> ConnectionState {
+        *self.state.read().expect("Poisoned mutex")
+    }
+}
+
+fn keep_newest<T>(pending: &mut VecDeque<T>, messages: Vec<T>, max: usize) {
+    pending.extend(messages);
+    while pending.len() > max {
+        pending.pop_front();
+    }
+}
+
+fn connect_options(will: Option<LastWill>) -> paho_mqtt::ConnectOptions {
+    let mut builder = paho_mqtt::ConnectOptionsBuilder::new();
+    builder.keep_alive_interval(Duration::from_secs(30));
+    if let Some(will) = will {
+        builder.will_message(paho_mqtt::Message::new(will.topic, will.message, 0));
+    }
+
+    builder.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case(0, 1 ; "first attempt uses initial delay")]
+    #[test_case(1, 2 ; "second attempt is doubled")]
+    #[test_case(3, 8 ; "delay grows exponentially")]
+    #[test_case(6, 60 ; "delay is capped")]
+    #[test_case(100, 60 ; "overflow is capped")]
+    fn should_back_off_exponentially(attempt: u32, expected_secs: u64) {
+        let sut = ReconnectPolicy::default();
+
+        assert_eq!(sut.delay(attempt), Duration::from_secs(expected_secs));
+    }
+
+    #[test]
+    fn should_drop_oldest_pending_messages_past_limit() {
+        let mut pending = VecDeque::from([1, 2]);
+
+        keep_newest(&mut pending, vec![3, 4], 3);
+
+        assert_eq!(pending, [2, 3, 4]);
     }
 }
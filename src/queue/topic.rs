@@ -0,0 +1,40 @@
+/// Checks whether `topic` is matched by MQTT-style topic `filter`.
+///
+/// `+` matches exactly one topic level and `#` (allowed only as the last
+/// level) matches any number of remaining levels, including none.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return filter_levels.next().is_none(),
+            (Some("+"), Some(_)) => {}
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case("room/user", "room/user", true ; "exact match")]
+    #[test_case("room/user", "room/other", false ; "different level")]
+    #[test_case("room/+", "room/user", true ; "single level wildcard")]
+    #[test_case("room/+", "room/user/extra", false ; "single level wildcard is not recursive")]
+    #[test_case("+/user", "room/user", true ; "single level wildcard at start")]
+    #[test_case("room/#", "room/user/extra", true ; "multi level wildcard")]
+    #[test_case("room/#", "room", true ; "multi level wildcard matches parent")]
+    #[test_case("#", "room/user", true ; "multi level wildcard matches everything")]
+    #[test_case("room/#/user", "room/a/user", false ; "multi level wildcard must be last")]
+    #[test_case("room", "room/user", false ; "filter shorter than topic")]
+    #[test_case("room/user", "room", false ; "filter longer than topic")]
+    fn should_match_topic(filter: &str, topic: &str, expected: bool) {
+        assert_eq!(matches(filter, topic), expected);
+    }
+}
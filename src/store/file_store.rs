@@ -0,0 +1,186 @@
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{room_file_name, Error, MessageStore};
+use crate::{
+    chat_room::ChatMessage,
+    crypto::{Decrypt, Encrypt},
+};
+
+/// Append-only file with one encrypted, base64 encoded message per line.
+/// Renames of members are kept the same way in a file of their own.
+pub struct FileStore<C> {
+    file: PathBuf,
+    renames_file: PathBuf,
+    crypto: C,
+}
+
+impl<C> FileStore<C>
+where
+    C: Encrypt + Decrypt,
+{
+    /// Opens store of `room` kept in `dir`
+    pub fn open(dir: &Path, room: &str, crypto: C) -> Result<Self, Error> {
+        std::fs::create_dir_all(dir)?;
+        let file = dir.join(room_file_name(room, "messages"));
+        let renames_file = dir.join(room_file_name(room, "renames"));
+
+        Ok(Self {
+            file,
+            renames_file,
+            crypto,
+        })
+    }
+
+    fn append_line(&self, file: &Path, value: &impl Serialize) -> Result<(), Error> {
+        let value = serde_json::to_vec(value)?;
+        let encrypted = self.crypto.encrypt(value);
+
+        let mut file = OpenOptions::new().create(true).append(true).open(file)?;
+        writeln!(file, "{}", base64::encode(encrypted))?;
+
+        Ok(())
+    }
+
+    /// Value kept in `line`, none for lines written with another room key
+    /// (e.g. changed password)
+    fn decode<T: DeserializeOwned>(&self, line: &str) -> Option<T> {
+        let encrypted = base64::decode(line).ok()?;
+        let value = self.crypto.decrypt(encrypted).ok()?;
+
+        serde_json::from_slice(&value).ok()
+    }
+}
+
+fn read_lines(file: &Path) -> Result<Vec<String>, Error> {
+    if !file.exists() {
+        return Ok(Vec::new());
+    }
+
+    let lines = BufReader::new(std::fs::File::open(file)?)
+        .lines()
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(lines)
+}
+
+impl<C> MessageStore for FileStore<C>
+where
+    C: Encrypt + Decrypt,
+{
+    fn append(&self, message: &ChatMessage) -> Result<(), Error> {
+        self.append_line(&self.file, message)
+    }
+
+    fn load_last(&self, count: usize) -> Result<Vec<ChatMessage>, Error> {
+        let mut messages = read_lines(&self.file)?
+            .iter()
+            .rev()
+            .filter_map(|line| self.decode(line))
+            .take(count)
+            .collect::<Vec<_>>();
+        messages.reverse();
+
+        Ok(messages)
+    }
+
+    fn append_rename(&self, old: &str, new: &str) -> Result<(), Error> {
+        self.append_line(&self.renames_file, &(old, new))
+    }
+
+    fn load_renames(&self) -> Result<Vec<(String, String)>, Error> {
+        Ok(read_lines(&self.renames_file)?
+            .iter()
+            .filter_map(|line| self.decode(line))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        crypto::{chacha::ChaChaCrypt, room_key::RoomKey},
+        store::temp_dir,
+    };
+
+    fn crypto(key: u8) -> ChaChaCrypt {
+        ChaChaCrypt::new(&RoomKey::from_bytes([key; 32]))
+    }
+
+    fn message(msg: &str) -> ChatMessage {
+        ChatMessage::new("user".into(), msg.into(), 0)
+    }
+
+    #[test]
+    fn should_load_newest_messages_in_order() {
+        let dir = temp_dir();
+        let sut = FileStore::open(&dir, "room", crypto(1)).unwrap();
+
+        let messages = vec![message("one"), message("two"), message("three")];
+        for msg in &messages {
+            sut.append(msg).unwrap();
+        }
+
+        assert_eq!(sut.load_last(2).unwrap(), messages[1..]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_load_nothing_from_new_store() {
+        let dir = temp_dir();
+        let sut = FileStore::open(&dir, "room", crypto(1)).unwrap();
+
+        assert!(sut.load_last(10).unwrap().is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_keep_renames_apart_from_messages() {
+        let dir = temp_dir();
+        let sut = FileStore::open(&dir, "room", crypto(1)).unwrap();
+
+        let messages = vec![message("hi")];
+        sut.append(&messages[0]).unwrap();
+        sut.append_rename("alice", "ally").unwrap();
+        sut.append_rename("ally", "al").unwrap();
+
+        assert_eq!(sut.load_last(10).unwrap(), messages);
+        let reopened = FileStore::open(&dir, "room", crypto(1)).unwrap();
+        assert_eq!(
+            reopened.load_renames().unwrap(),
+            vec![
+                ("alice".to_string(), "ally".to_string()),
+                ("ally".to_string(), "al".to_string())
+            ]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_encrypt_messages_at_rest() {
+        let dir = temp_dir();
+        let sut = FileStore::open(&dir, "room", crypto(1)).unwrap();
+
+        sut.append(&message("secret")).unwrap();
+
+        let content = std::fs::read_to_string(dir.join("room.messages")).unwrap();
+        assert!(!content.contains("secret"));
+        assert!(FileStore::open(&dir, "room", crypto(2))
+            .unwrap()
+            .load_last(10)
+            .unwrap()
+            .is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
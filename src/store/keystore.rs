@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use super::Error;
+use crate::{
+    chat_room::Trust,
+    crypto::{
+        dm_key::DmKeyPair,
+        identity::{IdentityKey, PUBLIC_KEY_LEN},
+    },
+};
+
+const IDENTITY_FILE: &str = "identity.key";
+const DM_KEY_FILE: &str = "dm.key";
+const KNOWN_KEYS_FILE: &str = "known_keys.json";
+
+/// Loads identity key of this user kept in `dir`, a new one is made and
+/// saved there on first use
+pub fn load_identity(dir: &Path) -> Result<IdentityKey, Error> {
+    let secret = load_secret(dir, IDENTITY_FILE, || IdentityKey::generate().to_bytes())?;
+
+    Ok(IdentityKey::from_bytes(secret))
+}
+
+/// Loads direct messages key of this user kept in `dir`, a new one is made
+/// and saved there on first use. Others keep the first one they see.
+pub fn load_dm_key(dir: &Path) -> Result<DmKeyPair, Error> {
+    let secret = load_secret(dir, DM_KEY_FILE, || DmKeyPair::generate().to_bytes())?;
+
+    Ok(DmKeyPair::from_bytes(secret))
+}
+
+/// Reads secret key from `name` in `dir`, or saves the `generate`d one
+/// readable only by this user
+fn load_secret(
+    dir: &Path,
+    name: &str,
+    generate: impl FnOnce() -> [u8; 32],
+) -> Result<[u8; 32], Error> {
+    std::fs::create_dir_all(dir)?;
+    let file = dir.join(name);
+
+    if file.exists() {
+        let secret = base64::decode(std::fs::read_to_string(&file)?.trim())?;
+        return secret
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Malformed key in {}", file.display()));
+    }
+
+    let secret = generate();
+    writeln!(create_private(&file)?, "{}", base64::encode(secret))?;
+
+    Ok(secret)
+}
+
+/// Replaces `file` with `contents` readable only by this user. Written aside
+/// and renamed, so a crash can't leave it half saved.
+pub(crate) fn write_private(file: &Path, contents: &[u8]) -> Result<(), Error> {
+    let temp = file.with_extension("tmp");
+    let _ = std::fs::remove_file(&temp);
+    let mut written = create_private(&temp)?;
+    written.write_all(contents)?;
+    written.sync_all()?;
+    std::fs::rename(temp, file)?;
+
+    Ok(())
+}
+
+/// Creates `file` readable only by this user, failing if it exists
+fn create_private(file: &Path) -> Result<std::fs::File, Error> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    Ok(options.open(file)?)
+}
+
+/// Identity keys of other users, trusted on first use. The first key seen
+/// for a user is remembered and later ones are checked against it.
+#[derive(Debug, Default)]
+pub struct KeyDirectory {
+    keys: RwLock<HashMap<String, [u8; PUBLIC_KEY_LEN]>>,
+    file: Option<PathBuf>,
+}
+
+impl KeyDirectory {
+    /// Directory forgetting keys on exit
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads directory kept in `dir`, missing file gives empty directory
+    pub fn load(dir: &Path) -> Result<Self, Error> {
+        std::fs::create_dir_all(dir)?;
+        let file = dir.join(KNOWN_KEYS_FILE);
+
+        let mut keys = HashMap::new();
+        if file.exists() {
+            let encoded: HashMap<String, String> = serde_json::from_slice(&std::fs::read(&file)?)?;
+            for (user, key) in encoded {
+                let key = base64::decode(key)?
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("Malformed key of {}", user))?;
+                keys.insert(user, key);
+            }
+        }
+
+        Ok(Self {
+            keys: RwLock::new(keys),
+            file: Some(file),
+        })
+    }
+
+    /// Key of `user` is remembered
+    pub fn knows(&self, user: &str) -> bool {
+        self.keys.read().expect("Poisoned mutex").contains_key(user)
+    }
+
+    /// Compares `key` with the one remembered for `user`, remembers it if
+    /// `user` is new. A changed key is not remembered.
+    pub fn check(&self, user: &str, key: [u8; PUBLIC_KEY_LEN]) -> Result<Trust, Error> {
+        let mut keys = self.keys.write().expect("Poisoned mutex");
+
+        match keys.get(user) {
+            Some(known) if *known == key => Ok(Trust::Verified),
+            Some(_) => Ok(Trust::KeyChanged),
+            None => {
+                keys.insert(user.to_string(), key);
+                self.save(&keys)?;
+                Ok(Trust::Verified)
+            }
+        }
+    }
+
+    fn save(&self, keys: &HashMap<String, [u8; PUBLIC_KEY_LEN]>) -> Result<(), Error> {
+        if let Some(file) = &self.file {
+            let encoded = keys
+                .iter()
+                .map(|(user, key)| (user, base64::encode(key)))
+                .collect::<HashMap<_, _>>();
+            write_private(file, &serde_json::to_vec_pretty(&encoded)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::store::temp_dir;
+
+    #[test]
+    fn should_trust_first_key_only() {
+        let sut = KeyDirectory::new();
+        let alice = IdentityKey::generate().public_key();
+        let mallory = IdentityKey::generate().public_key();
+
+        assert_eq!(sut.check("alice", alice).unwrap(), Trust::Verified);
+        assert_eq!(sut.check("alice", mallory).unwrap(), Trust::KeyChanged);
+        assert_eq!(sut.check("alice", alice).unwrap(), Trust::Verified);
+    }
+
+    #[test]
+    fn should_keep_keys_on_disk() {
+        let dir = temp_dir();
+        let alice = IdentityKey::generate().public_key();
+        let mallory = IdentityKey::generate().public_key();
+
+        KeyDirectory::load(&dir)
+            .unwrap()
+            .check("alice", alice)
+            .unwrap();
+        let loaded = KeyDirectory::load(&dir).unwrap();
+
+        assert_eq!(loaded.check("alice", mallory).unwrap(), Trust::KeyChanged);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_keep_known_keys_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir();
+        let sut = KeyDirectory::load(&dir).unwrap();
+
+        for user in ["alice", "bob"] {
+            sut.check(user, IdentityKey::generate().public_key())
+                .unwrap();
+        }
+
+        let mode = std::fs::metadata(dir.join(KNOWN_KEYS_FILE))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!dir.join("known_keys.tmp").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_keep_identity_on_disk() {
+        let dir = temp_dir();
+
+        let identity = load_identity(&dir).unwrap();
+        let loaded = load_identity(&dir).unwrap();
+
+        assert_eq!(loaded.public_key(), identity.public_key());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_keep_dm_key_apart_from_identity_on_disk() {
+        let dir = temp_dir();
+
+        let identity = load_identity(&dir).unwrap();
+        let dm_key = load_dm_key(&dir).unwrap();
+        let loaded = load_dm_key(&dir).unwrap();
+
+        assert_eq!(loaded.public_key(), dm_key.public_key());
+        assert_ne!(dm_key.to_bytes(), identity.to_bytes());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
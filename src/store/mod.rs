@@ -0,0 +1,59 @@
+use crate::chat_room::ChatMessage;
+
+type Error = anyhow::Error;
+
+#[cfg_attr(test, mockall::automock)]
+pub trait MessageStore {
+    fn append(&self, message: &ChatMessage) -> Result<(), Error>;
+
+    /// Returns up to `count` newest messages, oldest first
+    fn load_last(&self, count: usize) -> Result<Vec<ChatMessage>, Error>;
+
+    /// Keeps that the person who went by `old` is now known as `new`
+    fn append_rename(&self, old: &str, new: &str) -> Result<(), Error>;
+
+    /// Returns old and new names of all kept renames, oldest first
+    fn load_renames(&self) -> Result<Vec<(String, String)>, Error>;
+}
+
+/// File name for data kept per room, safe to use whatever the room name is.
+/// Other characters than ASCII letters, digits, `-` and `_` are percent
+/// encoded, so no two rooms share a file.
+pub fn room_file_name(room: &str, extension: &str) -> String {
+    let mut name = String::new();
+    for byte in room.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            _ => name.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    format!("{}.{}", name, extension)
+}
+
+pub mod file_store;
+pub mod keystore;
+
+/// Directory of its own for a test to keep files in, not created yet
+#[cfg(test)]
+pub(crate) fn temp_dir() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("rust-mqtt-chat-{}", rand::random::<u64>()))
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case("kitchen", "kitchen.messages" ; "plain name")]
+    #[test_case("a-b", "a-b.messages" ; "dash")]
+    #[test_case("a_b", "a_b.messages" ; "underscore")]
+    #[test_case("a b", "a%20b.messages" ; "space")]
+    #[test_case("../a", "%2E%2E%2Fa.messages" ; "path")]
+    #[test_case("a%20b", "a%2520b.messages" ; "percent")]
+    #[test_case("café", "caf%C3%A9.messages" ; "non ascii")]
+    fn should_name_room_file(room: &str, expected: &str) {
+        assert_eq!(room_file_name(room, "messages"), expected);
+    }
+}
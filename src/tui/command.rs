@@ -0,0 +1,401 @@
+use anyhow::Error;
+
+use crate::chat_room::ChatRoom;
+
+/// Action typed as `/<name> [args]` instead of a message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Help(Option<String>),
+    Msg { to: String, text: String },
+    Me(String),
+    Nick(String),
+    Topic(Option<String>),
+    Join(String),
+    Rekey(Option<String>),
+    Invite(String),
+    Leave,
+    Clear,
+    Quit,
+}
+
+/// Input of the panel, either a message to send or a command
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    Text(String),
+    Command(Command),
+}
+
+/// What the first argument of a command names, for tab completion
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Argument {
+    None,
+    User,
+    Command,
+}
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// Arguments shown in usage, `<required>` or `[optional]`
+    pub args: &'static str,
+    pub help: &'static str,
+    argument: Argument,
+    /// Builds command from trimmed arguments, `None` when they don't fit
+    parse: fn(&str) -> Option<Command>,
+}
+
+impl CommandSpec {
+    pub fn usage(&self) -> String {
+        format!("/{} {}", self.name, self.args)
+            .trim_end()
+            .to_string()
+    }
+}
+
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "help",
+        args: "[command]",
+        help: "Lists commands or explains one of them",
+        argument: Argument::Command,
+        parse: |args| Some(Command::Help(optional(args)?)),
+    },
+    CommandSpec {
+        name: "msg",
+        args: "<user> <text>",
+        help: "Sends a message only <user> can read",
+        argument: Argument::User,
+        parse: |args| {
+            let (to, text) = args.split_once(char::is_whitespace)?;
+            let text = text.trim_start();
+            Some(Command::Msg {
+                to: to.to_string(),
+                text: text.to_string(),
+            })
+            .filter(|_| !text.is_empty())
+        },
+    },
+    CommandSpec {
+        name: "me",
+        args: "<action>",
+        help: "Describes what you are doing, e.g. /me waves",
+        argument: Argument::None,
+        parse: |args| Some(Command::Me(args.to_string())).filter(|_| !args.is_empty()),
+    },
+    CommandSpec {
+        name: "nick",
+        args: "<name>",
+        help: "Changes your user name in the current room",
+        argument: Argument::None,
+        parse: |args| Some(Command::Nick(word(args)?)),
+    },
+    CommandSpec {
+        name: "topic",
+        args: "[topic]",
+        help: "Shows or sets the room topic",
+        argument: Argument::None,
+        parse: |args| {
+            Some(Command::Topic(
+                Some(args.to_string()).filter(|_| !args.is_empty()),
+            ))
+        },
+    },
+    CommandSpec {
+        name: "join",
+        args: "<room>",
+        help: "Joins another room with the first password given on start",
+        argument: Argument::None,
+        parse: |args| Some(Command::Join(word(args)?)),
+    },
+    CommandSpec {
+        name: "rekey",
+        args: "[user]",
+        help: "Changes the room key, leaving <user> out if given (admins only, in end-to-end rooms anyone, for their own messages)",
+        argument: Argument::User,
+        parse: |args| Some(Command::Rekey(optional(args)?)),
+    },
+    CommandSpec {
+        name: "invite",
+        args: "<user>",
+        help: "Sends the changed room key to <user> (admins only)",
+        argument: Argument::User,
+        parse: |args| Some(Command::Invite(word(args)?)),
+    },
+    CommandSpec {
+        name: "leave",
+        args: "",
+        help: "Leaves the current room",
+        argument: Argument::None,
+        parse: |args| Some(Command::Leave).filter(|_| args.is_empty()),
+    },
+    CommandSpec {
+        name: "clear",
+        args: "",
+        help: "Hides messages received so far",
+        argument: Argument::None,
+        parse: |args| Some(Command::Clear).filter(|_| args.is_empty()),
+    },
+    CommandSpec {
+        name: "quit",
+        args: "",
+        help: "Leaves all rooms and exits",
+        argument: Argument::None,
+        parse: |args| Some(Command::Quit).filter(|_| args.is_empty()),
+    },
+];
+
+/// Single word argument
+fn word(args: &str) -> Option<String> {
+    Some(args.to_string()).filter(|args| !args.is_empty() && !args.contains(char::is_whitespace))
+}
+
+/// Single word argument that may be left out
+fn optional(args: &str) -> Option<Option<String>> {
+    if args.is_empty() {
+        Some(None)
+    } else {
+        word(args).map(Some)
+    }
+}
+
+pub fn find(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// Reads typed input, `//` at the start sends a message beginning with `/`.
+/// Errors are meant to be shown to the user as they are.
+pub fn parse(input: &str) -> Result<Input, String> {
+    let line = match input.strip_prefix('/') {
+        Some(line) if !line.starts_with('/') => line,
+        Some(text) => return Ok(Input::Text(text.to_string())),
+        None => return Ok(Input::Text(input.to_string())),
+    };
+
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let spec = find(name).ok_or_else(|| format!("Unknown command /{}, see /help", name))?;
+
+    (spec.parse)(args.trim())
+        .map(Input::Command)
+        .ok_or_else(|| format!("Usage: {}", spec.usage()))
+}
+
+/// Completes command name or its first argument at the end of `input`,
+/// `None` when there is nothing to add
+pub fn complete(input: &str, users: &[String]) -> Option<String> {
+    let line = input.strip_prefix('/')?;
+
+    match line.split_once(' ') {
+        None => {
+            let names = COMMANDS.iter().map(|command| command.name);
+            let (name, unique) = complete_word(line, names)?;
+            Some(format!("/{}{}", name, if unique { " " } else { "" }))
+        }
+        Some((name, arg)) if !arg.contains(' ') => {
+            let candidates = match find(name)?.argument {
+                Argument::None => return None,
+                Argument::User => users.iter().map(String::as_str).collect::<Vec<_>>(),
+                Argument::Command => COMMANDS.iter().map(|command| command.name).collect(),
+            };
+            let (arg, unique) = complete_word(arg, candidates)?;
+            Some(format!(
+                "/{} {}{}",
+                name,
+                arg,
+                if unique { " " } else { "" }
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Longest common prefix of `candidates` starting with `prefix` and whether
+/// only one of them does
+fn complete_word<'a>(
+    prefix: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<(String, bool)> {
+    let mut matching = candidates
+        .into_iter()
+        .filter(|candidate| candidate.starts_with(prefix));
+    let first = matching.next()?;
+
+    let mut common = first;
+    let mut unique = true;
+    for candidate in matching {
+        unique = false;
+        let length = common
+            .char_indices()
+            .zip(candidate.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(common.len().min(candidate.len()), |((i, _), _)| i);
+        common = &common[..length];
+    }
+
+    Some((common.to_string(), unique)).filter(|(common, _)| unique || common.len() > prefix.len())
+}
+
+/// Lines describing `command` or, if not given, all commands
+pub fn help(command: Option<&str>) -> Result<Vec<String>, String> {
+    let describe = |spec: &CommandSpec| format!("{} - {}", spec.usage(), spec.help);
+
+    match command {
+        Some(name) => find(name)
+            .map(|spec| vec![describe(spec)])
+            .ok_or_else(|| format!("Unknown command /{}", name)),
+        None => Ok(COMMANDS.iter().map(describe).collect()),
+    }
+}
+
+/// Carries out commands acting on the chat room. Returns commands the
+/// view has to handle itself, like switching or leaving rooms.
+pub async fn dispatch<C>(command: Command, chat_room: &C) -> Result<Option<Command>, Error>
+where
+    C: ChatRoom,
+{
+    match command {
+        Command::Help(name) => match help(name.as_deref()) {
+            Ok(lines) => lines.into_iter().for_each(|line| chat_room.notice(line)),
+            Err(e) => chat_room.notice(e),
+        },
+        Command::Msg { to, text } => chat_room.send_direct(to, text).await?,
+        Command::Me(action) => chat_room.send(format!("/me {}", action)).await?,
+        Command::Nick(nick) => {
+            if let Err(e) = chat_room.set_nick(nick).await {
+                chat_room.notice(format!("{:#}", e));
+            }
+        }
+        Command::Rekey(revoked) => {
+            if let Err(e) = chat_room.rotate_key(revoked).await {
+                chat_room.notice(format!("{:#}", e));
+            }
+        }
+        Command::Invite(user) => {
+            if let Err(e) = chat_room.invite(user).await {
+                chat_room.notice(format!("{:#}", e));
+            }
+        }
+        Command::Topic(Some(subject)) => chat_room.set_subject(subject).await?,
+        Command::Topic(None) => chat_room.notice(match chat_room.get_subject() {
+            Some(subject) => format!("Topic: {}", subject),
+            None => "No topic is set".into(),
+        }),
+        command => return Ok(Some(command)),
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+    use test_case::test_case;
+
+    use super::*;
+
+    use crate::chat_room::MockChatRoom;
+
+    fn command(command: Command) -> Result<Input, String> {
+        Ok(Input::Command(command))
+    }
+
+    #[test_case("hello", Ok(Input::Text("hello".into())) ; "plain text")]
+    #[test_case("//shrug", Ok(Input::Text("/shrug".into())) ; "escaped slash")]
+    #[test_case("/help", command(Command::Help(None)) ; "no arguments")]
+    #[test_case("/help msg", command(Command::Help(Some("msg".into()))) ; "optional argument")]
+    #[test_case("/msg bob hi there", command(Command::Msg { to: "bob".into(), text: "hi there".into() }) ; "text argument")]
+    #[test_case("/msg  bob\nhi", command(Command::Msg { to: "bob".into(), text: "hi".into() }) ; "any whitespace")]
+    #[test_case("/join  hall ", command(Command::Join("hall".into())) ; "trimmed argument")]
+    #[test_case("/msg bob", Err("Usage: /msg <user> <text>".into()) ; "missing argument")]
+    #[test_case("/quit now", Err("Usage: /quit".into()) ; "unexpected argument")]
+    #[test_case("/nick two words", Err("Usage: /nick <name>".into()) ; "too many words")]
+    #[test_case("/shrug", Err("Unknown command /shrug, see /help".into()) ; "unknown command")]
+    fn should_parse_input(input: &str, expected: Result<Input, String>) {
+        assert_eq!(parse(input), expected);
+    }
+
+    #[test_case("/he", Some("/help ") ; "unique command")]
+    #[test_case("/m", None ; "ambiguous command")]
+    #[test_case("/msg b", Some("/msg bo") ; "common prefix of users")]
+    #[test_case("/msg al", Some("/msg alice ") ; "unique user")]
+    #[test_case("/help cl", Some("/help clear ") ; "command argument")]
+    #[test_case("/msg alice hi", None ; "text argument")]
+    #[test_case("/join b", None ; "argument not completed")]
+    #[test_case("hello", None ; "not a command")]
+    fn should_complete(input: &str, expected: Option<&str>) {
+        let users = vec!["alice".to_string(), "bob".to_string(), "bonnie".to_string()];
+
+        assert_eq!(complete(input, &users).as_deref(), expected);
+    }
+
+    #[test]
+    fn should_describe_every_command() {
+        let lines = help(None).unwrap();
+
+        assert_eq!(lines.len(), COMMANDS.len());
+        assert_eq!(
+            lines[1],
+            "/msg <user> <text> - Sends a message only <user> can read"
+        );
+        assert_eq!(
+            help(Some("quit")).unwrap(),
+            vec!["/quit - Leaves all rooms and exits"]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_send_action_as_text() {
+        let mut chat_room_mock = MockChatRoom::new();
+        chat_room_mock
+            .expect_send()
+            .with(eq("/me waves".to_string()))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let result = dispatch(Command::Me("waves".into()), &chat_room_mock).await;
+
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn should_show_topic() {
+        let mut chat_room_mock = MockChatRoom::new();
+        chat_room_mock
+            .expect_get_subject()
+            .returning(|| Some("pizza".into()));
+        chat_room_mock
+            .expect_notice()
+            .with(eq("Topic: pizza".to_string()))
+            .times(1)
+            .return_const(());
+
+        let result = dispatch(Command::Topic(None), &chat_room_mock).await;
+
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn should_tell_why_key_was_not_changed() {
+        let mut chat_room_mock = MockChatRoom::new();
+        chat_room_mock
+            .expect_rotate_key()
+            .with(eq(Some("eve".to_string())))
+            .returning(|_| Err(anyhow::anyhow!("Only admins can change the room key")));
+        chat_room_mock
+            .expect_notice()
+            .with(eq("Only admins can change the room key".to_string()))
+            .times(1)
+            .return_const(());
+
+        let result = dispatch(Command::Rekey(Some("eve".into())), &chat_room_mock).await;
+
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn should_leave_view_commands_to_view() {
+        let chat_room_mock = MockChatRoom::new();
+
+        let result = dispatch(Command::Join("hall".into()), &chat_room_mock).await;
+
+        assert_eq!(result.unwrap(), Some(Command::Join("hall".into())));
+    }
+}
@@ -7,6 +7,11 @@ use tui::{
     Frame,
 };
 
+use crate::tui::command::COMMANDS;
+
+/// Rows taken by the help, one for keys and one for commands
+pub const HELP_MSG_HEIGHT: u16 = 2;
+
 #[derive(Clone, Default, Debug)]
 pub struct HelpMsg {}
 
@@ -22,10 +27,29 @@ impl HelpMsg {
                 Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
                 Span::raw(" to exit, "),
                 Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to send the message"),
+                Span::raw(" to send the message, "),
+                Span::styled("Alt+Enter", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(" for a new line, "),
+                Span::styled("PgUp/PgDn", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(" to scroll, "),
+                Span::styled("Ctrl+R", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(" to search history, "),
+                Span::styled("Ctrl+N/P", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(" to switch rooms"),
             ]
         };
-        let mut text = Text::from(Spans::from(msg));
+        let commands = COMMANDS
+            .iter()
+            .map(|command| format!("/{}", command.name))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let commands = vec![
+            Span::styled("Commands", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(format!(": {}, ", commands)),
+            Span::styled("Tab", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" to complete"),
+        ];
+        let mut text = Text::from(vec![Spans::from(msg), Spans::from(commands)]);
         text.patch_style(Style::default());
         let help_message = Paragraph::new(text);
         frame.render_widget(help_message, chunk);
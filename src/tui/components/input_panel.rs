@@ -1,11 +1,41 @@
-use crossterm::event::KeyEvent;
-use tui::{backend::Backend, layout::Rect, style, widgets, Frame};
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
 
-use crate::chat_room::ChatRoom;
+use crossterm::event::{KeyEvent, KeyModifiers};
+use tui::{backend::Backend, layout::Rect, style, text::Spans, widgets, Frame};
+
+use crate::{
+    chat_room::ChatRoom,
+    tui::{
+        command::{self, Command, Input},
+        editor::{visible_slice, Editor},
+        history::History,
+    },
+};
+
+const DEFAULT_MAX_ROWS: u16 = 5;
+
+/// Minimal time between typing signals, well below their expiry
+const TYPING_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// State of incremental reverse search started with Ctrl+R
+struct ReverseSearch {
+    query: String,
+    /// History index of the entry matching `query`
+    found: Option<usize>,
+}
 
 pub struct InputPanel<C> {
-    input_message: String,
-    cursor: usize,
+    editor: Editor,
+    history: History,
+    search: Option<ReverseSearch>,
+    /// First displayed line and column, remembered so the view only scrolls when the cursor leaves it
+    scroll: Cell<(usize, usize)>,
+    max_rows: u16,
+    /// When others were last told this user is typing
+    typing_signalled: Option<Instant>,
     chat_room: C,
 }
 
@@ -15,94 +45,290 @@ where
 {
     pub fn new(chat_room: C) -> Self {
         Self {
-            input_message: String::new(),
-            cursor: 0,
+            editor: Editor::new(),
+            history: History::new(),
+            search: None,
+            scroll: Cell::new((0, 0)),
+            max_rows: DEFAULT_MAX_ROWS,
+            typing_signalled: None,
             chat_room,
         }
     }
 
-    pub async fn update(&mut self, event: KeyEvent) {
+    /// Sets how many lines the panel can grow to before it starts scrolling
+    pub fn with_max_rows(mut self, max_rows: u16) -> Self {
+        self.max_rows = max_rows.max(1);
+        self
+    }
+
+    /// Sets previously sent messages to recall with Up/Down and Ctrl+R
+    pub fn with_history(mut self, history: History) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Height needed to show the typed lines, including borders
+    pub fn height(&self) -> u16 {
+        let rows = self.shown_editor().line_count().min(self.max_rows as usize) as u16;
+        rows + 2
+    }
+
+    /// Handles key press, returns command the panel can't carry out itself
+    pub async fn update(&mut self, event: KeyEvent) -> Option<Command> {
+        let text = self.editor.text().to_owned();
+
+        let command = if self.search.is_some() {
+            self.update_search(event);
+            None
+        } else {
+            self.update_editor(event).await
+        };
+
+        if self.editor.text() != text {
+            self.signal_typing().await;
+        }
+
+        command
+    }
+
+    async fn update_editor(&mut self, event: KeyEvent) -> Option<Command> {
         match event.code {
-            crossterm::event::KeyCode::Char(ch) => {
-                let cursor = self.cursor;
-                self.input_message.insert(cursor, ch);
-                self.cursor += 1;
+            crossterm::event::KeyCode::Char('r') if event.modifiers == KeyModifiers::CONTROL => {
+                self.search = Some(ReverseSearch {
+                    query: String::new(),
+                    found: None,
+                });
             }
+            crossterm::event::KeyCode::Char(ch) => self.editor.insert(ch),
 
-            crossterm::event::KeyCode::Enter => {
-                let message = self.input_message.drain(..).collect::<String>();
-                if !message.is_empty() {
-                    self.chat_room.send(message).await.unwrap();
-                    self.cursor = 0;
-                }
+            crossterm::event::KeyCode::Enter
+                if event
+                    .modifiers
+                    .intersects(KeyModifiers::SHIFT | KeyModifiers::ALT) =>
+            {
+                self.editor.insert('\n')
             }
+            crossterm::event::KeyCode::Enter => return self.submit().await,
+            crossterm::event::KeyCode::Tab => self.complete(),
 
-            crossterm::event::KeyCode::Delete => {
-                if self.cursor < self.input_message.len() {
-                    let cursor = self.cursor;
-                    self.input_message.remove(cursor);
-                }
-            }
-            crossterm::event::KeyCode::Backspace => {
-                if self.cursor > 0 {
-                    self.cursor -= 1;
-                    let cursor = self.cursor;
-                    self.input_message.remove(cursor);
-                }
+            crossterm::event::KeyCode::Delete => self.editor.delete(),
+            crossterm::event::KeyCode::Backspace => self.editor.backspace(),
+
+            crossterm::event::KeyCode::Left => self.editor.move_left(),
+            crossterm::event::KeyCode::Right => self.editor.move_right(),
+            crossterm::event::KeyCode::Up => self.move_up(),
+            crossterm::event::KeyCode::Down => self.move_down(),
+
+            crossterm::event::KeyCode::Home => self.editor.move_home(),
+            crossterm::event::KeyCode::End => self.editor.move_end(),
+            _ => (),
+        }
+
+        None
+    }
+
+    /// Sends typed message or carries out typed command
+    async fn submit(&mut self) -> Option<Command> {
+        let input = self.editor.take();
+        if input.is_empty() {
+            return None;
+        }
+        self.history.push(input.clone());
+
+        let result = match command::parse(&input) {
+            Ok(Input::Text(message)) => self.chat_room.send(message).await.map(|_| None),
+            Ok(Input::Command(command)) => command::dispatch(command, &self.chat_room).await,
+            Err(e) => {
+                // Keep it for fixing rather than send a mistyped command publicly
+                self.chat_room.notice(e);
+                self.editor.set_text(input);
+                return None;
             }
+        };
+
+        result.unwrap_or_else(|e| {
+            self.chat_room.notice(format!("{:#}", e));
+            None
+        })
+    }
+
+    /// Completes command or user name typed at the end of the input
+    fn complete(&mut self) {
+        let users = self
+            .chat_room
+            .get_members()
+            .into_iter()
+            .map(|member| member.name)
+            .collect::<Vec<_>>();
+
+        if let Some(completed) = command::complete(self.editor.text(), &users) {
+            self.editor.set_text(completed);
+        }
+    }
+
+    /// Tells others about typing at most once per [`TYPING_DEBOUNCE`], input
+    /// cleared or sent makes the next change signal right away
+    async fn signal_typing(&mut self) {
+        if self.editor.is_empty() {
+            self.typing_signalled = None;
+            return;
+        }
+
+        let now = Instant::now();
+        if matches!(self.typing_signalled, Some(at) if now.duration_since(at) < TYPING_DEBOUNCE) {
+            return;
+        }
+        self.typing_signalled = Some(now);
+
+        // Typing indicator is only a nicety, not worth interrupting the user for
+        let _ = self.chat_room.send_typing().await;
+    }
+
+    /// Moves to the line above or, from the first line of unmodified input, recalls older message
+    fn move_up(&mut self) {
+        if self.editor.move_up() || !self.is_unmodified() {
+            return;
+        }
 
-            crossterm::event::KeyCode::Left => {
-                if self.cursor > 0 {
-                    self.cursor -= 1;
+        if let Some(entry) = self.history.older() {
+            self.editor.set_text(entry.to_owned());
+        }
+    }
+
+    /// Moves to the line below or, from the last line of recalled message, recalls newer one
+    fn move_down(&mut self) {
+        if self.editor.move_down() || self.history.current().is_none() || !self.is_unmodified() {
+            return;
+        }
+
+        let entry = self.history.newer().unwrap_or_default();
+        self.editor.set_text(entry.to_owned());
+    }
+
+    fn update_search(&mut self, event: KeyEvent) {
+        let search = self.search.as_mut().expect("Search in progress");
+
+        match event.code {
+            crossterm::event::KeyCode::Char('r') if event.modifiers == KeyModifiers::CONTROL => {
+                let before = search.found.unwrap_or(self.history.len());
+                if let Some(found) = self.history.search(&search.query, before) {
+                    search.found = Some(found);
                 }
             }
-            crossterm::event::KeyCode::Right => {
-                if self.cursor < self.input_message.len() {
-                    self.cursor += 1;
+            crossterm::event::KeyCode::Char('g') if event.modifiers == KeyModifiers::CONTROL => {
+                self.search = None;
+            }
+            crossterm::event::KeyCode::Char(ch) => {
+                search.query.push(ch);
+                // Current match may still fit the longer query
+                let before = search.found.map_or(self.history.len(), |found| found + 1);
+                search.found = self.history.search(&search.query, before);
+            }
+            crossterm::event::KeyCode::Backspace => {
+                search.query.pop();
+                search.found = self.history.search(&search.query, self.history.len());
+            }
+            _ => {
+                if let Some(entry) = search.found.and_then(|found| self.history.get(found)) {
+                    self.editor.set_text(entry.to_owned());
                 }
+                self.history.reset();
+                self.search = None;
             }
+        }
+    }
 
-            crossterm::event::KeyCode::Home => {
-                self.cursor = 0;
-            }
-            crossterm::event::KeyCode::End => {
-                self.cursor = self.input_message.len();
+    /// Input is empty or still holds the recalled history entry as is
+    fn is_unmodified(&self) -> bool {
+        self.editor.is_empty() || self.history.current() == Some(self.editor.text())
+    }
+
+    /// Editor with the reverse search match while searching, typed input otherwise
+    fn shown_editor(&self) -> Editor {
+        match &self.search {
+            Some(search) => {
+                let mut editor = Editor::new();
+                let found = search.found.and_then(|found| self.history.get(found));
+                editor.set_text(found.unwrap_or_default().to_owned());
+                editor
             }
-            _ => (),
+            None => self.editor.clone(),
         }
     }
 
     pub fn draw(&self, frame: &mut Frame<impl Backend>, chunk: Rect) {
-        let input = widgets::Paragraph::new(self.input_message.as_ref())
+        let width = chunk.width.saturating_sub(2) as usize;
+        let height = chunk.height.saturating_sub(2) as usize;
+        let editor = self.shown_editor();
+        let (row, column) = editor.cursor_position();
+
+        let (scroll_row, scroll_column) = self.scroll.get();
+        let scroll = (
+            scroll_into_view(scroll_row, row, height),
+            scroll_into_view(scroll_column, column, width),
+        );
+        self.scroll.set(scroll);
+
+        let lines = editor
+            .lines()
+            .skip(scroll.0)
+            .take(height)
+            .map(|line| Spans::from(visible_slice(line, scroll.1, width)))
+            .collect::<Vec<_>>();
+
+        let title = match &self.search {
+            Some(search) => format!("Input (reverse-i-search)`{}'", search.query),
+            None => "Input".to_string(),
+        };
+        let input = widgets::Paragraph::new(lines)
             .style(style::Style::default())
             .block(
                 widgets::Block::default()
                     .borders(widgets::Borders::ALL)
-                    .title("Input"),
+                    .title(title),
             );
 
         frame.render_widget(input, chunk);
 
         // Make the cursor visible and ask tui-rs to put it at the specified coordinates after rendering
         frame.set_cursor(
-            // Put cursor past the end of the input text
-            chunk.x + self.cursor as u16 + 1,
-            // Move one line down, from the border to the input line
-            chunk.y + 1,
+            // Skip the border and the part of the line scrolled out of view
+            chunk.x + (column - scroll.1) as u16 + 1,
+            // Move down from the border to the cursor line
+            chunk.y + (row - scroll.0) as u16 + 1,
         );
     }
 }
 
+/// Adjusts `scroll` just enough for `position` to fit in a view of `size`
+fn scroll_into_view(scroll: usize, position: usize, size: usize) -> usize {
+    if position < scroll {
+        position
+    } else if position >= scroll + size {
+        position + 1 - size.max(1)
+    } else {
+        scroll
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use mockall::{predicate::eq, Sequence};
     use test_case::test_case;
 
     use crossterm::event::{KeyCode, KeyModifiers};
+    use tui::{backend::TestBackend, Terminal};
 
     use super::*;
 
-    use crate::chat_room::MockChatRoom;
+    use crate::chat_room::{Member, MemberStatus, MockChatRoom};
+
+    /// Chat room accepting any number of typing signals
+    fn mock_chat_room() -> MockChatRoom {
+        let mut chat_room_mock = MockChatRoom::new();
+        chat_room_mock.expect_send_typing().returning(|| Ok(()));
+        chat_room_mock
+    }
 
     #[test_case(
         vec![
@@ -203,9 +429,54 @@ mod tests {
         ],
         "me"
         ; "arrow keys on empty msg does nothing")]
+    #[test_case(
+        vec![
+            KeyEvent::new(KeyCode::Char('ł'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Char('ó'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Left, KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Backspace, KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+        ],
+        "ó"
+        ; "remove multi byte letter inside msg")]
+    #[test_case(
+        vec![
+            KeyEvent::new(KeyCode::Char('日'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Char('本'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Left, KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Delete, KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Char('🦀'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+        ],
+        "日🦀"
+        ; "edit wide letters")]
+    #[test_case(
+        vec![
+            KeyEvent::new(KeyCode::Char('m'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::SHIFT),
+            KeyEvent::new(KeyCode::Char('e'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::ALT),
+            KeyEvent::new(KeyCode::Char('h'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+        ],
+        "m\ne\nh"
+        ; "insert new lines")]
+    #[test_case(
+        vec![
+            KeyEvent::new(KeyCode::Char('m'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::ALT),
+            KeyEvent::new(KeyCode::Char('e'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Up, KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Char('h'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Down, KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Char('d'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+        ],
+        "mh\ned"
+        ; "travel lines with arrow keys")]
     #[tokio::test]
     async fn should_send_typed_msg(events: Vec<KeyEvent>, expected_msg: &str) {
-        let mut chat_room_mock = MockChatRoom::new();
+        let mut chat_room_mock = mock_chat_room();
         chat_room_mock
             .expect_send()
             .times(1)
@@ -221,7 +492,7 @@ mod tests {
 
     #[tokio::test]
     async fn should_not_send_empty_msg() {
-        let mut chat_room_mock = MockChatRoom::new();
+        let mut chat_room_mock = mock_chat_room();
         chat_room_mock.expect_send().never().returning(|_| Ok(()));
 
         let mut sut = InputPanel::new(chat_room_mock);
@@ -243,7 +514,7 @@ mod tests {
         ];
 
         let mut seq = Sequence::new();
-        let mut chat_room_mock = MockChatRoom::new();
+        let mut chat_room_mock = mock_chat_room();
         chat_room_mock
             .expect_send()
             .times(1)
@@ -263,4 +534,347 @@ mod tests {
             sut.update(event).await;
         }
     }
+
+    fn draw(sut: &InputPanel<MockChatRoom>, width: u16) -> (String, (u16, u16)) {
+        let (lines, cursor) = draw_lines(sut, width, 3);
+        (lines[0].clone(), cursor)
+    }
+
+    /// Returns rendered lines inside the borders and cursor position
+    fn draw_lines(
+        sut: &InputPanel<MockChatRoom>,
+        width: u16,
+        height: u16,
+    ) -> (Vec<String>, (u16, u16)) {
+        let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+        terminal
+            .draw(|frame| sut.draw(frame, frame.size()))
+            .unwrap();
+
+        let buffer = terminal.backend().buffer();
+        let lines = (1..height - 1)
+            .map(|y| {
+                (0..width)
+                    .map(|x| buffer.get(x, y).symbol.clone())
+                    .collect()
+            })
+            .collect();
+        (lines, terminal.get_cursor().unwrap())
+    }
+
+    async fn type_text(sut: &mut InputPanel<MockChatRoom>, text: &str) {
+        for ch in text.chars() {
+            sut.update(KeyEvent::new(KeyCode::Char(ch), KeyModifiers::NONE))
+                .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn should_place_cursor_after_wide_letters() {
+        let mut sut = InputPanel::new(mock_chat_room());
+        type_text(&mut sut, "日本").await;
+
+        let (_, cursor) = draw(&sut, 10);
+
+        assert_eq!(cursor, (5, 1));
+    }
+
+    #[tokio::test]
+    async fn should_scroll_long_input_to_keep_cursor_visible() {
+        let mut sut = InputPanel::new(mock_chat_room());
+        type_text(&mut sut, "abcdefghij").await;
+
+        let (line, cursor) = draw(&sut, 8);
+
+        assert_eq!(line, "│fghij │");
+        assert_eq!(cursor, (6, 1));
+    }
+
+    #[tokio::test]
+    async fn should_scroll_back_when_cursor_moves_left() {
+        let mut sut = InputPanel::new(mock_chat_room());
+        type_text(&mut sut, "abcdefghij").await;
+        draw(&sut, 8);
+
+        sut.update(KeyEvent::new(KeyCode::Home, KeyModifiers::NONE))
+            .await;
+        let (line, cursor) = draw(&sut, 8);
+
+        assert_eq!(line, "│abcdef│");
+        assert_eq!(cursor, (1, 1));
+    }
+
+    #[tokio::test]
+    async fn should_grow_up_to_max_rows() {
+        let mut sut = InputPanel::new(mock_chat_room()).with_max_rows(2);
+        assert_eq!(sut.height(), 3);
+
+        type_text(&mut sut, "a").await;
+        sut.update(KeyEvent::new(KeyCode::Enter, KeyModifiers::ALT))
+            .await;
+        assert_eq!(sut.height(), 4);
+
+        sut.update(KeyEvent::new(KeyCode::Enter, KeyModifiers::ALT))
+            .await;
+        assert_eq!(sut.height(), 4);
+    }
+
+    #[tokio::test]
+    async fn should_scroll_lines_to_keep_cursor_visible() {
+        let mut sut = InputPanel::new(mock_chat_room()).with_max_rows(2);
+        for ch in ['a', 'b', 'c'] {
+            type_text(&mut sut, &ch.to_string()).await;
+            sut.update(KeyEvent::new(KeyCode::Enter, KeyModifiers::ALT))
+                .await;
+        }
+        type_text(&mut sut, "d").await;
+
+        let (lines, cursor) = draw_lines(&sut, 6, 4);
+
+        assert_eq!(lines, vec!["│c   │", "│d   │"]);
+        assert_eq!(cursor, (2, 2));
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn ctrl(ch: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(ch), KeyModifiers::CONTROL)
+    }
+
+    fn chat_room_expecting(messages: &[&str]) -> MockChatRoom {
+        let mut seq = Sequence::new();
+        let mut chat_room_mock = mock_chat_room();
+        for msg in messages {
+            chat_room_mock
+                .expect_send()
+                .times(1)
+                .in_sequence(&mut seq)
+                .with(eq(msg.to_string()))
+                .returning(|_| Ok(()));
+        }
+        chat_room_mock
+    }
+
+    fn history(entries: &[&str]) -> History {
+        let mut history = History::new();
+        entries.iter().for_each(|e| history.push(e.to_string()));
+        history
+    }
+
+    #[tokio::test]
+    async fn should_resend_recalled_message() {
+        let mut sut = InputPanel::new(chat_room_expecting(&["one", "two", "one"]));
+
+        for event in [
+            key(KeyCode::Char('o')),
+            key(KeyCode::Char('n')),
+            key(KeyCode::Char('e')),
+            key(KeyCode::Enter),
+            key(KeyCode::Char('t')),
+            key(KeyCode::Char('w')),
+            key(KeyCode::Char('o')),
+            key(KeyCode::Enter),
+            key(KeyCode::Up),
+            key(KeyCode::Up),
+            key(KeyCode::Enter),
+        ] {
+            sut.update(event).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn should_clear_input_after_newest_recalled_message() {
+        let mut sut =
+            InputPanel::new(chat_room_expecting(&["new"])).with_history(history(&["one", "two"]));
+
+        for event in [
+            key(KeyCode::Up),
+            key(KeyCode::Up),
+            key(KeyCode::Down),
+            key(KeyCode::Down),
+        ] {
+            sut.update(event).await;
+        }
+        type_text(&mut sut, "new").await;
+        sut.update(key(KeyCode::Enter)).await;
+    }
+
+    #[tokio::test]
+    async fn should_not_recall_over_modified_input() {
+        let mut sut =
+            InputPanel::new(chat_room_expecting(&["typed"])).with_history(history(&["one"]));
+
+        type_text(&mut sut, "typed").await;
+        sut.update(key(KeyCode::Up)).await;
+        sut.update(key(KeyCode::Enter)).await;
+    }
+
+    #[tokio::test]
+    async fn should_find_message_with_reverse_search() {
+        let mut sut = InputPanel::new(chat_room_expecting(&["status ok"]))
+            .with_history(history(&["status ok", "hello", "status failed"]));
+
+        sut.update(ctrl('r')).await;
+        type_text(&mut sut, "stat").await;
+        sut.update(ctrl('r')).await;
+        sut.update(key(KeyCode::Enter)).await;
+        sut.update(key(KeyCode::Enter)).await;
+    }
+
+    #[tokio::test]
+    async fn should_show_reverse_search_match() {
+        let mut sut =
+            InputPanel::new(mock_chat_room()).with_history(history(&["status ok", "hello"]));
+
+        sut.update(ctrl('r')).await;
+        type_text(&mut sut, "ok").await;
+        let (line, _) = draw(&sut, 40);
+
+        assert!(line.contains("status ok"));
+    }
+
+    #[tokio::test]
+    async fn should_restore_input_when_search_cancelled() {
+        let mut sut =
+            InputPanel::new(chat_room_expecting(&["typed"])).with_history(history(&["one"]));
+
+        type_text(&mut sut, "typed").await;
+        sut.update(ctrl('r')).await;
+        type_text(&mut sut, "one").await;
+        sut.update(ctrl('g')).await;
+        sut.update(key(KeyCode::Enter)).await;
+    }
+
+    #[tokio::test]
+    async fn should_signal_typing_once_per_debounce() {
+        let mut chat_room_mock = MockChatRoom::new();
+        chat_room_mock
+            .expect_send_typing()
+            .times(1)
+            .returning(|| Ok(()));
+        let mut sut = InputPanel::new(chat_room_mock);
+
+        type_text(&mut sut, "abc").await;
+    }
+
+    #[tokio::test]
+    async fn should_signal_typing_again_after_sending() {
+        let mut chat_room_mock = MockChatRoom::new();
+        chat_room_mock
+            .expect_send_typing()
+            .times(2)
+            .returning(|| Ok(()));
+        chat_room_mock.expect_send().returning(|_| Ok(()));
+        let mut sut = InputPanel::new(chat_room_mock);
+
+        type_text(&mut sut, "a").await;
+        sut.update(key(KeyCode::Enter)).await;
+        type_text(&mut sut, "b").await;
+    }
+
+    #[tokio::test]
+    async fn should_not_signal_typing_without_input() {
+        let mut chat_room_mock = MockChatRoom::new();
+        chat_room_mock.expect_send_typing().never();
+        let mut sut = InputPanel::new(chat_room_mock);
+
+        for event in [
+            key(KeyCode::Left),
+            key(KeyCode::Backspace),
+            key(KeyCode::Up),
+        ] {
+            sut.update(event).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn should_send_direct_message() {
+        let mut chat_room_mock = mock_chat_room();
+        chat_room_mock
+            .expect_send_direct()
+            .with(eq("bob".to_string()), eq("hi there".to_string()))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        chat_room_mock.expect_send().never();
+        let mut sut = InputPanel::new(chat_room_mock);
+
+        type_text(&mut sut, "/msg bob hi there").await;
+        sut.update(key(KeyCode::Enter)).await;
+    }
+
+    #[tokio::test]
+    async fn should_show_notice_when_sending_fails() {
+        let mut chat_room_mock = mock_chat_room();
+        chat_room_mock
+            .expect_send()
+            .times(1)
+            .returning(|_| Err(anyhow::anyhow!("Connection lost")));
+        chat_room_mock
+            .expect_notice()
+            .with(eq("Connection lost".to_string()))
+            .times(1)
+            .return_const(());
+        let mut sut = InputPanel::new(chat_room_mock);
+
+        type_text(&mut sut, "hello").await;
+        sut.update(key(KeyCode::Enter)).await;
+    }
+
+    #[tokio::test]
+    async fn should_keep_direct_message_without_text() {
+        let mut chat_room_mock = mock_chat_room();
+        chat_room_mock.expect_send_direct().never();
+        chat_room_mock.expect_send().never();
+        chat_room_mock
+            .expect_notice()
+            .with(eq("Usage: /msg <user> <text>".to_string()))
+            .times(1)
+            .return_const(());
+        let mut sut = InputPanel::new(chat_room_mock);
+
+        type_text(&mut sut, "/msg bob").await;
+        sut.update(key(KeyCode::Enter)).await;
+        let (line, _) = draw(&sut, 40);
+
+        assert!(line.contains("/msg bob"));
+    }
+
+    #[tokio::test]
+    async fn should_complete_user_name() {
+        let mut chat_room_mock = mock_chat_room();
+        chat_room_mock.expect_get_members().returning(|| {
+            vec![Member {
+                name: "alice".into(),
+                status: MemberStatus::Online,
+                alias_of: None,
+            }]
+        });
+        chat_room_mock
+            .expect_send_direct()
+            .with(eq("alice".to_string()), eq("hi".to_string()))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut sut = InputPanel::new(chat_room_mock);
+
+        type_text(&mut sut, "/ms").await;
+        sut.update(key(KeyCode::Tab)).await;
+        type_text(&mut sut, "a").await;
+        sut.update(key(KeyCode::Tab)).await;
+        type_text(&mut sut, "hi").await;
+        sut.update(key(KeyCode::Enter)).await;
+    }
+
+    #[tokio::test]
+    async fn should_return_commands_for_view() {
+        let mut chat_room_mock = mock_chat_room();
+        chat_room_mock.expect_send().never();
+        let mut sut = InputPanel::new(chat_room_mock);
+
+        type_text(&mut sut, "/join hall").await;
+        let command = sut.update(key(KeyCode::Enter)).await;
+
+        assert_eq!(command, Some(Command::Join("hall".into())));
+    }
 }
@@ -1,38 +1,163 @@
-use crossterm::event::KeyEvent;
+use anyhow::Error;
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+use futures::future::LocalBoxFuture;
 use tui::{
     backend::Backend,
     layout::{Constraint, Direction, Layout, Rect},
+    style::{Modifier, Style},
+    text::Spans,
+    widgets::Tabs,
     Frame,
 };
 
-use crate::chat_room::ChatRoom;
+use crate::{
+    chat_room::{validate_name, ChatRoom},
+    tui::{command::Command, history::History},
+};
+
+use super::{
+    help_msg::{HelpMsg, HELP_MSG_HEIGHT},
+    room_view::RoomView,
+};
+
+const DEFAULT_INPUT_ROWS: u16 = 5;
 
-use super::{help_msg::HelpMsg, input_panel::InputPanel, messages_panel::MessagesPanel};
+/// Joins room of the given name, gives its chat room and sent messages history
+pub type JoinRoom<C> = Box<dyn Fn(String) -> LocalBoxFuture<'static, Result<(C, History), Error>>>;
 
 pub struct MainView<C> {
-    msg_panel: MessagesPanel<C>,
+    rooms: Vec<RoomView<C>>,
+    /// Index of the room shown
+    active: usize,
     help_msg: HelpMsg,
-    input_panel: InputPanel<C>,
+    input_rows: u16,
+    join_room: Option<JoinRoom<C>>,
+    quit: bool,
 }
 
 impl<C> MainView<C>
 where
     C: ChatRoom + Clone,
 {
-    pub fn new(chat_room: C) -> Self {
-        let msg_panel = MessagesPanel::new(chat_room.clone());
-        let help_msg = HelpMsg::new();
-        let input_panel = InputPanel::new(chat_room);
-
+    pub fn new() -> Self {
         Self {
-            msg_panel,
-            help_msg,
-            input_panel,
+            rooms: Vec::new(),
+            active: 0,
+            help_msg: HelpMsg::new(),
+            input_rows: DEFAULT_INPUT_ROWS,
+            join_room: None,
+            quit: false,
+        }
+    }
+
+    /// Sets how many lines the input panel of rooms added later can grow to
+    pub fn with_input_rows(mut self, rows: u16) -> Self {
+        self.input_rows = rows;
+        self
+    }
+
+    /// Adds a tab for room `name`, with previously sent messages the input panel can recall
+    pub fn with_room(mut self, name: String, chat_room: C, history: History) -> Self {
+        let room = RoomView::new(name, chat_room)
+            .with_input_rows(self.input_rows)
+            .with_history(history);
+        self.rooms.push(room);
+        self
+    }
+
+    /// Lets `/join` open rooms not given on start
+    pub fn with_join_room(mut self, join_room: JoinRoom<C>) -> Self {
+        self.join_room = Some(join_room);
+        self
+    }
+
+    /// User asked to quit or left the last room
+    pub fn should_quit(&self) -> bool {
+        self.quit
+    }
+
+    pub async fn update(&mut self, event: Event) {
+        if let Event::Key(key) = event {
+            if let Some(room) = self.switched_room(key) {
+                self.active = room;
+                return;
+            }
+        }
+
+        let command = match self.rooms.get_mut(self.active) {
+            Some(room) => room.update(event).await,
+            None => None,
+        };
+        match command {
+            Some(Command::Join(name)) => self.join(name).await,
+            Some(Command::Leave) => self.leave().await,
+            Some(Command::Quit) => self.quit = true,
+            _ => (),
+        }
+    }
+
+    /// Tells every room this user is leaving
+    pub async fn leave_rooms(&self) -> Result<(), Error> {
+        for room in &self.rooms {
+            room.leave().await?;
         }
+
+        Ok(())
     }
 
-    pub async fn update(&mut self, event: KeyEvent) {
-        self.input_panel.update(event).await
+    async fn join(&mut self, name: String) {
+        if let Some(joined) = self.rooms.iter().position(|room| room.name() == name) {
+            self.active = joined;
+            return;
+        }
+
+        let joined = match (&self.join_room, validate_name(&name)) {
+            (_, Err(e)) => Err(e),
+            (Some(join_room), Ok(())) => join_room(name.clone()).await,
+            (None, Ok(())) => Err(anyhow::anyhow!("joining is not available")),
+        };
+        match joined {
+            Ok((chat_room, history)) => {
+                self.rooms.push(
+                    RoomView::new(name, chat_room)
+                        .with_input_rows(self.input_rows)
+                        .with_history(history),
+                );
+                self.active = self.rooms.len() - 1;
+            }
+            Err(e) => self.rooms[self.active].notice(format!("Could not join {}: {:#}", name, e)),
+        }
+    }
+
+    async fn leave(&mut self) {
+        let room = self.rooms.remove(self.active);
+        // Room is gone from the view either way, there is nowhere to report failure
+        let _ = room.leave().await;
+
+        self.active = self.active.min(self.rooms.len().saturating_sub(1));
+        self.quit = self.rooms.is_empty();
+    }
+
+    /// Room selected with Ctrl+N/Ctrl+P or Alt+digit
+    fn switched_room(&self, key: KeyEvent) -> Option<usize> {
+        let count = self.rooms.len();
+        if count == 0 {
+            return None;
+        }
+
+        match key.code {
+            KeyCode::Char('n') if key.modifiers == KeyModifiers::CONTROL => {
+                Some((self.active + 1) % count)
+            }
+            KeyCode::Char('p') if key.modifiers == KeyModifiers::CONTROL => {
+                Some((self.active + count - 1) % count)
+            }
+            KeyCode::Char(digit) if key.modifiers == KeyModifiers::ALT => digit
+                .to_digit(10)
+                .and_then(|digit| (digit as usize).checked_sub(1))
+                .filter(|&room| room < count),
+            _ => None,
+        }
     }
 
     pub fn draw(&self, frame: &mut Frame<impl Backend>, chunk: Rect) {
@@ -41,16 +166,250 @@ where
             .margin(2)
             .constraints(
                 [
-                    Constraint::Min(1),
                     Constraint::Length(1),
-                    Constraint::Length(3),
+                    Constraint::Min(1),
+                    Constraint::Length(HELP_MSG_HEIGHT),
                 ]
                 .as_ref(),
             )
             .split(chunk);
 
-        self.msg_panel.draw(frame, chunks[0]);
-        self.help_msg.draw(frame, chunks[1]);
-        self.input_panel.draw(frame, chunks[2]);
+        let titles = self
+            .rooms
+            .iter()
+            .enumerate()
+            .map(|(i, room)| match room.unread() {
+                unread if unread > 0 && i != self.active => {
+                    Spans::from(format!("{} ({})", room.name(), unread))
+                }
+                _ => Spans::from(room.name().to_string()),
+            })
+            .collect();
+        let tabs = Tabs::new(titles)
+            .select(self.active)
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_widget(tabs, chunks[0]);
+
+        if let Some(room) = self.rooms.get(self.active) {
+            room.draw(frame, chunks[1]);
+        }
+        self.help_msg.draw(frame, chunks[2]);
+    }
+}
+
+impl<C> Default for MainView<C>
+where
+    C: ChatRoom + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::Error;
+    use test_case::test_case;
+    use tui::{backend::TestBackend, Terminal};
+
+    use super::*;
+
+    use crate::{
+        chat_room::{ChatMessage, Member, Trust},
+        queue::ConnectionState,
+    };
+
+    /// Mocks can't be cloned into every panel, so rooms here only share messages
+    #[derive(Clone, Default)]
+    struct FakeChatRoom {
+        messages: Arc<Mutex<Vec<ChatMessage>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ChatRoom for FakeChatRoom {
+        async fn send(&self, _: String) -> Result<(), Error> {
+            Ok(())
+        }
+        async fn send_direct(&self, _: String, _: String) -> Result<(), Error> {
+            Ok(())
+        }
+        fn get_messages(&self) -> Vec<ChatMessage> {
+            self.messages.lock().unwrap().clone()
+        }
+        fn get_members(&self) -> Vec<Member> {
+            Vec::new()
+        }
+        async fn send_typing(&self) -> Result<(), Error> {
+            Ok(())
+        }
+        fn get_typing(&self) -> Vec<String> {
+            Vec::new()
+        }
+        fn connection_state(&self) -> ConnectionState {
+            ConnectionState::Connected
+        }
+        fn notice(&self, _: String) {}
+        async fn set_subject(&self, _: String) -> Result<(), Error> {
+            Ok(())
+        }
+        fn get_subject(&self) -> Option<String> {
+            None
+        }
+        async fn set_nick(&self, _: String) -> Result<(), Error> {
+            Ok(())
+        }
+        async fn leave(&self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn rotate_key(&self, _revoked: Option<String>) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn invite(&self, _user: String) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// Builds view with rooms "kitchen", "hall" and "attic", returns the "hall" room
+    fn main_view() -> (MainView<FakeChatRoom>, FakeChatRoom) {
+        let hall = FakeChatRoom::default();
+        let sut = MainView::new()
+            .with_room("kitchen".into(), FakeChatRoom::default(), History::new())
+            .with_room("hall".into(), hall.clone(), History::new())
+            .with_room("attic".into(), FakeChatRoom::default(), History::new());
+
+        (sut, hall)
+    }
+
+    fn draw_tabs(sut: &MainView<FakeChatRoom>) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(60, 20)).unwrap();
+        terminal
+            .draw(|frame| sut.draw(frame, frame.size()))
+            .unwrap();
+
+        let buffer = terminal.backend().buffer();
+        (0..buffer.area.width)
+            .map(|x| buffer.get(x, 2).symbol.clone())
+            .collect()
+    }
+
+    fn ctrl(c: char) -> Event {
+        Event::Key(KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL))
+    }
+
+    #[test_case(ctrl('n'), 1 ; "next room")]
+    #[test_case(ctrl('p'), 2 ; "previous room wraps around")]
+    #[test_case(Event::Key(KeyEvent::new(KeyCode::Char('3'), KeyModifiers::ALT)), 2 ; "room by number")]
+    #[test_case(Event::Key(KeyEvent::new(KeyCode::Char('4'), KeyModifiers::ALT)), 0 ; "missing room number")]
+    #[tokio::test]
+    async fn should_switch_rooms(event: Event, expected: usize) {
+        let (mut sut, _) = main_view();
+
+        sut.update(event).await;
+
+        assert_eq!(sut.active, expected);
+    }
+
+    #[tokio::test]
+    async fn should_count_unread_messages_of_other_rooms() {
+        let (mut sut, hall) = main_view();
+        draw_tabs(&sut);
+
+        let stored = ChatMessage::new("bob".into(), "stored".into(), 0);
+        let notice = ChatMessage::system("notice".into());
+        hall.messages.lock().unwrap().extend([stored, notice]);
+        assert!(!draw_tabs(&sut).contains("hall ("));
+
+        for msg in ["one", "two"] {
+            let mut message = ChatMessage::new("bob".into(), msg.into(), 0);
+            message.trust = Trust::Verified;
+            hall.messages.lock().unwrap().push(message);
+        }
+        assert!(draw_tabs(&sut).contains("hall (2)"));
+
+        sut.update(ctrl('n')).await;
+        draw_tabs(&sut);
+        sut.update(ctrl('p')).await;
+
+        let tabs = draw_tabs(&sut);
+        assert!(tabs.contains("hall"));
+        assert!(!tabs.contains("hall ("));
+    }
+
+    async fn type_command(sut: &mut MainView<FakeChatRoom>, command: &str) {
+        for ch in command.chars() {
+            sut.update(Event::Key(KeyEvent::new(
+                KeyCode::Char(ch),
+                KeyModifiers::NONE,
+            )))
+            .await;
+        }
+        sut.update(Event::Key(KeyEvent::new(
+            KeyCode::Enter,
+            KeyModifiers::NONE,
+        )))
+        .await;
+    }
+
+    #[tokio::test]
+    async fn should_join_room_in_new_tab() {
+        let (sut, _) = main_view();
+        let mut sut = sut.with_join_room(Box::new(|_| {
+            Box::pin(async { Ok((FakeChatRoom::default(), History::new())) })
+        }));
+
+        type_command(&mut sut, "/join cellar").await;
+
+        assert_eq!(sut.active, 3);
+        assert!(draw_tabs(&sut).contains("cellar"));
+    }
+
+    #[test_case("/join +" ; "wildcard")]
+    #[test_case("/join #" ; "multi level wildcard")]
+    #[test_case("/join cellar/wine" ; "topic separator")]
+    #[tokio::test]
+    async fn should_not_join_room_of_invalid_name(command: &str) {
+        let (sut, _) = main_view();
+        let mut sut = sut.with_join_room(Box::new(|_| unreachable!("Invalid name is not joined")));
+
+        type_command(&mut sut, command).await;
+
+        assert_eq!(sut.rooms.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn should_switch_to_joined_room() {
+        let (mut sut, _) = main_view();
+
+        type_command(&mut sut, "/join attic").await;
+
+        assert_eq!(sut.active, 2);
+        assert_eq!(sut.rooms.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn should_quit_after_leaving_last_room() {
+        let mut sut = MainView::new()
+            .with_room("kitchen".into(), FakeChatRoom::default(), History::new())
+            .with_room("hall".into(), FakeChatRoom::default(), History::new());
+
+        type_command(&mut sut, "/leave").await;
+        assert!(!draw_tabs(&sut).contains("kitchen"));
+        assert!(!sut.should_quit());
+
+        type_command(&mut sut, "/leave").await;
+        assert!(sut.should_quit());
+    }
+
+    #[tokio::test]
+    async fn should_quit_on_command() {
+        let (mut sut, _) = main_view();
+
+        type_command(&mut sut, "/quit").await;
+
+        assert!(sut.should_quit());
     }
 }
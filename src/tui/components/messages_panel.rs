@@ -1,20 +1,36 @@
-use rand::Rng;
-use rand_pcg::Pcg64;
-use rand_seeder::Seeder;
+use std::cell::Cell;
+
+use crossterm::event::{Event, KeyCode, MouseEventKind};
 use tui::{
     backend::Backend,
-    layout::Rect,
+    layout::{Alignment, Rect},
     style::{Color, Modifier, Style},
     text::{Span, Spans},
     widgets::{Block, Borders, Paragraph},
     Frame,
 };
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use super::get_rbg;
+use crate::{
+    chat_room::{ChatMessage, ChatRoom, MessageKind, Trust},
+    queue::ConnectionState,
+};
 
-use crate::chat_room::ChatRoom;
+const MOUSE_SCROLL_LINES: usize = 3;
 
 #[derive(Clone, Default, Debug)]
 pub struct MessagesPanel<C> {
     chat_room: C,
+    /// First visible line while scrolled up, `None` follows the newest messages
+    scroll: Option<usize>,
+    /// Number of messages when the panel stopped following the newest ones
+    seen_messages: usize,
+    /// Number of oldest messages hidden with /clear
+    cleared: usize,
+    /// Line count and height remembered from the last draw
+    line_count: Cell<usize>,
+    page_height: Cell<usize>,
 }
 
 impl<C> MessagesPanel<C>
@@ -22,38 +38,578 @@ where
     C: ChatRoom,
 {
     pub fn new(chat_room: C) -> Self {
-        Self { chat_room }
+        Self {
+            chat_room,
+            scroll: None,
+            seen_messages: 0,
+            cleared: 0,
+            line_count: Cell::new(0),
+            page_height: Cell::new(0),
+        }
+    }
+
+    pub fn update(&mut self, event: &Event) {
+        match event {
+            Event::Key(key) if key.code == KeyCode::PageUp => {
+                self.scroll_up(self.page_height.get().max(1))
+            }
+            Event::Key(key) if key.code == KeyCode::PageDown => {
+                self.scroll_down(self.page_height.get().max(1))
+            }
+            Event::Mouse(mouse) if mouse.kind == MouseEventKind::ScrollUp => {
+                self.scroll_up(MOUSE_SCROLL_LINES)
+            }
+            Event::Mouse(mouse) if mouse.kind == MouseEventKind::ScrollDown => {
+                self.scroll_down(MOUSE_SCROLL_LINES)
+            }
+            _ => (),
+        }
+    }
+
+    /// Hides messages received so far
+    pub fn clear(&mut self) {
+        self.cleared = self.chat_room.get_messages().len();
+        self.scroll = None;
+    }
+
+    fn max_scroll(&self) -> usize {
+        self.line_count.get().saturating_sub(self.page_height.get())
+    }
+
+    fn scroll_up(&mut self, lines: usize) {
+        let max_scroll = self.max_scroll();
+        let top = self.scroll.unwrap_or(max_scroll).saturating_sub(lines);
+
+        if top < max_scroll {
+            if self.scroll.is_none() {
+                self.seen_messages = self.chat_room.get_messages().len();
+            }
+            self.scroll = Some(top);
+        }
+    }
+
+    fn scroll_down(&mut self, lines: usize) {
+        if let Some(top) = self.scroll {
+            let top = top + lines;
+            self.scroll = if top < self.max_scroll() {
+                Some(top)
+            } else {
+                None
+            };
+        }
     }
 
     pub fn draw(&self, frame: &mut Frame<impl Backend>, chunk: Rect) {
-        let messages = self
-            .chat_room
-            .get_messages()
+        let messages = self.chat_room.get_messages();
+        let new_messages = messages.len().saturating_sub(self.seen_messages);
+
+        let width = chunk.width.saturating_sub(2) as usize;
+        let lines = messages
             .iter()
-            .map(|message| {
-                Spans::from(vec![
-                    Span::raw(message.time.format("%H:%M:%S ").to_string()),
-                    Span::styled(
-                        message.user.clone(),
-                        Style::default()
-                            .fg(get_rbg(&message.user))
-                            .add_modifier(Modifier::BOLD),
-                    ),
-                    Span::raw(" "),
-                    Span::raw(message.msg.clone()),
-                ])
-            })
+            .skip(self.cleared)
+            .flat_map(|message| wrap_message(message, width))
             .collect::<Vec<_>>();
 
-        let messages = Paragraph::new(messages)
-            .block(Block::default().borders(Borders::ALL).title("Messages"));
+        self.line_count.set(lines.len());
+        self.page_height
+            .set(chunk.height.saturating_sub(2) as usize);
+        let top = match self.scroll {
+            Some(top) => top.min(self.max_scroll()),
+            None => self.max_scroll(),
+        };
+
+        let mut title = vec![Span::raw(match self.chat_room.get_subject() {
+            Some(subject) => format!("Messages - {}", subject),
+            None => "Messages".to_string(),
+        })];
+        if self.chat_room.connection_state() == ConnectionState::Reconnecting {
+            title.push(Span::styled(
+                " (reconnecting...)",
+                Style::default().fg(Color::Red),
+            ));
+        }
+
+        // Sliced here, scroll offset of the paragraph would overflow on long rooms
+        let visible = lines
+            .into_iter()
+            .skip(top)
+            .take(self.page_height.get())
+            .collect::<Vec<_>>();
+        let messages = Paragraph::new(visible).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(Spans::from(title)),
+        );
         frame.render_widget(messages, chunk);
+
+        if self.scroll.is_some() && new_messages > 0 && chunk.height > 0 {
+            let indicator = Paragraph::new(Span::styled(
+                format!(" {} new messages below ", new_messages),
+                Style::default().add_modifier(Modifier::REVERSED),
+            ))
+            .alignment(Alignment::Right);
+            // Draw over the bottom border, so no message line is covered
+            let area = Rect::new(
+                chunk.x + 1,
+                chunk.y + chunk.height - 1,
+                chunk.width.saturating_sub(2),
+                1,
+            );
+            frame.render_widget(indicator, area);
+        }
+    }
+}
+
+/// Renders message as lines no wider than `width`. Lines following the first
+/// one are indented to where the message text starts, unless that would leave
+/// too little room for the text.
+fn wrap_message(message: &ChatMessage, width: usize) -> Vec<Spans<'static>> {
+    let mut prefix = vec![time_span(message)];
+    let mut msg = message.msg.as_str();
+    match &message.kind {
+        MessageKind::Text => match message.action() {
+            Some(action) => {
+                prefix.push(Span::raw("* "));
+                prefix.push(user_span(message));
+                prefix.extend(trust_span(message.trust));
+                msg = action;
+            }
+            None => {
+                prefix.push(user_span(message));
+                prefix.extend(trust_span(message.trust));
+            }
+        },
+        MessageKind::System => prefix.push(Span::styled("*", Style::default().fg(Color::DarkGray))),
+        MessageKind::Direct { to } => {
+            prefix.push(Span::styled("[DM] ", Style::default().fg(Color::Magenta)));
+            prefix.push(user_span(message));
+            prefix.extend(trust_span(message.trust));
+            prefix.extend(vec![Span::raw(" → "), name_span(to, to)]);
+        }
+    }
+    prefix.push(Span::raw(" "));
+    let prefix_width = prefix.iter().map(Span::width).sum::<usize>();
+    let indent = if prefix_width * 2 <= width {
+        prefix_width
+    } else {
+        0
+    };
+
+    let mut text = wrap_text(msg, width.saturating_sub(prefix_width), width - indent).into_iter();
+
+    prefix.push(Span::raw(text.next().unwrap_or_default()));
+    let mut lines = vec![Spans::from(prefix)];
+    lines.extend(text.map(|line| Spans::from(format!("{:indent$}{}", "", line, indent = indent))));
+
+    lines
+}
+
+/// Time the sender claims, marked with `~` when it was far from the local
+/// clock on receipt, as the sender's clock may be off
+fn time_span(message: &ChatMessage) -> Span<'static> {
+    let time = message.time.format("%H:%M:%S").to_string();
+    if message.skewed {
+        Span::styled(format!("{}~ ", time), Style::default().fg(Color::Yellow))
+    } else {
+        Span::raw(format!("{} ", time))
     }
 }
 
-fn get_rbg(data: &str) -> Color {
-    let mut rng: Pcg64 = Seeder::from(data).make_rng();
-    let (r, g, b) = rng.gen();
+/// Sender name, coloured by their first name so renames keep the colour
+fn user_span(message: &ChatMessage) -> Span<'static> {
+    let colour_of = message.alias_of.as_ref().unwrap_or(&message.user);
+    name_span(&message.user, colour_of)
+}
+
+/// Mark after the sender name telling whether the signature checked out, or
+/// that it could not be checked, e.g. for stored or relayed messages
+fn trust_span(trust: Trust) -> Option<Span<'static>> {
+    let (mark, colour) = match trust {
+        Trust::Unknown => ("°", Color::DarkGray),
+        Trust::Verified => ("✓", Color::Green),
+        Trust::Unsigned => ("?", Color::Yellow),
+        Trust::KeyChanged => ("!", Color::Red),
+    };
+
+    Some(Span::styled(mark, Style::default().fg(colour)))
+}
+
+fn name_span(name: &str, colour_of: &str) -> Span<'static> {
+    Span::styled(
+        name.to_string(),
+        Style::default()
+            .fg(get_rbg(colour_of))
+            .add_modifier(Modifier::BOLD),
+    )
+}
+
+/// Wraps every line of `text` separately, so embedded new lines are kept
+fn wrap_text(text: &str, first_width: usize, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
 
-    Color::Rgb(r, g, b)
+    for line in text.split('\n') {
+        let line_width = if lines.is_empty() { first_width } else { width };
+        lines.extend(wrap_line(line, line_width, width));
+    }
+
+    lines
+}
+
+/// Greedy word wrap. Words wider than a line are broken between characters.
+fn wrap_line(text: &str, first_width: usize, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = vec![String::new()];
+    let mut line_width = 0;
+    let mut max_width = first_width;
+
+    for word in text.split_inclusive(' ') {
+        let word_width = word.trim_end_matches(' ').width();
+
+        if line_width + word_width > max_width && line_width > 0 {
+            lines.push(String::new());
+            line_width = 0;
+            max_width = width;
+        }
+
+        for ch in word.chars() {
+            let ch_width = ch.width().unwrap_or(0);
+            if line_width + ch_width > max_width && ch != ' ' {
+                lines.push(String::new());
+                line_width = 0;
+                max_width = width;
+            }
+
+            let line = lines.last_mut().expect("Always at least one line");
+            line.push(ch);
+            line_width += ch_width;
+        }
+    }
+
+    lines
+        .into_iter()
+        .map(|line| line.trim_end_matches(' ').to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crossterm::event::{KeyEvent, KeyModifiers, MouseEvent};
+    use tui::{backend::TestBackend, Terminal};
+
+    use test_case::test_case;
+
+    use super::*;
+
+    use crate::chat_room::MockChatRoom;
+
+    type Messages = Arc<Mutex<Vec<ChatMessage>>>;
+
+    fn chat_room(count: usize) -> (MockChatRoom, Messages) {
+        let messages = Messages::default();
+        for i in 0..count {
+            push_message(&messages, &format!("msg{}", i));
+        }
+
+        let mut chat_room_mock = MockChatRoom::new();
+        let shared = messages.clone();
+        chat_room_mock
+            .expect_get_messages()
+            .returning(move || shared.lock().unwrap().clone());
+        chat_room_mock
+            .expect_connection_state()
+            .return_const(ConnectionState::Connected);
+        chat_room_mock.expect_get_subject().return_const(None);
+
+        (chat_room_mock, messages)
+    }
+
+    fn push_message(messages: &Messages, msg: &str) {
+        messages
+            .lock()
+            .unwrap()
+            .push(ChatMessage::new("bob".into(), msg.into(), 0));
+    }
+
+    /// Draws panel with 3 visible lines and returns rendered rows
+    fn draw(sut: &MessagesPanel<MockChatRoom>) -> Vec<String> {
+        let mut terminal = Terminal::new(TestBackend::new(40, 5)).unwrap();
+        terminal
+            .draw(|frame| sut.draw(frame, frame.size()))
+            .unwrap();
+
+        let buffer = terminal.backend().buffer();
+        (0..buffer.area.height)
+            .map(|y| {
+                (0..buffer.area.width)
+                    .map(|x| buffer.get(x, y).symbol.clone())
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn key(code: KeyCode) -> Event {
+        Event::Key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn mouse(kind: MouseEventKind) -> Event {
+        Event::Mouse(MouseEvent {
+            kind,
+            column: 0,
+            row: 0,
+            modifiers: KeyModifiers::NONE,
+        })
+    }
+
+    #[test]
+    fn should_show_newest_messages() {
+        let (chat_room_mock, _) = chat_room(10);
+        let sut = MessagesPanel::new(chat_room_mock);
+
+        let rows = draw(&sut);
+
+        assert!(rows[1].contains("msg7"));
+        assert!(rows[3].contains("msg9"));
+    }
+
+    #[test]
+    fn should_mark_system_notices() {
+        let (chat_room_mock, messages) = chat_room(0);
+        messages
+            .lock()
+            .unwrap()
+            .push(ChatMessage::system("notice".into()));
+        let sut = MessagesPanel::new(chat_room_mock);
+
+        let rows = draw(&sut);
+
+        assert!(rows[1].contains(" * notice"));
+    }
+
+    #[test]
+    fn should_show_actions_in_third_person() {
+        let (chat_room_mock, messages) = chat_room(0);
+        push_message(&messages, "/me waves");
+        let sut = MessagesPanel::new(chat_room_mock);
+
+        let rows = draw(&sut);
+
+        assert!(rows[1].contains(" * bob° waves"));
+    }
+
+    #[test]
+    fn should_hide_cleared_messages() {
+        let (chat_room_mock, messages) = chat_room(2);
+        let mut sut = MessagesPanel::new(chat_room_mock);
+
+        sut.clear();
+        push_message(&messages, "after");
+        let rows = draw(&sut);
+
+        assert!(rows[1].contains("after"));
+        assert!(!rows.iter().any(|row| row.contains("msg")));
+    }
+
+    #[test_case(Trust::Unknown, " bob° hi" ; "unknown")]
+    #[test_case(Trust::Verified, " bob✓ hi" ; "verified")]
+    #[test_case(Trust::Unsigned, " bob? hi" ; "unsigned")]
+    #[test_case(Trust::KeyChanged, " bob! hi" ; "key changed")]
+    fn should_mark_trust_of_sender(trust: Trust, expected: &str) {
+        let (chat_room_mock, messages) = chat_room(0);
+        let mut message = ChatMessage::new("bob".into(), "hi".into(), 0);
+        message.trust = trust;
+        messages.lock().unwrap().push(message);
+        let sut = MessagesPanel::new(chat_room_mock);
+
+        let rows = draw(&sut);
+
+        assert!(rows[1].contains(expected));
+    }
+
+    #[test]
+    fn should_mark_time_far_from_local_clock() {
+        let (chat_room_mock, messages) = chat_room(0);
+        let mut message = ChatMessage::new("bob".into(), "hi".into(), 0);
+        message.skewed = true;
+        let time = message.time.format("%H:%M:%S").to_string();
+        messages.lock().unwrap().push(message);
+        let sut = MessagesPanel::new(chat_room_mock);
+
+        let rows = draw(&sut);
+
+        assert!(rows[1].contains(&format!("{}~ bob° hi", time)));
+    }
+
+    #[test]
+    fn should_mark_direct_messages() {
+        let (chat_room_mock, messages) = chat_room(0);
+        let mut message = ChatMessage::new("bob".into(), "psst".into(), 0);
+        message.kind = MessageKind::Direct { to: "eve".into() };
+        messages.lock().unwrap().push(message);
+        let sut = MessagesPanel::new(chat_room_mock);
+
+        let rows = draw(&sut);
+
+        assert!(rows[1].contains(" [DM] bob° → eve psst"));
+    }
+
+    #[test]
+    fn should_show_newest_of_more_lines_than_u16_holds() {
+        let (chat_room_mock, _) = chat_room(70_000);
+        let sut = MessagesPanel::new(chat_room_mock);
+
+        let rows = draw(&sut);
+
+        assert!(rows[3].contains("msg69999"));
+    }
+
+    #[test]
+    fn should_scroll_page_up_and_down() {
+        let (chat_room_mock, _) = chat_room(10);
+        let mut sut = MessagesPanel::new(chat_room_mock);
+        draw(&sut);
+
+        sut.update(&key(KeyCode::PageUp));
+        let rows = draw(&sut);
+        assert!(rows[1].contains("msg4"));
+        assert!(rows[3].contains("msg6"));
+
+        sut.update(&key(KeyCode::PageDown));
+        let rows = draw(&sut);
+        assert!(rows[3].contains("msg9"));
+    }
+
+    #[test]
+    fn should_scroll_with_mouse_wheel() {
+        let (chat_room_mock, _) = chat_room(10);
+        let mut sut = MessagesPanel::new(chat_room_mock);
+        draw(&sut);
+
+        sut.update(&mouse(MouseEventKind::ScrollUp));
+        let rows = draw(&sut);
+
+        assert!(rows[1].contains("msg4"));
+    }
+
+    #[test]
+    fn should_not_scroll_past_first_message() {
+        let (chat_room_mock, _) = chat_room(4);
+        let mut sut = MessagesPanel::new(chat_room_mock);
+        draw(&sut);
+
+        sut.update(&key(KeyCode::PageUp));
+        sut.update(&key(KeyCode::PageUp));
+        let rows = draw(&sut);
+
+        assert!(rows[1].contains("msg0"));
+    }
+
+    #[test]
+    fn should_follow_new_messages_at_bottom() {
+        let (chat_room_mock, messages) = chat_room(10);
+        let sut = MessagesPanel::new(chat_room_mock);
+        draw(&sut);
+
+        push_message(&messages, "newest");
+        let rows = draw(&sut);
+
+        assert!(rows[3].contains("newest"));
+    }
+
+    #[test]
+    fn should_keep_position_and_count_new_messages_when_scrolled_up() {
+        let (chat_room_mock, messages) = chat_room(10);
+        let mut sut = MessagesPanel::new(chat_room_mock);
+        draw(&sut);
+        sut.update(&key(KeyCode::PageUp));
+
+        push_message(&messages, "newest");
+        push_message(&messages, "newest");
+        let rows = draw(&sut);
+
+        assert!(rows[1].contains("msg4"));
+        assert!(rows[4].contains("2 new messages below"));
+    }
+
+    #[test]
+    fn should_resume_following_after_scrolling_to_bottom() {
+        let (chat_room_mock, messages) = chat_room(10);
+        let mut sut = MessagesPanel::new(chat_room_mock);
+        draw(&sut);
+        sut.update(&key(KeyCode::PageUp));
+        push_message(&messages, "newest");
+        draw(&sut);
+
+        sut.update(&key(KeyCode::PageDown));
+        sut.update(&key(KeyCode::PageDown));
+        let rows = draw(&sut);
+
+        assert!(rows[3].contains("newest"));
+        assert!(!rows[4].contains("new messages below"));
+    }
+
+    #[test]
+    fn should_wrap_long_message_with_hanging_indent() {
+        let (chat_room_mock, messages) = chat_room(0);
+        push_message(&messages, "one two three four five six");
+        let sut = MessagesPanel::new(chat_room_mock);
+
+        let rows = draw(&sut);
+
+        // "HH:MM:SS bob° " takes 14 of 38 columns
+        assert!(rows[1].ends_with(" bob° one two three four five │"));
+        assert_eq!(rows[2], format!("│{:14}six{:21}│", "", ""));
+    }
+
+    #[test]
+    fn should_stick_to_bottom_with_wrapped_messages() {
+        let (chat_room_mock, messages) = chat_room(5);
+        push_message(&messages, &"long ".repeat(10));
+        let sut = MessagesPanel::new(chat_room_mock);
+
+        let rows = draw(&sut);
+
+        assert!(rows[1].contains("msg4"));
+        assert!(rows[3].trim_end_matches(&['│', ' '][..]).ends_with("long"));
+    }
+
+    #[test]
+    fn should_scroll_by_wrapped_lines() {
+        let (chat_room_mock, messages) = chat_room(0);
+        push_message(&messages, &"long ".repeat(20));
+        push_message(&messages, "last");
+        let mut sut = MessagesPanel::new(chat_room_mock);
+        draw(&sut);
+
+        sut.update(&mouse(MouseEventKind::ScrollUp));
+        let rows = draw(&sut);
+
+        assert!(rows[1].contains("bob° long"));
+    }
+
+    #[test_case("short", 10, 10, vec!["short"] ; "fits in line")]
+    #[test_case("one two three", 8, 8, vec!["one two", "three"] ; "wraps on spaces")]
+    #[test_case("one two three", 4, 20, vec!["one", "two three"] ; "first line can be narrower")]
+    #[test_case("abcdefgh", 3, 3, vec!["abc", "def", "gh"] ; "breaks long words")]
+    #[test_case("one  two", 4, 4, vec!["one", "two"] ; "drops spaces at line end")]
+    #[test_case("日本語", 4, 4, vec!["日本", "語"] ; "respects wide characters")]
+    #[test_case("text", 0, 10, vec!["", "text"] ; "no room on first line")]
+    #[test_case("one\ntwo", 10, 10, vec!["one", "two"] ; "keeps new lines")]
+    #[test_case("one two\nthree", 4, 10, vec!["one", "two", "three"] ; "wraps each line")]
+    fn should_wrap_text(text: &str, first_width: usize, width: usize, expected: Vec<&str>) {
+        assert_eq!(wrap_text(text, first_width, width), expected);
+    }
+
+    #[test]
+    fn should_render_multi_line_message_with_indent() {
+        let (chat_room_mock, messages) = chat_room(0);
+        push_message(&messages, "first\nsecond");
+        let sut = MessagesPanel::new(chat_room_mock);
+
+        let rows = draw(&sut);
+
+        assert!(rows[1].contains(" bob° first "));
+        assert_eq!(rows[2], format!("│{:14}second{:18}│", "", ""));
+    }
 }
@@ -1,4 +1,20 @@
+use rand::Rng;
+use rand_pcg::Pcg64;
+use rand_seeder::Seeder;
+use tui::style::Color;
+
 pub mod help_msg;
 pub mod input_panel;
 pub mod main_view;
 pub mod messages_panel;
+pub mod room_view;
+pub mod typing_indicator;
+pub mod users_panel;
+
+/// Colour of the user name, the same for the user on every client
+fn get_rbg(data: &str) -> Color {
+    let mut rng: Pcg64 = Seeder::from(data).make_rng();
+    let (r, g, b) = rng.gen();
+
+    Color::Rgb(r, g, b)
+}
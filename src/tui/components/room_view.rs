@@ -0,0 +1,138 @@
+use std::cell::Cell;
+
+use crossterm::event::Event;
+use tui::{
+    backend::Backend,
+    layout::{Constraint, Direction, Layout, Rect},
+    Frame,
+};
+
+use crate::{
+    chat_room::{ChatMessage, ChatRoom, MessageKind, Trust},
+    tui::{command::Command, history::History},
+};
+
+use super::{
+    input_panel::InputPanel,
+    messages_panel::MessagesPanel,
+    typing_indicator::TypingIndicator,
+    users_panel::{UsersPanel, USERS_PANEL_WIDTH},
+};
+
+/// Everything shown for a single room
+pub struct RoomView<C> {
+    name: String,
+    chat_room: C,
+    msg_panel: MessagesPanel<C>,
+    users_panel: UsersPanel<C>,
+    typing_indicator: TypingIndicator<C>,
+    input_panel: InputPanel<C>,
+    /// Number of live messages when the room was last shown
+    seen_messages: Cell<usize>,
+}
+
+impl<C> RoomView<C>
+where
+    C: ChatRoom + Clone,
+{
+    pub fn new(name: String, chat_room: C) -> Self {
+        Self {
+            name,
+            msg_panel: MessagesPanel::new(chat_room.clone()),
+            users_panel: UsersPanel::new(chat_room.clone()),
+            typing_indicator: TypingIndicator::new(chat_room.clone()),
+            input_panel: InputPanel::new(chat_room.clone()),
+            chat_room,
+            seen_messages: Cell::new(0),
+        }
+    }
+
+    /// Sets how many lines the input panel can grow to
+    pub fn with_input_rows(mut self, rows: u16) -> Self {
+        self.input_panel = self.input_panel.with_max_rows(rows);
+        self
+    }
+
+    /// Sets previously sent messages the input panel can recall
+    pub fn with_history(mut self, history: History) -> Self {
+        self.input_panel = self.input_panel.with_history(history);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Number of messages that arrived since the room was last shown
+    pub fn unread(&self) -> usize {
+        self.live_messages()
+            .saturating_sub(self.seen_messages.get())
+    }
+
+    fn live_messages(&self) -> usize {
+        self.chat_room
+            .get_messages()
+            .iter()
+            .filter(|message| is_live(message))
+            .count()
+    }
+
+    /// Handles event, returns command only the main view can carry out
+    pub async fn update(&mut self, event: Event) -> Option<Command> {
+        self.msg_panel.update(&event);
+
+        let command = match event {
+            Event::Key(event) => self.input_panel.update(event).await?,
+            _ => return None,
+        };
+
+        match command {
+            Command::Clear => {
+                self.msg_panel.clear();
+                None
+            }
+            command => Some(command),
+        }
+    }
+
+    /// Shows a system line in this room
+    pub fn notice(&self, msg: String) {
+        self.chat_room.notice(msg)
+    }
+
+    pub async fn leave(&self) -> Result<(), anyhow::Error> {
+        self.chat_room.leave().await
+    }
+
+    pub fn draw(&self, frame: &mut Frame<impl Backend>, chunk: Rect) {
+        self.seen_messages.set(self.live_messages());
+
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(
+                [
+                    Constraint::Min(1),
+                    Constraint::Length(1),
+                    Constraint::Length(self.input_panel.height()),
+                ]
+                .as_ref(),
+            )
+            .split(chunk);
+
+        let top_chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(1), Constraint::Length(USERS_PANEL_WIDTH)].as_ref())
+            .split(chunks[0]);
+
+        self.msg_panel.draw(frame, top_chunks[0]);
+        self.users_panel.draw(frame, top_chunks[1]);
+        self.typing_indicator.draw(frame, chunks[1]);
+        self.input_panel.draw(frame, chunks[2]);
+    }
+}
+
+/// Whether `message` was sent by someone while the room was open, unlike
+/// notices and messages loaded from the store or passed on as history
+fn is_live(message: &ChatMessage) -> bool {
+    message.kind != MessageKind::System && message.trust != Trust::Unknown
+}
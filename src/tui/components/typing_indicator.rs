@@ -0,0 +1,73 @@
+use tui::{
+    backend::Backend,
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    text::Span,
+    widgets::Paragraph,
+    Frame,
+};
+
+use crate::chat_room::ChatRoom;
+
+/// More typing users than this are not named
+const MAX_NAMED: usize = 3;
+
+#[derive(Clone, Default, Debug)]
+pub struct TypingIndicator<C> {
+    chat_room: C,
+}
+
+impl<C> TypingIndicator<C>
+where
+    C: ChatRoom,
+{
+    pub fn new(chat_room: C) -> Self {
+        Self { chat_room }
+    }
+
+    pub fn draw(&self, frame: &mut Frame<impl Backend>, chunk: Rect) {
+        let text = typing_text(&self.chat_room.get_typing()).unwrap_or_default();
+
+        let indicator = Paragraph::new(Span::styled(
+            text,
+            Style::default()
+                .fg(Color::DarkGray)
+                .add_modifier(Modifier::ITALIC),
+        ));
+        frame.render_widget(indicator, chunk);
+    }
+}
+
+fn typing_text(users: &[String]) -> Option<String> {
+    let text = match users {
+        [] => return None,
+        [user] => format!("{} is typing…", user),
+        [first @ .., last] if users.len() <= MAX_NAMED => {
+            format!("{} and {} are typing…", first.join(", "), last)
+        }
+        _ => "Several people are typing…".to_string(),
+    };
+
+    Some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case(&[], None ; "nobody")]
+    #[test_case(&["alice"], Some("alice is typing…") ; "one user")]
+    #[test_case(&["alice", "bob"], Some("alice and bob are typing…") ; "two users")]
+    #[test_case(&["alice", "bob", "carol"], Some("alice, bob and carol are typing…") ; "three users")]
+    #[test_case(&["alice", "bob", "carol", "dave"], Some("Several people are typing…") ; "many users")]
+    fn should_name_typing_users(users: &[&str], expected: Option<&str>) {
+        let users = users
+            .iter()
+            .map(|user| user.to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(typing_text(&users).as_deref(), expected);
+    }
+}
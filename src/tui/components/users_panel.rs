@@ -0,0 +1,113 @@
+use tui::{
+    backend::Backend,
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, Paragraph},
+    Frame,
+};
+
+use super::get_rbg;
+use crate::chat_room::{ChatRoom, Member, MemberStatus};
+
+/// Width of the panel including borders
+pub const USERS_PANEL_WIDTH: u16 = 20;
+
+#[derive(Clone, Default, Debug)]
+pub struct UsersPanel<C> {
+    chat_room: C,
+}
+
+impl<C> UsersPanel<C>
+where
+    C: ChatRoom,
+{
+    pub fn new(chat_room: C) -> Self {
+        Self { chat_room }
+    }
+
+    pub fn draw(&self, frame: &mut Frame<impl Backend>, chunk: Rect) {
+        let mut members = self.chat_room.get_members();
+        members.sort_by_key(|member| member.status);
+
+        let lines = members.iter().map(member_line).collect::<Vec<_>>();
+        let users =
+            Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Users"));
+        frame.render_widget(users, chunk);
+    }
+}
+
+fn member_line(member: &Member) -> Spans<'static> {
+    let name = member.name.clone();
+    // Renamed members keep the colour of their first name
+    let colour = get_rbg(member.alias_of.as_ref().unwrap_or(&member.name));
+
+    match member.status {
+        MemberStatus::Online => Spans::from(Span::styled(
+            name,
+            Style::default().fg(colour).add_modifier(Modifier::BOLD),
+        )),
+        MemberStatus::Away => Spans::from(vec![
+            Span::styled(name, Style::default().fg(colour)),
+            Span::styled(" (away)", Style::default().fg(Color::DarkGray)),
+        ]),
+        MemberStatus::Offline => Spans::from(Span::styled(
+            name,
+            Style::default()
+                .fg(Color::DarkGray)
+                .add_modifier(Modifier::CROSSED_OUT),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tui::{backend::TestBackend, Terminal};
+
+    use super::*;
+
+    use crate::chat_room::MockChatRoom;
+
+    fn member(name: &str, status: MemberStatus) -> Member {
+        Member {
+            name: name.into(),
+            status,
+            alias_of: None,
+        }
+    }
+
+    fn draw(sut: &UsersPanel<MockChatRoom>) -> Vec<String> {
+        let mut terminal = Terminal::new(TestBackend::new(USERS_PANEL_WIDTH, 5)).unwrap();
+        terminal
+            .draw(|frame| sut.draw(frame, frame.size()))
+            .unwrap();
+
+        let buffer = terminal.backend().buffer();
+        (0..buffer.area.height)
+            .map(|y| {
+                (0..buffer.area.width)
+                    .map(|x| buffer.get(x, y).symbol.clone())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_list_online_members_first() {
+        let mut chat_room_mock = MockChatRoom::new();
+        chat_room_mock.expect_get_members().returning(|| {
+            vec![
+                member("alice", MemberStatus::Offline),
+                member("bob", MemberStatus::Away),
+                member("carol", MemberStatus::Online),
+            ]
+        });
+        let sut = UsersPanel::new(chat_room_mock);
+
+        let rows = draw(&sut);
+
+        assert!(rows[1].contains("carol"));
+        assert!(rows[2].contains("bob (away)"));
+        assert!(rows[3].contains("alice"));
+    }
+}
@@ -0,0 +1,340 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Editable text with a cursor. Cursor moves and deletions operate on whole
+/// grapheme clusters, so multi-byte and combined characters are never split.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Editor {
+    text: String,
+    /// Byte offset into `text`, always on a grapheme boundary
+    cursor: usize,
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Returns the text and leaves the editor empty
+    pub fn take(&mut self) -> String {
+        self.cursor = 0;
+        std::mem::take(&mut self.text)
+    }
+
+    /// Replaces the text and puts the cursor at its end
+    pub fn set_text(&mut self, text: String) {
+        self.cursor = text.len();
+        self.text = text;
+    }
+
+    pub fn insert(&mut self, ch: char) {
+        self.text.insert(self.cursor, ch);
+        self.cursor += ch.len_utf8();
+    }
+
+    pub fn backspace(&mut self) {
+        if let Some(len) = self.previous_grapheme_len() {
+            self.cursor -= len;
+            self.text.drain(self.cursor..self.cursor + len);
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if let Some(len) = self.next_grapheme_len() {
+            self.text.drain(self.cursor..self.cursor + len);
+        }
+    }
+
+    pub fn move_left(&mut self) {
+        if let Some(len) = self.previous_grapheme_len() {
+            self.cursor -= len;
+        }
+    }
+
+    pub fn move_right(&mut self) {
+        if let Some(len) = self.next_grapheme_len() {
+            self.cursor += len;
+        }
+    }
+
+    /// Moves cursor to the start of the current line
+    pub fn move_home(&mut self) {
+        self.cursor = self.line_start();
+    }
+
+    /// Moves cursor to the end of the current line
+    pub fn move_end(&mut self) {
+        self.cursor = self.line_end();
+    }
+
+    /// Moves cursor to the line above, keeping its column where possible.
+    /// Returns `false` when already on the first line.
+    pub fn move_up(&mut self) -> bool {
+        let start = self.line_start();
+        if start == 0 {
+            return false;
+        }
+
+        let (_, column) = self.cursor_position();
+        let previous_end = start - 1;
+        let previous_start = self.text[..previous_end].rfind('\n').map_or(0, |i| i + 1);
+        self.cursor =
+            previous_start + offset_of_column(&self.text[previous_start..previous_end], column);
+
+        true
+    }
+
+    /// Moves cursor to the line below, keeping its column where possible.
+    /// Returns `false` when already on the last line.
+    pub fn move_down(&mut self) -> bool {
+        let end = self.line_end();
+        if end == self.text.len() {
+            return false;
+        }
+
+        let (_, column) = self.cursor_position();
+        let next_start = end + 1;
+        let next_end = self.text[next_start..]
+            .find('\n')
+            .map_or(self.text.len(), |i| next_start + i);
+        self.cursor = next_start + offset_of_column(&self.text[next_start..next_end], column);
+
+        true
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.text.split('\n')
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.lines().count()
+    }
+
+    /// Line and display column of the cursor
+    pub fn cursor_position(&self) -> (usize, usize) {
+        let before = &self.text[..self.cursor];
+        let row = before.matches('\n').count();
+
+        (row, before[self.line_start()..].width())
+    }
+
+    fn line_start(&self) -> usize {
+        self.text[..self.cursor].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    fn line_end(&self) -> usize {
+        self.text[self.cursor..]
+            .find('\n')
+            .map_or(self.text.len(), |i| self.cursor + i)
+    }
+
+    fn previous_grapheme_len(&self) -> Option<usize> {
+        self.text[..self.cursor]
+            .graphemes(true)
+            .next_back()
+            .map(str::len)
+    }
+
+    fn next_grapheme_len(&self) -> Option<usize> {
+        self.text[self.cursor..]
+            .graphemes(true)
+            .next()
+            .map(str::len)
+    }
+}
+
+/// Byte offset of the last grapheme boundary in `line` not past display `column`
+fn offset_of_column(line: &str, column: usize) -> usize {
+    let mut width = 0;
+
+    for (offset, grapheme) in line.grapheme_indices(true) {
+        width += grapheme.width();
+        if width > column {
+            return offset;
+        }
+    }
+
+    line.len()
+}
+
+/// Cuts out the part of `text` that is displayed from column `offset` in a
+/// view `width` columns wide. Wide graphemes cut by the view edges are
+/// replaced with spaces.
+pub fn visible_slice(text: &str, offset: usize, width: usize) -> String {
+    let mut visible = String::new();
+    let mut column = 0;
+
+    for grapheme in text.graphemes(true) {
+        let grapheme_width = grapheme.width();
+        let start = column;
+        column += grapheme_width;
+
+        if column <= offset {
+            continue;
+        }
+        if column > offset + width {
+            visible.push_str(&" ".repeat(offset + width - start.max(offset)));
+            break;
+        }
+        if start < offset {
+            visible.push_str(&" ".repeat(column - offset));
+            continue;
+        }
+
+        visible.push_str(grapheme);
+    }
+
+    visible
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    fn editor(text: &str) -> Editor {
+        let mut editor = Editor::new();
+        text.chars().for_each(|ch| editor.insert(ch));
+        editor
+    }
+
+    #[test]
+    fn should_remove_multi_byte_character_before_cursor() {
+        let mut sut = editor("ałb");
+
+        sut.move_left();
+        sut.backspace();
+
+        assert_eq!(sut.text(), "ab");
+    }
+
+    #[test]
+    fn should_delete_multi_byte_character_after_cursor() {
+        let mut sut = editor("ałb");
+
+        sut.move_home();
+        sut.move_right();
+        sut.delete();
+
+        assert_eq!(sut.text(), "ab");
+    }
+
+    #[test]
+    fn should_insert_between_multi_byte_characters() {
+        let mut sut = editor("łł");
+
+        sut.move_left();
+        sut.insert('x');
+
+        assert_eq!(sut.text(), "łxł");
+    }
+
+    #[test]
+    fn should_treat_combined_characters_as_one() {
+        // "e" followed by combining acute accent
+        let mut sut = editor("e\u{301}x");
+
+        sut.move_left();
+        sut.move_left();
+        assert_eq!(sut.cursor_position(), (0, 0));
+
+        sut.delete();
+        assert_eq!(sut.text(), "x");
+    }
+
+    #[test]
+    fn should_not_move_past_text_bounds() {
+        let mut sut = editor("ł");
+
+        sut.move_right();
+        sut.delete();
+        sut.move_home();
+        sut.move_left();
+        sut.backspace();
+
+        assert_eq!(sut.text(), "ł");
+        assert_eq!(sut.cursor_position(), (0, 0));
+    }
+
+    #[test_case("abc", 3 ; "ascii")]
+    #[test_case("łó", 2 ; "multi byte")]
+    #[test_case("日本", 4 ; "wide characters")]
+    #[test_case("a🦀", 3 ; "emoji")]
+    fn should_measure_cursor_column_by_display_width(text: &str, expected: usize) {
+        assert_eq!(editor(text).cursor_position(), (0, expected));
+    }
+
+    #[test]
+    fn should_take_text_and_reset_cursor() {
+        let mut sut = editor("ab");
+
+        assert_eq!(sut.take(), "ab");
+        assert!(sut.is_empty());
+        assert_eq!(sut.cursor_position(), (0, 0));
+    }
+
+    #[test]
+    fn should_track_cursor_line() {
+        let sut = editor("one\ntwo\nsix");
+
+        assert_eq!(sut.line_count(), 3);
+        assert_eq!(sut.cursor_position(), (2, 3));
+    }
+
+    #[test]
+    fn should_move_between_lines_keeping_column() {
+        let mut sut = editor("first\nab\nthird");
+
+        assert!(sut.move_up());
+        assert_eq!(sut.cursor_position(), (1, 2));
+        assert!(sut.move_up());
+        assert_eq!(sut.cursor_position(), (0, 2));
+        assert!(!sut.move_up());
+
+        assert!(sut.move_down());
+        assert!(sut.move_down());
+        assert_eq!(sut.cursor_position(), (2, 2));
+        assert!(!sut.move_down());
+    }
+
+    #[test]
+    fn should_keep_column_between_wide_characters() {
+        let mut sut = editor("日本語\nabc");
+
+        sut.move_up();
+        sut.insert('x');
+
+        assert_eq!(sut.text(), "日x本語\nabc");
+    }
+
+    #[test]
+    fn should_jump_to_start_and_end_of_current_line() {
+        let mut sut = editor("one\ntwo");
+
+        sut.move_left();
+        sut.move_home();
+        sut.insert('>');
+        sut.move_end();
+        sut.insert('<');
+
+        assert_eq!(sut.text(), "one\n>two<");
+    }
+
+    #[test_case("abcdef", 0, 3, "abc" ; "start of text")]
+    #[test_case("abcdef", 2, 3, "cde" ; "middle of text")]
+    #[test_case("abcdef", 4, 3, "ef" ; "end of text")]
+    #[test_case("日本語", 1, 4, " 本 " ; "wide characters cut at edges")]
+    #[test_case("日本語", 2, 4, "本語" ; "wide characters aligned")]
+    fn should_cut_visible_slice(text: &str, offset: usize, width: usize, expected: &str) {
+        assert_eq!(visible_slice(text, offset, width), expected);
+    }
+}
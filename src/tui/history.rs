@@ -0,0 +1,321 @@
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::{
+    crypto::{chacha::ChaChaCrypt, Decrypt, Encrypt},
+    store::room_file_name,
+    tui::command::{self, Command, Input},
+};
+
+const MAX_ENTRIES: usize = 1000;
+
+/// Previously sent messages, oldest first. When backed by a file, every new
+/// entry is appended to it encrypted and base64 encoded on its own line.
+/// Direct messages are kept in memory only.
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    entries: Vec<String>,
+    /// Index of the entry recalled with [`History::older`]/[`History::newer`]
+    position: Option<usize>,
+    file: Option<HistoryFile>,
+}
+
+#[derive(Clone)]
+struct HistoryFile {
+    path: PathBuf,
+    crypto: ChaChaCrypt,
+}
+
+impl std::fmt::Debug for HistoryFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HistoryFile")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads history of `room` kept in `dir`, missing file gives empty history.
+    /// The file is trimmed to the entries that are kept.
+    pub fn load(dir: &Path, room: &str, crypto: ChaChaCrypt) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let file = HistoryFile {
+            path: dir.join(room_file_name(room, "history")),
+            crypto,
+        };
+
+        let mut lines = Vec::new();
+        if file.path.exists() {
+            for line in BufReader::new(std::fs::File::open(&file.path)?).lines() {
+                lines.push(line?);
+            }
+        }
+        if lines.len() > MAX_ENTRIES {
+            lines.drain(..lines.len() - MAX_ENTRIES);
+            file.rewrite(&lines)?;
+        }
+
+        // Lines written with another room key (e.g. changed password) are skipped
+        let entries = lines.iter().filter_map(|line| file.decode(line)).collect();
+
+        Ok(Self {
+            entries,
+            position: None,
+            file: Some(file),
+        })
+    }
+
+    pub fn push(&mut self, entry: String) {
+        self.position = None;
+        if self.entries.last() == Some(&entry) {
+            return;
+        }
+
+        // History is only a convenience, losing an entry on disk is not worth failing for
+        let _ = self.save(&entry);
+
+        self.entries.push(entry);
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries.remove(0);
+        }
+    }
+
+    /// Entry currently recalled, if any
+    pub fn current(&self) -> Option<&str> {
+        self.position.map(|i| self.entries[i].as_str())
+    }
+
+    /// Recalls entry older than the current one
+    pub fn older(&mut self) -> Option<&str> {
+        let position = match self.position {
+            Some(0) => 0,
+            Some(i) => i - 1,
+            None => self.entries.len().checked_sub(1)?,
+        };
+        self.position = Some(position);
+
+        self.current()
+    }
+
+    /// Recalls entry newer than the current one, `None` after the newest
+    pub fn newer(&mut self) -> Option<&str> {
+        self.position = self
+            .position
+            .map(|i| i + 1)
+            .filter(|&i| i < self.entries.len());
+
+        self.current()
+    }
+
+    pub fn reset(&mut self) {
+        self.position = None;
+    }
+
+    /// Finds the newest entry older than `before` containing `query`
+    pub fn search(&self, query: &str, before: usize) -> Option<usize> {
+        let before = before.min(self.entries.len());
+        self.entries[..before]
+            .iter()
+            .rposition(|entry| entry.contains(query))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.entries.get(index).map(String::as_str)
+    }
+
+    fn save(&self, entry: &str) -> Result<()> {
+        match &self.file {
+            Some(file) if !is_direct_message(entry) => file.append(entry),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether `entry` is sent as a direct message, however it is spelled
+fn is_direct_message(entry: &str) -> bool {
+    matches!(
+        command::parse(entry),
+        Ok(Input::Command(Command::Msg { .. }))
+    )
+}
+
+impl HistoryFile {
+    fn encode(&self, entry: String) -> String {
+        base64::encode(self.crypto.encrypt(entry))
+    }
+
+    fn decode(&self, line: &str) -> Option<String> {
+        let encrypted = base64::decode(line).ok()?;
+        let entry = self.crypto.decrypt(encrypted).ok()?;
+        String::from_utf8(entry).ok()
+    }
+
+    fn append(&self, entry: &str) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", self.encode(entry.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Replaces the file contents with `lines`, atomically
+    fn rewrite(&self, lines: &[String]) -> Result<()> {
+        let temp = self.path.with_extension("tmp");
+        let mut file = std::fs::File::create(&temp)?;
+        for line in lines {
+            writeln!(file, "{}", line)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(temp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    use crate::{crypto::room_key::RoomKey, store::temp_dir};
+
+    fn history(entries: &[&str]) -> History {
+        let mut history = History::new();
+        entries.iter().for_each(|e| history.push(e.to_string()));
+        history
+    }
+
+    fn crypto(password: &str) -> ChaChaCrypt {
+        ChaChaCrypt::new(&RoomKey::derive(&password, &"kitchen").unwrap())
+    }
+
+    #[test]
+    fn should_recall_entries_from_newest() {
+        let mut sut = history(&["one", "two"]);
+
+        assert_eq!(sut.older(), Some("two"));
+        assert_eq!(sut.older(), Some("one"));
+        assert_eq!(sut.older(), Some("one"));
+        assert_eq!(sut.newer(), Some("two"));
+        assert_eq!(sut.newer(), None);
+    }
+
+    #[test]
+    fn should_not_recall_from_empty_history() {
+        let mut sut = History::new();
+
+        assert_eq!(sut.older(), None);
+        assert_eq!(sut.newer(), None);
+    }
+
+    #[test]
+    fn should_skip_repeated_entry() {
+        let sut = history(&["one", "one"]);
+
+        assert_eq!(sut.len(), 1);
+    }
+
+    #[test]
+    fn should_search_backwards() {
+        let sut = history(&["status ok", "hello", "status failed"]);
+
+        assert_eq!(sut.search("status", sut.len()), Some(2));
+        assert_eq!(sut.search("status", 2), Some(0));
+        assert_eq!(sut.search("status", 0), None);
+        assert_eq!(sut.search("missing", sut.len()), None);
+    }
+
+    #[test]
+    fn should_keep_history_per_room_on_disk() {
+        let dir = temp_dir();
+
+        let mut sut = History::load(&dir, "kitchen", crypto("secret")).unwrap();
+        sut.push("multi\nline".to_string());
+        sut.push("second".to_string());
+        History::load(&dir, "hall", crypto("secret"))
+            .unwrap()
+            .push("other room".to_string());
+
+        let mut loaded = History::load(&dir, "kitchen", crypto("secret")).unwrap();
+        assert_eq!(loaded.older(), Some("second"));
+        assert_eq!(loaded.older(), Some("multi\nline"));
+        assert_eq!(loaded.older(), Some("multi\nline"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_keep_history_encrypted_without_direct_messages() {
+        let dir = temp_dir();
+
+        let mut sut = History::load(&dir, "kitchen", crypto("secret")).unwrap();
+        sut.push("public text".to_string());
+        sut.push("/msg bob private text".to_string());
+
+        let contents = std::fs::read_to_string(dir.join(room_file_name("kitchen", "history")));
+        let contents = contents.unwrap();
+        assert!(!contents.contains("text"));
+        assert_eq!(contents.lines().count(), 1);
+        assert_eq!(sut.older(), Some("/msg bob private text"));
+
+        let mut loaded = History::load(&dir, "kitchen", crypto("secret")).unwrap();
+        assert_eq!(loaded.older(), Some("public text"));
+        assert_eq!(loaded.len(), 1);
+        let other_key = History::load(&dir, "kitchen", crypto("other")).unwrap();
+        assert!(other_key.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test_case("/msg bob secret" ; "space")]
+    #[test_case("/msg\tbob secret" ; "tab")]
+    #[test_case("/msg\nbob\nsecret" ; "new lines")]
+    fn should_not_save_direct_messages(entry: &str) {
+        let dir = temp_dir();
+
+        History::load(&dir, "kitchen", crypto("secret"))
+            .unwrap()
+            .push(entry.to_string());
+
+        let loaded = History::load(&dir, "kitchen", crypto("secret")).unwrap();
+        assert!(loaded.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_trim_history_file_on_load() {
+        let dir = temp_dir();
+
+        let mut sut = History::load(&dir, "kitchen", crypto("secret")).unwrap();
+        (0..MAX_ENTRIES + 5).for_each(|i| sut.push(i.to_string()));
+        History::load(&dir, "kitchen", crypto("secret")).unwrap();
+
+        let contents = std::fs::read_to_string(dir.join(room_file_name("kitchen", "history")));
+        assert_eq!(contents.unwrap().lines().count(), MAX_ENTRIES);
+        let mut loaded = History::load(&dir, "kitchen", crypto("secret")).unwrap();
+        assert_eq!(loaded.older(), Some((MAX_ENTRIES + 4).to_string().as_str()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,2 +1,5 @@
+pub mod command;
 pub mod components;
+pub mod editor;
+pub mod history;
 pub mod terminal_driver;
@@ -1,4 +1,6 @@
-use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::event::{
+    DisableMouseCapture, EnableMouseCapture, Event, EventStream, KeyCode, KeyEvent, KeyModifiers,
+};
 use futures::{FutureExt, StreamExt};
 
 use crate::{chat_room::ChatRoom, tui::components::main_view::MainView};
@@ -16,14 +18,16 @@ pub struct TerminalDriver<W: Write> {
 impl<W: Write> TerminalDriver<W> {
     pub fn new(mut out: W) -> Result<TerminalDriver<W>> {
         terminal::enable_raw_mode()?;
-        out.execute(terminal::EnterAlternateScreen)?;
+        out.execute(terminal::EnterAlternateScreen)?
+            .execute(EnableMouseCapture)?;
 
         Ok(TerminalDriver {
             terminal: Terminal::new(CrosstermBackend::new(out))?,
         })
     }
 
-    pub async fn run<C>(&mut self, mut ui: MainView<C>) -> Result<()>
+    /// Runs until the user quits, `ui` is kept so rooms can be left afterwards
+    pub async fn run<C>(&mut self, ui: &mut MainView<C>) -> Result<()>
     where
         C: ChatRoom + Clone,
     {
@@ -31,16 +35,19 @@ impl<W: Write> TerminalDriver<W> {
         let timeout = std::time::Duration::from_millis(15);
 
         loop {
-            self.render(&ui)?;
+            self.render(ui)?;
 
             if let Ok(event) = tokio::time::timeout(timeout, event_stream.next().fuse()).await {
                 let event = event.ok_or_else(|| anyhow::anyhow!("Empty events queue"))??;
-                if let Event::Key(event) = event {
-                    if quit_event_happened(event) {
+                if let Event::Key(key) = event {
+                    if quit_event_happened(key) {
                         break;
                     }
+                }
 
-                    ui.update(event).await;
+                ui.update(event).await;
+                if ui.should_quit() {
+                    break;
                 }
             };
         }
@@ -62,7 +69,8 @@ impl<W: Write> Drop for TerminalDriver<W> {
     fn drop(&mut self) {
         self.terminal
             .backend_mut()
-            .execute(terminal::LeaveAlternateScreen)
+            .execute(DisableMouseCapture)
+            .and_then(|out| out.execute(terminal::LeaveAlternateScreen))
             .expect("Could not execute to stdout");
         terminal::disable_raw_mode().expect("Terminal doesn't support to disable raw mode");
     }